@@ -173,6 +173,14 @@ pub enum Relocation {
     LITERAL32 = 8,
     // 64-bit literal
     LITERAL64 = 12,
+    // 8-bit absolute address
+    ABSOLUTE8 = 17,
+    // 16-bit absolute address
+    ABSOLUTE16 = 18,
+    // 32-bit absolute address
+    ABSOLUTE32 = 20,
+    // 64-bit absolute address
+    ABSOLUTE64 = 24,
 }
 
 impl Relocation {
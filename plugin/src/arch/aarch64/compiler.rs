@@ -4,7 +4,7 @@ use super::Context;
 use super::ast::{FlatArg, RegKind, RegId, Modifier};
 use super::encoding_helpers;
 
-use crate::common::{Stmt, Size, delimited, emit_error_at, bitmask};
+use crate::common::{Stmt, Size, JumpKind, delimited, emit_error_at, bitmask};
 use crate::parse_helpers::{as_ident, as_number, as_float, as_signed_number};
 
 use syn::spanned::Spanned;
@@ -426,7 +426,11 @@ pub(super) fn compile_instruction(ctx: &mut Context, data: MatchData) -> Result<
                     Relocation::LITERAL8
                     | Relocation::LITERAL16
                     | Relocation::LITERAL32
-                    | Relocation::LITERAL64 => ()
+                    | Relocation::LITERAL64
+                    | Relocation::ABSOLUTE8
+                    | Relocation::ABSOLUTE16
+                    | Relocation::ABSOLUTE32
+                    | Relocation::ABSOLUTE64 => ()
                 },
 
                 _ => panic!("Invalid argument processor")
@@ -468,6 +472,12 @@ pub(super) fn compile_instruction(ctx: &mut Context, data: MatchData) -> Result<
             },
             FlatArg::JumpTarget { ref jump } => match *command {
                 Command::Offset(relocation) => {
+                    // extern targets can only be reached by b and bl, through a veneer if necessary
+                    if let (JumpKind::Bare(_) | JumpKind::Symbol(_), false) = (&jump.kind, relocation == Relocation::B) {
+                        emit_error_at(jump.span(), "Extern relocations are only allowed for b and bl in aarch64".into());
+                        return Err(None);
+                    }
+
                     // what kind of relocation is it
                     let data = [relocation.to_id()];
 
@@ -6,7 +6,7 @@ use super::ast::{Instruction, RawArg, CleanArg, FlatArg, RefItem, Register, RegF
 use super::aarch64data::{Opdata, Matcher, COND_MAP, get_mnemonic_data};
 use super::debug::format_opdata_list;
 
-use crate::common::{Size, JumpKind, emit_error_at};
+use crate::common::{Size, emit_error_at};
 use crate::parse_helpers::{as_ident, as_number, as_float};
 
 /// Try finding an appropriate definition that matches the given instruction / arguments. 
@@ -53,12 +53,8 @@ fn sanitize_args(args: Vec<RawArg>) -> Result<Vec<CleanArg>, Option<String>> {
                 sanitize_register(span, &reg)?;
                 res.push(CleanArg::Direct { span, reg });
             },
-            // offsets: validate that only relative jumps are allowed (no extern relocations)
+            // offsets: passthrough. extern relocations are validated when the relocation is known
             RawArg::JumpTarget { jump } => {
-                if let JumpKind::Bare(_) = jump.kind {
-                    emit_error_at(jump.span(), "Extern relocations are not allowed in aarch64".into());
-                    return Err(None);
-                }
                 res.push(CleanArg::JumpTarget { jump });
             },
             // modifier: LSL LSR ASR ROR and MSL require an immediate.
@@ -44,14 +44,18 @@ impl Arch for ArchAarch64 {
         }
     }
 
-    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size) {
+    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size, absolute: bool) {
         let span = reloc.span();
 
-        let relocation = match size {
-            Size::BYTE => Relocation::LITERAL8,
-            Size::WORD => Relocation::LITERAL16,
-            Size::DWORD => Relocation::LITERAL32,
-            Size::QWORD => Relocation::LITERAL64,
+        let relocation = match (size, absolute) {
+            (Size::BYTE, false) => Relocation::LITERAL8,
+            (Size::WORD, false) => Relocation::LITERAL16,
+            (Size::DWORD, false) => Relocation::LITERAL32,
+            (Size::QWORD, false) => Relocation::LITERAL64,
+            (Size::BYTE, true) => Relocation::ABSOLUTE8,
+            (Size::WORD, true) => Relocation::ABSOLUTE16,
+            (Size::DWORD, true) => Relocation::ABSOLUTE32,
+            (Size::QWORD, true) => Relocation::ABSOLUTE64,
             _ => {
                 emit_error_at(span, "Relocation of unsupported size for the current target architecture".into());
                 return;
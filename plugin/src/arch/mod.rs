@@ -11,7 +11,7 @@ pub mod aarch64;
 pub(crate) trait Arch : Debug + Send {
     fn name(&self) -> &str;
     fn set_features(&mut self, features: &[syn::Ident]);
-    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size);
+    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size, absolute: bool);
     fn default_align(&self) -> u8;
     fn compile_instruction(&self, state: &mut State, input: parse::ParseStream) -> parse::Result<()>;
 }
@@ -38,7 +38,7 @@ impl Arch for DummyArch {
         }
     }
 
-    fn handle_static_reloc(&self, _stmts: &mut Vec<Stmt>, reloc: Jump, _size: Size) {
+    fn handle_static_reloc(&self, _stmts: &mut Vec<Stmt>, reloc: Jump, _size: Size, _absolute: bool) {
         let span = reloc.span();
         emit_error_at(span, "Current assembling architecture is undefined. Define it using a .arch directive".into());
     }
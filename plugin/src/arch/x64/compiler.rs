@@ -112,6 +112,15 @@ pub(super) fn compile_instruction(ctx: &mut Context, instruction: Instruction, a
         _ => return Err(Some("Impossible address size".into()))
     };
 
+    // jumps to labels without an explicit size can be relaxed by the assembler
+    let relaxable = args.iter().any(|arg| match arg {
+        CleanArg::JumpTarget {jump, size: None} => match jump.kind {
+            JumpKind::Bare(_) | JumpKind::Symbol(_) => false,
+            _ => true
+        },
+        _ => false
+    });
+
     // find a matching op
     let data = match_op_format(ctx, &op, &args)?;
 
@@ -490,10 +499,11 @@ pub(super) fn compile_instruction(ctx: &mut Context, instruction: Instruction, a
                 relocations.iter_mut().for_each(|r| r.1 += size.in_bytes());
 
                 // add the new relocation
-                if let JumpKind::Bare(_) = &jump.kind {
+                if let JumpKind::Bare(_) | JumpKind::Symbol(_) = &jump.kind {
                     match ctx.mode {
                         X86Mode::Protected => relocations.push((jump, 0, size, RelocationKind::Extern)),
-                        X86Mode::Long => return Err(Some("Extern relocations are not supported in x64 mode".to_string()))
+                        // the runtime routes these through a veneer if the target is out of range
+                        X86Mode::Long => relocations.push((jump, 0, size, RelocationKind::Relative))
                     }
                 } else {
                     relocations.push((jump, 0, size, RelocationKind::Relative));
@@ -514,6 +524,11 @@ pub(super) fn compile_instruction(ctx: &mut Context, instruction: Instruction, a
         buffer.push(target.encode(data));
     }
 
+    // only jmp rel32 and jcc rel32 have a shorter encoding
+    if relaxable && (data.ops == [0xE9] || (data.ops.len() == 2 && data.ops[0] == 0x0F && data.ops[1] & 0xF0 == 0x80)) {
+        buffer.push(Stmt::RelaxableJump);
+    }
+
     Ok(())
 }
 
@@ -523,7 +538,7 @@ fn clean_memoryref(arg: RawArg) -> Result<CleanArg, Option<String>> {
         RawArg::Direct {span, reg} => CleanArg::Direct {span, reg},
         RawArg::JumpTarget {jump, size} => CleanArg::JumpTarget {jump, size},
         RawArg::IndirectJumpTarget {jump, size} => {
-            if let JumpKind::Bare(_) = jump.kind {
+            if let JumpKind::Bare(_) | JumpKind::Symbol(_) = jump.kind {
                 return Err(Some("Extern indirect jumps are not supported. Use a displacement".to_string()))
             }
             CleanArg::IndirectJumpTarget {jump, size}
@@ -1,4 +1,5 @@
 use syn::parse;
+use quote::quote;
 
 mod ast;
 mod compiler;
@@ -8,7 +9,7 @@ mod x64data;
 
 use crate::State;
 use crate::arch::Arch;
-use crate::common::{Size, Stmt, Jump, emit_error_at};
+use crate::common::{Size, Stmt, Jump, delimited, emit_error_at};
 
 #[cfg(feature = "dynasm_opmap")]
 pub use debug::create_opmap;
@@ -55,11 +56,33 @@ impl Arch for Archx64 {
         self.features = new_features;
     }
 
-    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size) {
-        let data = [0, size.in_bytes()]; // no offset, specified size, relative implicit
+    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size, absolute: bool) {
+        if !absolute {
+            let data = [0, size.in_bytes()]; // no offset, specified size, relative implicit
+
+            stmts.push(Stmt::Const(0, size));
+            stmts.push(reloc.encode(&data));
+            return;
+        }
+
+        // the encoding can only describe relative relocations, so absolute ones are constructed with their kind
+        let size_name = match size {
+            Size::BYTE => quote!(Byte),
+            Size::WORD => quote!(Word),
+            Size::DWORD => quote!(DWord),
+            Size::QWORD => quote!(QWord),
+            _ => {
+                emit_error_at(reloc.span(), "Relocation of unsupported size for the current target architecture".into());
+                return;
+            }
+        };
+        let relocation = delimited(quote!(dynasmrt::x64::X64Relocation::with_kind(
+            dynasmrt::relocations::RelocationSize::#size_name,
+            dynasmrt::relocations::RelocationKind::AbsToRel
+        )));
 
         stmts.push(Stmt::Const(0, size));
-        stmts.push(reloc.encode(&data));
+        stmts.push(reloc.relocate(relocation));
     }
 
     fn default_align(&self) -> u8 {
@@ -112,8 +135,8 @@ impl Arch for Archx86 {
         self.features = new_features;
     }
 
-    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size) {
-        let data = [0, size.in_bytes(), 0]; // no offset, specified size, relative
+    fn handle_static_reloc(&self, stmts: &mut Vec<Stmt>, reloc: Jump, size: Size, absolute: bool) {
+        let data = [0, size.in_bytes(), absolute as u8]; // no offset, specified size, relative or absolute
 
         stmts.push(Stmt::Const(0, size));
         stmts.push(reloc.encode(&data));
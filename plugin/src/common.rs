@@ -43,6 +43,39 @@ impl Size {
 }
 
 
+/// The name of a local label. This is either an identifier, or the number of a GNU-style numeric label.
+/// As identifiers cannot start with a digit, these never clash.
+#[derive(Debug, Clone)]
+pub struct LocalName {
+    pub name: String,
+    pub span: Span
+}
+
+impl LocalName {
+    /// The name of the numeric label `lit`, ignoring its suffix.
+    pub fn numeric(lit: &syn::LitInt) -> parse::Result<LocalName> {
+        let text = lit.to_string();
+        let digits = &text[.. text.len() - lit.suffix().len()];
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(parse::Error::new(lit.span(), "Numeric labels have to be decimal numbers"));
+        }
+        Ok(LocalName {
+            name: digits.to_string(),
+            span: lit.span()
+        })
+    }
+}
+
+impl From<syn::Ident> for LocalName {
+    fn from(ident: syn::Ident) -> LocalName {
+        LocalName {
+            name: ident.to_string(),
+            span: ident.span()
+        }
+    }
+}
+
+
 /**
  * Jump types
  */
@@ -58,16 +91,25 @@ pub enum JumpKind {
     // in order to allow the full range of expressions to be used. the only currently existing ambiguity is
     // with the symbol <, as this symbol is also the starting symbol for the universal calling syntax <Type as Trait>.method(args)
     Global(syn::Ident),   // -> label (["+" "-"] offset)?
-    Backward(syn::Ident), //  > label (["+" "-"] offset)?
-    Forward(syn::Ident),  //  < label (["+" "-"] offset)?
+    NamedGlobal(syn::Expr), // -> (expr) (["+" "-"] offset)?
+    Backward(LocalName),  //  > label (["+" "-"] offset)? | number "b"
+    Forward(LocalName),   //  < label (["+" "-"] offset)? | number "f"
     Dynamic(syn::Expr),   // =>expr | => (expr) (["+" "-"] offset)?
-    Bare(syn::Expr)       // jump to this address
+    Bare(syn::Expr),      // jump to this address
+    Symbol(syn::LitStr)   // jump to the address of this symbol, resolved at runtime
 }
 
 impl ParseOpt for Jump {
     fn parse(input: parse::ParseStream) -> parse::Result<Option<Jump>> {
         // extern label
         if eat_pseudo_keyword(input, "extern") {
+            // extern "symbol"
+            if input.peek(syn::LitStr) {
+                let name: syn::LitStr = input.parse()?;
+
+                return Ok(Some(Jump { kind: JumpKind::Symbol(name), offset: None }));
+            }
+
             let expr: syn::Expr = input.parse()?;
 
             return Ok(Some(Jump { kind: JumpKind::Bare(expr), offset: None }));
@@ -76,24 +118,45 @@ impl ParseOpt for Jump {
         // -> global_label
         let kind = if input.peek(Token![->]) {
             let _: Token![->] = input.parse()?;
-            let name: syn::Ident = input.parse()?;
 
-            JumpKind::Global(name)
+            // -> (expr) for global labels named at runtime
+            if input.peek(syn::token::Paren) {
+                let inner;
+                let _ = syn::parenthesized!(inner in input);
+                let inner = &inner;
+
+                JumpKind::NamedGlobal(inner.parse()?)
+            } else {
+                let name: syn::Ident = input.parse()?;
+
+                JumpKind::Global(name)
+            }
 
         // > forward_label
         } else if input.peek(Token![>]) {
             let _: Token![>] = input.parse()?;
             let name: syn::Ident = input.parse()?;
 
-            JumpKind::Forward(name)
+            JumpKind::Forward(name.into())
 
         // < backwards_label
         } else if input.peek(Token![<]) {
             let _: Token![<] = input.parse()?;
             let name: syn::Ident = input.parse()?;
 
-            JumpKind::Backward(name)
-            
+            JumpKind::Backward(name.into())
+
+        // 1f | 1b numeric label
+        } else if input.peek(syn::LitInt) && matches!(input.fork().parse::<syn::LitInt>()?.suffix(), "f" | "b") {
+            let lit: syn::LitInt = input.parse()?;
+            let name = LocalName::numeric(&lit)?;
+
+            if lit.suffix() == "f" {
+                JumpKind::Forward(name)
+            } else {
+                JumpKind::Backward(name)
+            }
+
         // => dynamic_label
         } else if input.peek(Token![=>]) {
             let _: Token![=>] = input.parse()?;
@@ -141,36 +204,74 @@ impl Jump {
     }
 
     pub fn encode(self, data: &[u8]) -> Stmt {
-        let span = self.span();
+        let data = serialize::expr_tuple_of_u8s(self.span(), data);
+        self.into_stmt(Reloc::Encoded(data))
+    }
+
+    /// Like `encode`, but passes the relocation constructed by `relocation` to the runtime instead of its encoding.
+    pub fn relocate(self, relocation: TokenTree) -> Stmt {
+        self.into_stmt(Reloc::Constructed(relocation))
+    }
 
+    fn into_stmt(self, data: Reloc) -> Stmt {
         let offset = delimited(if let Some(offset) = self.offset {
             quote!(#offset)
         } else {
             quote!(0isize)
         });
 
-        let data = serialize::expr_tuple_of_u8s(span, data);
         match self.kind {
             JumpKind::Global(ident) => Stmt::GlobalJumpTarget(ident, offset, data),
-            JumpKind::Backward(ident) => Stmt::BackwardJumpTarget(ident, offset, data),
-            JumpKind::Forward(ident) => Stmt::ForwardJumpTarget(ident, offset, data),
+            JumpKind::NamedGlobal(expr) => Stmt::NamedGlobalJumpTarget(delimited(expr), offset, data),
+            JumpKind::Backward(name) => Stmt::BackwardJumpTarget(name, offset, data),
+            JumpKind::Forward(name) => Stmt::ForwardJumpTarget(name, offset, data),
             JumpKind::Dynamic(expr) => Stmt::DynamicJumpTarget(delimited(expr), offset, data),
             JumpKind::Bare(expr) => Stmt::BareJumpTarget(delimited(expr), data),
+            JumpKind::Symbol(name) => Stmt::SymbolJumpTarget(delimited(name), data),
         }
     }
 
     pub fn span(&self) -> Span {
         match &self.kind {
             JumpKind::Global(ident) => ident.span(),
-            JumpKind::Backward(ident) => ident.span(),
-            JumpKind::Forward(ident) => ident.span(),
+            JumpKind::NamedGlobal(expr) => expr.span(),
+            JumpKind::Backward(name) => name.span,
+            JumpKind::Forward(name) => name.span,
             JumpKind::Dynamic(expr) => expr.span(),
             JumpKind::Bare(expr) => expr.span(),
+            JumpKind::Symbol(name) => name.span(),
         }
     }
 }
 
 
+/// The relocation of a jump target, as it is passed to the runtime
+#[derive(Debug, Clone)]
+pub enum Reloc {
+    // the encoded form emitted for the current architecture
+    Encoded(TokenTree),
+    // an expression constructing the relocation, for relocations the encoding cannot describe
+    Constructed(TokenTree),
+}
+
+impl Reloc {
+    /// Picks the runtime method that records this relocation, out of the ones taking the encoded
+    /// and the constructed form.
+    pub fn method(&self, encoded: &'static str, constructed: &'static str) -> &'static str {
+        match self {
+            Reloc::Encoded(_) => encoded,
+            Reloc::Constructed(_) => constructed,
+        }
+    }
+
+    pub fn into_expr(self) -> TokenTree {
+        match self {
+            Reloc::Encoded(expr)
+            | Reloc::Constructed(expr) => expr,
+        }
+    }
+}
+
 /// An abstract representation of a dynasm runtime statement to be emitted
 #[derive(Debug, Clone)]
 pub enum Stmt {
@@ -190,15 +291,25 @@ pub enum Stmt {
 
     // label declarations
     GlobalLabel(syn::Ident),
-    LocalLabel(syn::Ident),
+    NamedGlobalLabel(TokenTree),
+    LocalLabel(LocalName),
     DynamicLabel(TokenTree),
 
     // and their respective relocations (as expressions as they differ per assembler)
-    GlobalJumpTarget(  syn::Ident, TokenTree, TokenTree),
-    ForwardJumpTarget( syn::Ident, TokenTree, TokenTree),
-    BackwardJumpTarget(syn::Ident, TokenTree, TokenTree),
-    DynamicJumpTarget(TokenTree, TokenTree, TokenTree),
-    BareJumpTarget(   TokenTree, TokenTree),
+    GlobalJumpTarget(  syn::Ident, TokenTree, Reloc),
+    NamedGlobalJumpTarget(TokenTree, TokenTree, Reloc),
+    ForwardJumpTarget( LocalName, TokenTree, Reloc),
+    BackwardJumpTarget(LocalName, TokenTree, Reloc),
+    DynamicJumpTarget(TokenTree, TokenTree, Reloc),
+    BareJumpTarget(   TokenTree, Reloc),
+    SymbolJumpTarget( TokenTree, Reloc),
+    // mark the last relocation as belonging to a jump that can be relaxed
+    RelaxableJump,
+    // enter and leave a scope for local labels
+    PushLabelScope,
+    PopLabelScope,
+    // continue assembling into a different section
+    SwitchSection(TokenTree),
 
     // a random statement that has to be inserted between assembly hunks
     Stmt(TokenTree)
@@ -4,10 +4,10 @@ use syn::parse;
 use syn::Token;
 use quote::quote;
 
-use crate::common::{Stmt, Size, delimited, emit_error_at};
+use crate::common::{Stmt, Size, Jump, JumpKind, delimited, emit_error_at};
 use crate::arch;
 use crate::DynasmData;
-use crate::parse_helpers::ParseOptExt;
+use crate::parse_helpers::{ParseOptExt, eat_pseudo_keyword};
 
 pub(crate) fn evaluate_directive(file_data: &mut DynasmData, stmts: &mut Vec<Stmt>, input: parse::ParseStream) -> parse::Result<()> {
     let directive: syn::Ident = input.parse()?;
@@ -68,6 +68,29 @@ pub(crate) fn evaluate_directive(file_data: &mut DynasmData, stmts: &mut Vec<Stm
 
             stmts.push(Stmt::Align(delimited(value), with));
         },
+        // ; .scope
+        "scope" => stmts.push(Stmt::PushLabelScope),
+        // ; .endscope
+        "endscope" => stmts.push(Stmt::PopLabelScope),
+        // ; .cold
+        "cold" => stmts.push(Stmt::SwitchSection(delimited(quote!(dynasmrt::Section::Cold)))),
+        // ; .hot
+        "hot" => stmts.push(Stmt::SwitchSection(delimited(quote!(dynasmrt::Section::Hot)))),
+        "section" => {
+            // ; .section ident
+            let name: syn::Ident = input.parse()?;
+            let section = match name.to_string().as_str() {
+                "text" => quote!(Hot),
+                "cold" => quote!(Cold),
+                "rodata" => quote!(Rodata),
+                "data" => quote!(Data),
+                _ => {
+                    emit_error_at(name.span(), format!("Unknown section '{}'", name));
+                    return Ok(());
+                }
+            };
+            stmts.push(Stmt::SwitchSection(delimited(quote!(dynasmrt::Section::#section))));
+        },
         "alias" => {
             // ; .alias ident, ident
             // consider changing this to ; .alias ident = ident next breaking change
@@ -104,23 +127,36 @@ fn directive_const(file_data: &mut DynasmData, stmts: &mut Vec<Stmt>, input: par
         return Ok(())
     }
 
-    if let Some(jump) = input.parse_opt()? {
-        file_data.current_arch.handle_static_reloc(stmts, jump, size);
-    } else {
-        let expr: syn::Expr = input.parse()?;
-        stmts.push(Stmt::ExprSigned(delimited(expr), size));
-    }
-
+    directive_value(file_data, stmts, input, size)?;
 
     while input.peek(Token![,]) {
         let _: Token![,] = input.parse()?;
 
-        if let Some(jump) = input.parse_opt()? {
-            file_data.current_arch.handle_static_reloc(stmts, jump, size);
+        directive_value(file_data, stmts, input, size)?;
+    }
+
+    Ok(())
+}
+
+/// Parses a single value of a data directive: an expression, a reference to a label (emitted relative to
+/// the location of the value), or a reference to a label prefixed with `abs` (emitted as its absolute address).
+fn directive_value(file_data: &mut DynasmData, stmts: &mut Vec<Stmt>, input: parse::ParseStream, size: Size) -> parse::Result<()> {
+    // abs isn't a keyword, so it only marks an absolute reference if a label reference follows it
+    let fork = input.fork();
+    if eat_pseudo_keyword(&fork, "abs") && matches!(fork.parse_opt::<Jump>(), Ok(Some(_))) {
+        eat_pseudo_keyword(input, "abs");
+        let jump: Jump = input.parse_opt()?.unwrap();
+
+        if let JumpKind::Bare(_) | JumpKind::Symbol(_) = jump.kind {
+            emit_error_at(jump.span(), "Absolute references can only refer to labels".into());
         } else {
-            let expr: syn::Expr = input.parse()?;
-            stmts.push(Stmt::ExprSigned(delimited(expr), size));
+            file_data.current_arch.handle_static_reloc(stmts, jump, size, true);
         }
+    } else if let Some(jump) = input.parse_opt()? {
+        file_data.current_arch.handle_static_reloc(stmts, jump, size, false);
+    } else {
+        let expr: syn::Expr = input.parse()?;
+        stmts.push(Stmt::ExprSigned(delimited(expr), size));
     }
 
     Ok(())
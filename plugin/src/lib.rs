@@ -101,6 +101,17 @@ impl parse::Parse for Dynasm {
             if input.peek(Token![->]) {
                 let _: Token![->] = input.parse()?;
 
+                // ; -> (expr) :
+                if input.peek(syn::token::Paren) {
+                    let inner;
+                    let _ = syn::parenthesized!(inner in input);
+                    let expr: syn::Expr = inner.parse()?;
+                    let _: Token![:] = input.parse()?;
+
+                    stmts.push(common::Stmt::NamedGlobalLabel(common::delimited(expr)));
+                    continue;
+                }
+
                 let name: syn::Ident = input.parse()?;
                 let _: Token![:] = input.parse()?;
 
@@ -124,7 +135,20 @@ impl parse::Parse for Dynasm {
                 let name: syn::Ident = input.parse()?;
                 let _: Token![:] = input.parse()?;
 
-                stmts.push(common::Stmt::LocalLabel(name));
+                stmts.push(common::Stmt::LocalLabel(name.into()));
+                continue;
+            }
+
+            // ; number :
+            if input.peek(syn::LitInt) && input.peek2(Token![:]) {
+
+                let lit: syn::LitInt = input.parse()?;
+                let _: Token![:] = input.parse()?;
+
+                if !lit.suffix().is_empty() {
+                    return Err(parse::Error::new(lit.span(), "Numeric labels cannot have a suffix"));
+                }
+                stmts.push(common::Stmt::LocalLabel(common::LocalName::numeric(&lit)?));
                 continue;
             }
 
@@ -249,7 +273,7 @@ fn file_local_data() -> FileLocalData {
     let span = Span::call_site().unstable();
 
     // and use the file that that was at as scope for resolving dynasm data
-    let id = span.source_file().path();
+    let id = std::path::PathBuf::from(span.file());
 
     {
         let data = RwLockReadGuardRef::new(DYNASM_STORAGE.read().unwrap());
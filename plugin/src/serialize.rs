@@ -6,7 +6,7 @@ use quote::{quote, quote_spanned, ToTokens};
 
 use byteorder::{ByteOrder, LittleEndian};
 
-use crate::common::{Size, Stmt, delimited};
+use crate::common::{Size, Stmt, LocalName, delimited};
 
 
 /// Converts a sequence of abstract Statements to actual tokens
@@ -79,13 +79,20 @@ pub fn serialize(name: &TokenTree, stmts: Vec<Stmt>) -> TokenStream {
             Stmt::ExprExtend(expr) => ("extend", vec![expr]),
             Stmt::Align(expr, with)      => ("align", vec![expr, with]),
             Stmt::GlobalLabel(n) => ("global_label", vec![expr_string_from_ident(&n)]),
-            Stmt::LocalLabel(n)  => ("local_label", vec![expr_string_from_ident(&n)]),
+            Stmt::NamedGlobalLabel(expr) => ("named_global_label", vec![expr_ref(expr)]),
+            Stmt::LocalLabel(n)  => ("local_label", vec![expr_string_from_local(&n)]),
             Stmt::DynamicLabel(expr) => ("dynamic_label", vec![expr]),
-            Stmt::GlobalJumpTarget(n,     offset, reloc) => ("global_reloc"  , vec![expr_string_from_ident(&n), offset, reloc]),
-            Stmt::ForwardJumpTarget(n,    offset, reloc) => ("forward_reloc" , vec![expr_string_from_ident(&n), offset, reloc]),
-            Stmt::BackwardJumpTarget(n,   offset, reloc) => ("backward_reloc", vec![expr_string_from_ident(&n), offset, reloc]),
-            Stmt::DynamicJumpTarget(expr, offset, reloc) => ("dynamic_reloc" , vec![expr, offset, reloc]),
-            Stmt::BareJumpTarget(expr, reloc)    => ("bare_reloc"    , vec![expr, reloc]),
+            Stmt::GlobalJumpTarget(n,     offset, reloc) => (reloc.method("global_reloc", "global_relocation"), vec![expr_string_from_ident(&n), offset, reloc.into_expr()]),
+            Stmt::NamedGlobalJumpTarget(expr, offset, reloc) => (reloc.method("named_global_reloc", "named_global_relocation"), vec![expr_ref(expr), offset, reloc.into_expr()]),
+            Stmt::ForwardJumpTarget(n,    offset, reloc) => (reloc.method("forward_reloc", "forward_relocation"), vec![expr_string_from_local(&n), offset, reloc.into_expr()]),
+            Stmt::BackwardJumpTarget(n,   offset, reloc) => (reloc.method("backward_reloc", "backward_relocation"), vec![expr_string_from_local(&n), offset, reloc.into_expr()]),
+            Stmt::DynamicJumpTarget(expr, offset, reloc) => (reloc.method("dynamic_reloc", "dynamic_relocation"), vec![expr, offset, reloc.into_expr()]),
+            Stmt::BareJumpTarget(expr, reloc)    => (reloc.method("bare_reloc", "bare_relocation"), vec![expr, reloc.into_expr()]),
+            Stmt::SymbolJumpTarget(name, reloc)  => (reloc.method("symbol_reloc", "symbol_relocation"), vec![name, reloc.into_expr()]),
+            Stmt::RelaxableJump => ("relaxable_reloc", vec![]),
+            Stmt::PushLabelScope => ("push_label_scope", vec![]),
+            Stmt::PopLabelScope => ("pop_label_scope", vec![]),
+            Stmt::SwitchSection(section) => ("switch_section", vec![section]),
             Stmt::Stmt(s) => {
                 output.extend(quote! {
                     #s ;
@@ -123,6 +130,19 @@ pub fn expr_zero() -> TokenTree {
     proc_macro2::Literal::u8_unsuffixed(0).into()
 }
 
+// takes a reference to an expression
+pub fn expr_ref(expr: TokenTree) -> TokenTree {
+    let span = expr.span();
+    delimited(quote_spanned! { span=>
+        &#expr
+    })
+}
+
+// given the name of a local label, makes it into a "string"
+pub fn expr_string_from_local(n: &LocalName) -> TokenTree {
+    proc_macro2::Literal::string(&n.name).into()
+}
+
 // given an ident, makes it into a "string"
 pub fn expr_string_from_ident(i: &syn::Ident) -> TokenTree {
     let name = i.to_string();
@@ -1,6 +1,8 @@
 //! This module implements the relocation model for the aarch64 architecture, as well as aliases for aarch64 Assemblers.
 
 use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation, fits_signed_bitfield};
+use crate::elf;
+use crate::module::{self, ModuleRelocation};
 use byteorder::{ByteOrder, LittleEndian};
 use std::convert::TryFrom;
 
@@ -20,6 +22,8 @@ pub enum Aarch64Relocation {
     TBZ,
     // Anything in directives
     Plain(RelocationSize),
+    // Absolute addresses in directives
+    Absolute(RelocationSize),
 }
 
 impl Aarch64Relocation {
@@ -30,7 +34,8 @@ impl Aarch64Relocation {
             Self::ADR => 0x9F00_001F,
             Self::ADRP => 0x9F00_001F,
             Self::TBZ => 0xFFF8_001F,
-            Self::Plain(_) => 0
+            Self::Plain(_)
+            | Self::Absolute(_) => 0
         }
     }
 
@@ -75,7 +80,8 @@ impl Aarch64Relocation {
                 let value = (value >> 2) as u32;
                 (value & 0x3FFF) << 5
             },
-            Self::Plain(_) => return Err(ImpossibleRelocation { } )
+            Self::Plain(_)
+            | Self::Absolute(_) => return Err(ImpossibleRelocation { } )
         })
     }
 }
@@ -89,6 +95,7 @@ impl Relocation for Aarch64Relocation {
             2 => Self::ADR,
             3 => Self::ADRP,
             4 => Self::TBZ,
+            x if x > 16 => Self::Absolute(RelocationSize::from_encoding(x - 16)),
             x  => Self::Plain(RelocationSize::from_encoding(x - 4))
         }
     }
@@ -97,12 +104,13 @@ impl Relocation for Aarch64Relocation {
     }
     fn size(&self) -> usize {
         match self {
-            Self::Plain(s) => s.size(),
+            Self::Plain(s)
+            | Self::Absolute(s) => s.size(),
             _ => RelocationSize::DWord.size(),
         }
     }
     fn write_value(&self, buf: &mut [u8], value: isize) -> Result<(), ImpossibleRelocation> {
-        if let Self::Plain(s) = self {
+        if let Self::Plain(s) | Self::Absolute(s) = self {
             return s.write_value(buf, value);
         };
 
@@ -115,7 +123,7 @@ impl Relocation for Aarch64Relocation {
         Ok(())
     }
     fn read_value(&self, buf: &[u8]) -> isize {
-        if let Self::Plain(s) = self {
+        if let Self::Plain(s) | Self::Absolute(s) = self {
             return s.read_value(buf);
         };
 
@@ -139,7 +147,8 @@ impl Relocation for Aarch64Relocation {
             Self::TBZ => u64::from(
                 (value & mask) >> 5
             ) << 2,
-            Self::Plain(_) => unreachable!()
+            Self::Plain(_)
+            | Self::Absolute(_) => unreachable!()
         };
 
         // Sign extend.
@@ -149,7 +158,8 @@ impl Relocation for Aarch64Relocation {
             Self::ADR => 21,
             Self::ADRP => 33,
             Self::TBZ => 14,
-            Self::Plain(_) => unreachable!()
+            Self::Plain(_)
+            | Self::Absolute(_) => unreachable!()
         };
         let offset = 1u64 << (bits - 1);
         let value: u64 = (unpacked ^ offset) - offset;
@@ -157,11 +167,83 @@ impl Relocation for Aarch64Relocation {
         value as i64 as isize
     }
     fn kind(&self) -> RelocationKind {
-        RelocationKind::Relative
+        match self {
+            Self::Absolute(_) => RelocationKind::AbsToRel,
+            _ => RelocationKind::Relative
+        }
     }
     fn page_size() -> usize {
         4096
     }
+    fn nop() -> &'static [u8] {
+        &[0x1F, 0x20, 0x03, 0xD5]
+    }
+    fn elf_machine() -> Option<u16> {
+        Some(elf::EM_AARCH64)
+    }
+    fn elf_relocation_type(&self) -> Option<u32> {
+        // ADRP relocations are relative to the address of the instruction, not to its page,
+        // so they cannot be expressed as an ELF relocation.
+        match self {
+            Self::B => Some(elf::R_AARCH64_CALL26),
+            Self::BCOND => Some(elf::R_AARCH64_CONDBR19),
+            Self::ADR => Some(elf::R_AARCH64_ADR_PREL_LO21),
+            Self::ADRP => None,
+            Self::TBZ => Some(elf::R_AARCH64_TSTBR14),
+            Self::Plain(RelocationSize::Word) => Some(elf::R_AARCH64_PREL16),
+            Self::Plain(RelocationSize::DWord) => Some(elf::R_AARCH64_PREL32),
+            Self::Plain(RelocationSize::QWord) => Some(elf::R_AARCH64_PREL64),
+            Self::Plain(RelocationSize::Byte) => None,
+            Self::Absolute(RelocationSize::QWord) => Some(elf::R_AARCH64_ABS64),
+            Self::Absolute(_) => None,
+        }
+    }
+    fn veneer(&self, target: usize) -> Option<Vec<u8>> {
+        // only b and bl can be routed through a veneer
+        if let Self::B = self {
+            // ldr x16, 8; br x16; .qword target
+            let mut code = vec![0; 16];
+            LittleEndian::write_u32(&mut code[0 .. 4], 0x5800_0050);
+            LittleEndian::write_u32(&mut code[4 .. 8], 0xD61F_0200);
+            LittleEndian::write_u64(&mut code[8 .. 16], target as u64);
+            Some(code)
+        } else {
+            None
+        }
+    }
+    fn jump_over(length: usize) -> Option<Vec<u8>> {
+        // b, relative to the start of the instruction
+        let mut code = vec![0; 4];
+        LittleEndian::write_u32(&mut code, 0x1400_0000 | ((length as u32 + 4) >> 2));
+        Some(code)
+    }
+}
+
+impl ModuleRelocation for Aarch64Relocation {
+    const NAME: &'static str = "aarch64";
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.push(match self {
+            Self::B => 0,
+            Self::BCOND => 1,
+            Self::ADR => 2,
+            Self::ADRP => 3,
+            Self::TBZ => 4,
+            Self::Plain(size) => 4 + *size as u8,
+            Self::Absolute(size) => 16 + *size as u8
+        });
+    }
+    fn read_from(buf: &[u8]) -> Option<Self> {
+        match *buf {
+            [0] => Some(Self::B),
+            [1] => Some(Self::BCOND),
+            [2] => Some(Self::ADR),
+            [3] => Some(Self::ADRP),
+            [4] => Some(Self::TBZ),
+            [x] if x > 16 => Some(Self::Absolute(module::read_size(x - 16)?)),
+            [x] if x > 4 => Some(Self::Plain(module::read_size(x - 4)?)),
+            _ => None
+        }
+    }
 }
 
 /// An aarch64 Assembler. This is aliased here for backwards compatability.
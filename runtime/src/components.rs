@@ -1,21 +1,76 @@
 //! This module provides several reusable compoments for implementing assemblers
 
 use std::io;
+use std::borrow::Borrow;
+use std::cell::RefCell;
 use std::collections::hash_map::{HashMap, Entry};
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::convert::TryInto;
 use std::mem;
+use std::ptr;
+use std::ops::Range;
+
+use memmap::MmapMut;
+
+use crate::{DynamicLabel, Patchpoint, Section, UncommittedModifier, AssemblyOffset, DynasmError, LabelKind, TargetKind, DynasmLabelApi};
+use crate::mmap::{ExecutableBuffer, MutableBuffer, PlacementHint, protect_data};
+use crate::relocations::{Relocation, RelocationKind, RelocationSize, ImpossibleRelocation, pad};
+
+
+/// Selects how a `MemoryManager` makes newly committed code executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum MappingStrategy {
+    /// The executable buffer is temporarily remapped as writable whenever code is committed or altered.
+    /// When committing, only the pages that the new code ends up in are remapped.
+    /// While this happens, no `Executor` can access the buffer.
+    #[default]
+    ProtectionSwapping,
+    /// The executable buffer is backed by shared memory that is mapped twice: once as executable memory,
+    /// and once as writable memory at a different address. Code is written through the writable alias,
+    /// so committing code never changes page protections and does not have to wait for `Executor`s.
+    /// This is only supported on linux.
+    DualMapped,
+    /// Like `DualMapped`, but the executable buffer is shared with `LockFreeExecutor`s instead of `Executor`s,
+    /// which can access it without taking any lock. As executors can be running code at any time, committed code is
+    /// never modified in place: altering committed code creates a modified copy of the buffer. Old buffers are
+    /// released once no executor uses them anymore.
+    /// As executors of the buffer never lock it, `Assembler::reader` and `MemoryManager::write` panic when this
+    /// strategy is used. Use `Assembler::lock_free_reader` instead.
+    /// This is only supported on linux, and cannot be combined with a fixed reservation.
+    LockFree,
+}
+
 
-use crate::{DynamicLabel, AssemblyOffset, DynasmError, LabelKind, DynasmLabelApi};
-use crate::mmap::{ExecutableBuffer, MutableBuffer};
-use crate::relocations::{Relocation, RelocationKind, RelocationSize, ImpossibleRelocation};
+// The ways in which the executable buffer can be shared with executors
+#[derive(Debug)]
+enum SharedBuffer {
+    Locked(Arc<RwLock<ExecutableBuffer>>),
+    LockFree(Arc<AtomicBuffer>),
+}
 
 
-/// This struct implements a protection-swapping assembling buffer
+/// This struct implements an assembling buffer that manages executable memory.
+/// Depending on its `MappingStrategy` it either swaps the protection of the buffer when
+/// it is written to, or writes through a separately mapped writable alias.
 #[derive(Debug)]
 pub struct MemoryManager {
     // buffer where the end result is copied into
-    execbuffer: Arc<RwLock<ExecutableBuffer>>,
+    execbuffer: SharedBuffer,
+    // writable alias of execbuffer, if it is dual mapped
+    alias: Option<MmapMut>,
+    // how the buffer is written to
+    strategy: MappingStrategy,
+    // if the buffer was reserved up front and may never be moved
+    fixed: bool,
+    // where the buffer should be placed, if anywhere
+    placement: Option<PlacementHint>,
+    // the sizes of the read-only and the writable data section following the executable memory, if any
+    data_sections: Option<(usize, usize)>,
 
     // size of the allocated mmap (so we don't have to go through RwLock to get it)
     execbuffer_size: usize,
@@ -23,20 +78,83 @@ pub struct MemoryManager {
     asmoffset: usize,
 
     // the address that the current execbuffer starts at
-    execbuffer_addr: usize
+    execbuffer_addr: usize,
+    // the buffers the committed code was moved out of since they were last taken, if they are kept
+    replaced: Option<Vec<Arc<ExecutableBuffer>>>,
 }
 
 impl MemoryManager {
     /// Create a new memory manager, with `initial_mmap_size` data allocated
     pub fn new(initial_mmap_size: usize) -> io::Result<Self> {
-        let execbuffer = ExecutableBuffer::new(initial_mmap_size)?;
-        let execbuffer_addr = execbuffer.as_ptr() as usize;
+        Self::with_strategy(initial_mmap_size, MappingStrategy::ProtectionSwapping)
+    }
+
+    /// Create a new memory manager, with `initial_mmap_size` data allocated, that
+    /// uses `strategy` to write to the executable memory.
+    pub fn with_strategy(initial_mmap_size: usize, strategy: MappingStrategy) -> io::Result<Self> {
+        Self::create(initial_mmap_size, strategy, false, None)
+    }
+
+    /// Create a new memory manager, with `initial_mmap_size` data allocated, that uses `strategy` to write to the
+    /// executable memory. The executable memory is placed according to `hint` if possible, also whenever it has to
+    /// be moved to a larger buffer. Use `is_placed` to check if this succeeded.
+    pub fn with_placement(initial_mmap_size: usize, strategy: MappingStrategy, hint: PlacementHint) -> io::Result<Self> {
+        Self::create(initial_mmap_size, strategy, false, Some(hint))
+    }
+
+    /// Create a new memory manager that reserves `reserved_size` bytes of address space up front,
+    /// and uses `strategy` to write to the executable memory. Pages inside this range are only backed
+    /// by memory once code is committed to them. The executable buffer of this manager will never be moved,
+    /// so any address obtained from it stays valid for as long as the buffer lives, and relocations never need
+    /// to be adjusted.
+    pub fn with_reservation(reserved_size: usize, strategy: MappingStrategy) -> io::Result<Self> {
+        if strategy == MappingStrategy::LockFree {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Lock-free executable buffers cannot be reserved up front"));
+        }
+        Self::create(reserved_size, strategy, true, None)
+    }
+
+    /// Create a new memory manager like `with_reservation` that reserves `code_size` bytes of executable memory,
+    /// directly followed by a read-only data section of `rodata_size` bytes and a writable data section of
+    /// `data_size` bytes. Neither data section is executable, and all sizes should be multiples of the page size.
+    /// The data sections are written to with `write_data`, at offsets counted from the start of the executable memory.
+    /// As the memory is reserved up front, `MappingStrategy::LockFree` is not supported.
+    pub fn with_data_sections(code_size: usize, rodata_size: usize, data_size: usize, strategy: MappingStrategy) -> io::Result<Self> {
+        if strategy == MappingStrategy::LockFree {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Lock-free executable buffers cannot have data sections"));
+        }
+        let mut memory = Self::with_reservation(code_size + rodata_size + data_size, strategy)?;
+        memory.execbuffer_size = code_size;
+        memory.data_sections = Some((rodata_size, data_size));
+        memory.protect_data()?;
+        Ok(memory)
+    }
+
+    fn create(initial_mmap_size: usize, strategy: MappingStrategy, fixed: bool, placement: Option<PlacementHint>) -> io::Result<Self> {
+        let (execbuffer, alias) = match (strategy, placement) {
+            (MappingStrategy::ProtectionSwapping, None) => (ExecutableBuffer::new(initial_mmap_size)?, None),
+            (MappingStrategy::ProtectionSwapping, Some(hint)) => (ExecutableBuffer::new_near(initial_mmap_size, hint)?.0, None),
+            (MappingStrategy::DualMapped, _)
+            | (MappingStrategy::LockFree, _) => ExecutableBuffer::new_dual_mapped(initial_mmap_size, placement)?,
+        };
+        let execbuffer_addr = execbuffer.base_addr();
+
+        let execbuffer = match strategy {
+            MappingStrategy::LockFree => SharedBuffer::LockFree(Arc::new(AtomicBuffer::new(execbuffer))),
+            _ => SharedBuffer::Locked(Arc::new(RwLock::new(execbuffer)))
+        };
 
         Ok(MemoryManager {
-            execbuffer: Arc::new(RwLock::new(execbuffer)),
+            execbuffer,
+            alias,
+            strategy,
+            fixed,
+            placement,
+            data_sections: None,
             execbuffer_size: initial_mmap_size,
             asmoffset: 0,
-            execbuffer_addr
+            execbuffer_addr,
+            replaced: None,
         })
     }
 
@@ -50,77 +168,634 @@ impl MemoryManager {
         self.execbuffer_addr
     }
 
+    /// Returns the strategy used to write to the managed executable memory
+    pub fn strategy(&self) -> MappingStrategy {
+        self.strategy
+    }
+
+    /// Returns if the managed executable memory was reserved up front, and thus will never be moved.
+    pub fn is_fixed(&self) -> bool {
+        self.fixed
+    }
+
+    /// Returns if `len` more bytes can be committed to the manager. This is only false if the manager was created
+    /// with a fixed reservation.
+    pub fn fits(&self, len: usize) -> bool {
+        !self.fixed || self.asmoffset + len <= self.execbuffer_size
+    }
+
+    /// Keep the buffers that the committed code is moved out of from now on, until they are taken with
+    /// `take_replaced`. Otherwise they are released as soon as no `Executor` uses them anymore.
+    pub fn keep_replaced(&mut self) {
+        self.replaced.get_or_insert_with(Vec::new);
+    }
+
+    /// Take the buffers that the committed code was moved out of since this was last called, if `keep_replaced`
+    /// was called. These still contain the code as it was when it was moved.
+    pub fn take_replaced(&mut self) -> Vec<Arc<ExecutableBuffer>> {
+        self.replaced.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    // release the buffer that the committed code was moved out of, unless it is kept
+    fn retire(&mut self, old: Arc<ExecutableBuffer>) {
+        if let Some(replaced) = &mut self.replaced {
+            replaced.push(old);
+        }
+    }
+
+    /// Returns if the managed executable memory was placed according to the hint it was created with.
+    /// This can change when the memory has to be moved to a larger buffer. Always false if no hint was given.
+    pub fn is_placed(&self) -> bool {
+        self.placement.is_some_and(|hint| hint.contains(self.execbuffer_addr, self.execbuffer_size))
+    }
+
     /// Commits the data from `new` into the managed memory, calling `f` when the buffer is moved to fix anything
-    /// that relies on the address of the buffer
-    pub fn commit<F>(&mut self, new: &mut Vec<u8>, f: F) where F: FnOnce(&mut [u8], usize, usize) {
+    /// that relies on the address of the buffer. If the memory manager was created with a fixed reservation and
+    /// `new` does not fit in it anymore, nothing is committed and `DynasmError::OutOfMemory` is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a larger buffer could not be allocated.
+    pub fn commit<F>(&mut self, new: &mut Vec<u8>, f: F) -> Result<(), DynasmError> where F: FnOnce(&mut [u8], usize, usize) {
         let old_asmoffset = self.asmoffset;
         let new_asmoffset = self.asmoffset + new.len();
 
         if old_asmoffset >= new_asmoffset {
-            return;
+            return Ok(());
         }
 
         // see if we need to request a new buffer
         if new_asmoffset > self.execbuffer_size {
+            if self.fixed {
+                return Err(DynasmError::OutOfMemory);
+            }
+
             while self.execbuffer_size <= new_asmoffset {
                 self.execbuffer_size *= 2;
             }
 
-            // create a larger writable buffer
-            let mut new_buffer = MutableBuffer::new(self.execbuffer_size).expect("Could not allocate a larger buffer");
-            new_buffer.set_len(new_asmoffset);
+            match self.strategy {
+                MappingStrategy::ProtectionSwapping => {
+                    // create a larger writable buffer
+                    let new_buffer = match self.placement {
+                        Some(hint) => MutableBuffer::new_near(self.execbuffer_size, hint).map(|(buffer, _)| buffer),
+                        None => MutableBuffer::new(self.execbuffer_size)
+                    };
+                    let mut new_buffer = new_buffer.expect("Could not allocate a larger buffer");
+                    new_buffer.set_len(new_asmoffset);
+
+                    // copy over the data
+                    let lock = self.locked().clone();
+                    new_buffer[.. old_asmoffset].copy_from_slice(&lock.read().unwrap());
+                    new_buffer[old_asmoffset..].copy_from_slice(new);
+                    let new_buffer_addr = new_buffer.as_ptr() as usize;
+
+                    // allow modifications to be made
+                    f(&mut new_buffer, self.execbuffer_addr, new_buffer_addr);
+
+                    // swap the buffers
+                    self.execbuffer_addr = new_buffer_addr;
+                    let new_buffer = new_buffer.make_exec().expect("Could not swap buffer protection modes");
+                    let old = mem::replace(&mut *lock.write().unwrap(), new_buffer);
+                    self.retire(Arc::new(old));
+                },
+                MappingStrategy::DualMapped
+                | MappingStrategy::LockFree => {
+                    // copy over the data through the writable aliases
+                    let (new_buffer, mut new_alias) = self.copy_dual_mapped(self.execbuffer_size);
+                    new_alias[old_asmoffset .. new_asmoffset].copy_from_slice(new);
+                    let new_buffer_addr = new_buffer.base_addr();
+
+                    // allow modifications to be made
+                    f(&mut new_alias[.. new_asmoffset], self.execbuffer_addr, new_buffer_addr);
+
+                    self.swap_dual_mapped(new_buffer, new_alias, new_asmoffset);
+                }
+            }
+
+        } else {
+            match self.strategy {
+                MappingStrategy::ProtectionSwapping => {
+                    // temporarily change the protection of the pages the new data ends up in, and copy it in.
+                    // the lock is still required as the last page of already committed code can be affected.
+                    let mut lock = self.write();
+                    lock.write_at(old_asmoffset, new).expect("Could not swap buffer protection modes");
+                },
+                MappingStrategy::DualMapped
+                | MappingStrategy::LockFree => {
+                    // write the new data past the end of the executable part of the buffer, and only then publish it.
+                    // as nothing can observe this part of the buffer yet, this does not have to exclude any readers.
+                    let alias = self.alias.as_mut().expect("Dual mapped buffer without alias");
+                    alias[old_asmoffset .. new_asmoffset].copy_from_slice(new);
+                    match &self.execbuffer {
+                        SharedBuffer::Locked(lock) => lock.read().unwrap().set_len(new_asmoffset),
+                        SharedBuffer::LockFree(atomic) => atomic.load().set_len(new_asmoffset),
+                    }
+                }
+            }
+        }
 
-            // copy over the data
-            new_buffer[.. old_asmoffset].copy_from_slice(&self.execbuffer.read().unwrap());
-            new_buffer[old_asmoffset..].copy_from_slice(&new);
-            let new_buffer_addr = new_buffer.as_ptr() as usize;
+        new.clear();
+        self.asmoffset = new_asmoffset;
+        Ok(())
+    }
 
-            // allow modifications to be made
-            f(&mut new_buffer, self.execbuffer_addr, new_buffer_addr);
+    /// Call `f` with mutable access to the committed part of the managed memory. No `Executor` can access the
+    /// executable buffer while this happens. The second and third arguments to `f` are the address the buffer
+    /// used to be located at, and the address it will be executed at. If these differ, the committed code was moved
+    /// to a new buffer for this, and anything that relies on the address of the buffer should be fixed before the
+    /// buffer is modified further. This only happens when the `LockFree` strategy is used, in which case the new
+    /// buffer is only published if `f` succeeds.
+    pub fn alter<F, O>(&mut self, f: F) -> Result<O, DynasmError> where F: FnOnce(&mut [u8], usize, usize) -> Result<O, DynasmError> {
+        let execbuffer_addr = self.execbuffer_addr;
+        let asmoffset = self.asmoffset;
+
+        match self.strategy {
+            MappingStrategy::ProtectionSwapping => {
+                // swap out a buffer from base
+                let mut lock = self.write();
+                let buffer = mem::take(&mut *lock);
+                let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+
+                let output = f(&mut buffer, execbuffer_addr, execbuffer_addr);
+
+                // repack the buffer. this protects the data sections like code, so restore them as well
+                *lock = buffer.make_exec().expect("Could not swap buffer protection modes");
+                self.protect_data().expect("Could not swap buffer protection modes");
+                output
+            },
+            MappingStrategy::DualMapped => {
+                // The lock is only held to ensure no code is executed while it is being modified.
+                let lock = self.locked().clone();
+                let _guard = lock.write().unwrap();
+                match &mut self.alias {
+                    Some(alias) => f(&mut alias[.. asmoffset], execbuffer_addr, execbuffer_addr),
+                    None => f(&mut [], execbuffer_addr, execbuffer_addr)
+                }
+            },
+            MappingStrategy::LockFree => {
+                // executors cannot be excluded, so copy the buffer and modify that before publishing it.
+                // if this fails, the copy is dropped and executors never observe it.
+                let (new_buffer, mut new_alias) = self.copy_dual_mapped(self.execbuffer_size);
+                let output = f(&mut new_alias[.. asmoffset], execbuffer_addr, new_buffer.base_addr())?;
+                self.swap_dual_mapped(new_buffer, new_alias, asmoffset);
+                Ok(output)
+            }
+        }
+    }
 
-            // swap the buffers
-            self.execbuffer_addr = new_buffer_addr;
-            *self.execbuffer.write().unwrap() = new_buffer.make_exec().expect("Could not swap buffer protection modes")
+    // Create a new dual mapped buffer of size `size` and its writable alias, containing a copy of the committed data.
+    fn copy_dual_mapped(&self, size: usize) -> (ExecutableBuffer, MmapMut) {
+        let (new_buffer, new_alias) = ExecutableBuffer::new_dual_mapped(size, self.placement).expect("Could not allocate a larger buffer");
+        let mut new_alias = new_alias.expect("Could not allocate a larger buffer");
 
-        } else {
+        if let Some(alias) = &self.alias {
+            new_alias[.. self.asmoffset].copy_from_slice(&alias[.. self.asmoffset]);
+        }
+        (new_buffer, new_alias)
+    }
 
-            // temporarily change the buffer protection modes and copy in new data
-            let mut lock = self.write();
-            let buffer = mem::replace(&mut *lock, ExecutableBuffer::default());
-            let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
+    // Replace the executable buffer with the dual mapped buffer `new_buffer`, containing `length` bytes.
+    fn swap_dual_mapped(&mut self, new_buffer: ExecutableBuffer, new_alias: MmapMut, length: usize) {
+        let new_buffer_addr = new_buffer.base_addr();
+        new_buffer.set_len(length);
+        self.execbuffer_addr = new_buffer_addr;
+        let old = match &self.execbuffer {
+            SharedBuffer::Locked(lock) => Arc::new(mem::replace(&mut *lock.write().unwrap(), new_buffer)),
+            SharedBuffer::LockFree(atomic) => atomic.store(new_buffer),
+        };
+        self.retire(old);
+        self.alias = Some(new_alias);
+    }
 
-            // update buffer and length
-            buffer.set_len(new_asmoffset);
-            buffer[old_asmoffset..].copy_from_slice(&new);
+    /// Write `data` into the committed part of the managed memory at `offset` with a single atomic store, so code
+    /// that is executing concurrently observes either the old or the new data. `data` has to be 1, 2, 4 or 8 bytes long,
+    /// and `offset` has to be a multiple of its length. With the `DualMapped` and `LockFree` strategies, this is written
+    /// through the writable alias without blocking executors, although with the `LockFree` strategy only the current
+    /// buffer is modified. With the `ProtectionSwapping` strategy, this waits until no `Executor` accesses the buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` does not fit in the committed memory or is not aligned as described.
+    pub fn write_atomic(&mut self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.asmoffset, "Atomic write past the end of the committed memory");
+        assert!(offset.is_multiple_of(data.len()), "Misaligned atomic write of {} bytes at {}", data.len(), offset);
+
+        match self.strategy {
+            MappingStrategy::ProtectionSwapping => {
+                let _ = self.alter(|buffer, _, _| {
+                    buffer[offset .. offset + data.len()].copy_from_slice(data);
+                    Ok(())
+                });
+            },
+            MappingStrategy::DualMapped
+            | MappingStrategy::LockFree => {
+                let alias = self.alias.as_mut().expect("Dual mapped buffer without alias");
+                let ptr = alias[offset ..].as_mut_ptr();
+
+                // safety: ptr points to data.len() bytes of the alias, and is aligned to data.len() as checked above.
+                unsafe {
+                    match data.len() {
+                        1 => AtomicU8::from_ptr(ptr).store(data[0], Ordering::Release),
+                        2 => AtomicU16::from_ptr(ptr as *mut u16).store(u16::from_ne_bytes(data.try_into().unwrap()), Ordering::Release),
+                        4 => AtomicU32::from_ptr(ptr as *mut u32).store(u32::from_ne_bytes(data.try_into().unwrap()), Ordering::Release),
+                        8 => AtomicU64::from_ptr(ptr as *mut u64).store(u64::from_ne_bytes(data.try_into().unwrap()), Ordering::Release),
+                        n => panic!("Atomic writes of {} bytes are not supported", n)
+                    }
+                }
+            }
+        }
+    }
 
-            // repack the buffer
-            let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
-            mem::replace(&mut *lock, buffer);
+    /// Write `data` into the data sections of the managed memory at `offset`, counted from the start of the
+    /// executable memory. With the `ProtectionSwapping` strategy, the read-only data section is temporarily made
+    /// writable for this.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this manager has no data sections, or if `data` does not fit in them.
+    pub fn write_data(&mut self, offset: usize, data: &[u8]) {
+        let (rodata_size, data_size) = self.data_sections.expect("Memory manager without data sections");
+        let start = self.execbuffer_size;
+        assert!(offset >= start && offset + data.len() <= start + rodata_size + data_size, "Write outside of the data sections");
+
+        match &mut self.alias {
+            Some(alias) => alias[offset .. offset + data.len()].copy_from_slice(data),
+            None => {
+                protect_data(self.execbuffer_addr + start, rodata_size, true).expect("Could not swap buffer protection modes");
+                // safety: the range was checked to lie within the data sections, which are now all writable.
+                unsafe {
+                    ptr::copy_nonoverlapping(data.as_ptr(), (self.execbuffer_addr + offset) as *mut u8, data.len());
+                }
+                self.protect_data().expect("Could not swap buffer protection modes");
+            }
         }
+    }
 
-        new.clear();
-        self.asmoffset = new_asmoffset;
+    // make the read-only data section read-only and the writable data section writable, if there are any
+    fn protect_data(&self) -> io::Result<()> {
+        if let Some((rodata_size, data_size)) = self.data_sections {
+            let rodata_addr = self.execbuffer_addr + self.execbuffer_size;
+            protect_data(rodata_addr, rodata_size, false)?;
+            protect_data(rodata_addr + rodata_size, data_size, true)?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` with read access to the committed part of the managed memory.
+    pub fn read<F, O>(&self, f: F) -> O where F: FnOnce(&[u8]) -> O {
+        match &self.execbuffer {
+            SharedBuffer::Locked(lock) => f(&lock.read().unwrap()),
+            SharedBuffer::LockFree(atomic) => f(&atomic.load()),
+        }
+    }
+
+    fn locked(&self) -> &Arc<RwLock<ExecutableBuffer>> {
+        match &self.execbuffer {
+            SharedBuffer::Locked(lock) => lock,
+            SharedBuffer::LockFree(_) => panic!("Lock-free executable buffers cannot be locked")
+        }
     }
 
     /// Borrow the internal memory buffer mutably
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `LockFree` strategy is used.
     pub fn write(&self) -> RwLockWriteGuard<ExecutableBuffer> {
-        self.execbuffer.write().unwrap()
+        self.locked().write().unwrap()
     }
 
     /// finalizes the currently committed part of the buffer.
+    // the manager is handed back whole on failure so it can keep being used, like `Assembler::finalize` does.
+    #[allow(clippy::result_large_err)]
     pub fn finalize(self) -> Result<ExecutableBuffer, Self> {
-        match Arc::try_unwrap(self.execbuffer) {
-            Ok(execbuffer) => Ok(execbuffer.into_inner().unwrap()),
-            Err(arc) => Err(Self {
-                execbuffer: arc,
-                ..self
-            })
+        match self.execbuffer {
+            SharedBuffer::Locked(lock) => match Arc::try_unwrap(lock) {
+                Ok(execbuffer) => Ok(execbuffer.into_inner().unwrap()),
+                Err(arc) => Err(Self {
+                    execbuffer: SharedBuffer::Locked(arc),
+                    ..self
+                })
+            },
+            SharedBuffer::LockFree(atomic) => match Arc::try_unwrap(atomic).map(AtomicBuffer::into_inner) {
+                Ok(Ok(execbuffer)) => Ok(execbuffer),
+                Ok(Err(atomic)) => Err(Self {
+                    execbuffer: SharedBuffer::LockFree(Arc::new(atomic)),
+                    ..self
+                }),
+                Err(arc) => Err(Self {
+                    execbuffer: SharedBuffer::LockFree(arc),
+                    ..self
+                })
+            }
         }
     }
 
     /// Create an atomically refcounted reference to the internal executable buffer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `LockFree` strategy is used. Use `MemoryManager::lock_free_reader` instead.
     pub fn reader(&self) -> Arc<RwLock<ExecutableBuffer>> {
-        self.execbuffer.clone()
+        self.locked().clone()
+    }
+
+    /// Create an atomically refcounted reference to the internal executable buffer that can be read without locking.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `LockFree` strategy is not used.
+    pub fn lock_free_reader(&self) -> Arc<AtomicBuffer> {
+        match &self.execbuffer {
+            SharedBuffer::LockFree(atomic) => atomic.clone(),
+            SharedBuffer::Locked(_) => panic!("Only lock-free executable buffers can be read without locking")
+        }
+    }
+}
+
+
+/// A container for an `ExecutableBuffer` that can be replaced atomically, while readers can obtain a reference to the
+/// current buffer without ever taking a lock. Replaced buffers are only released once all readers are done with them.
+#[derive(Debug)]
+pub struct AtomicBuffer {
+    // the current buffer, as produced by Arc::into_raw.
+    current: AtomicPtr<ExecutableBuffer>,
+    // readers register themselves in the counter for the current epoch while obtaining a reference to the current buffer.
+    // this lets a writer determine when nobody can still be obtaining a reference to a replaced buffer.
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+}
+
+impl AtomicBuffer {
+    /// Create a new `AtomicBuffer` containing `buffer`.
+    pub fn new(buffer: ExecutableBuffer) -> AtomicBuffer {
+        AtomicBuffer {
+            current: AtomicPtr::new(Arc::into_raw(Arc::new(buffer)) as *mut _),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Obtain a reference to the current buffer, without locking.
+    pub fn load(&self) -> Arc<ExecutableBuffer> {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let readers = &self.readers[epoch & 1];
+            readers.fetch_add(1, Ordering::SeqCst);
+
+            // if a writer swapped epochs in between, it might not be waiting for us.
+            if self.epoch.load(Ordering::SeqCst) != epoch {
+                readers.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+
+            let current = self.current.load(Ordering::SeqCst);
+            let buffer = unsafe {
+                Arc::increment_strong_count(current);
+                Arc::from_raw(current)
+            };
+
+            readers.fetch_sub(1, Ordering::SeqCst);
+            return buffer;
+        }
+    }
+
+    /// Replace the current buffer with `buffer`, and return the old buffer. It is released once all readers and the
+    /// caller are done with it. This should only be called by the owner of the executable memory.
+    pub(crate) fn store(&self, buffer: ExecutableBuffer) -> Arc<ExecutableBuffer> {
+        let new = Arc::into_raw(Arc::new(buffer)) as *mut _;
+        let old = self.current.swap(new, Ordering::SeqCst);
+
+        // wait until no reader can still be in the process of obtaining a reference to the old buffer.
+        // this only takes as long as it takes a reader to increment a reference count.
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        while self.readers[epoch & 1].load(Ordering::SeqCst) != 0 {
+            std::hint::spin_loop();
+        }
+
+        unsafe { Arc::from_raw(old) }
+    }
+
+    // Take the buffer out of this container if there are no other references to it.
+    fn into_inner(self) -> Result<ExecutableBuffer, AtomicBuffer> {
+        let current = self.current.swap(ptr::null_mut(), Ordering::SeqCst);
+        match Arc::try_unwrap(unsafe { Arc::from_raw(current) }) {
+            Ok(buffer) => Ok(buffer),
+            Err(arc) => {
+                self.current.store(Arc::into_raw(arc) as *mut _, Ordering::SeqCst);
+                Err(self)
+            }
+        }
+    }
+}
+
+impl Drop for AtomicBuffer {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Arc::from_raw(current) });
+        }
+    }
+}
+
+
+// the names interned by `intern`
+static NAMES: Mutex<Option<HashSet<&'static str>>> = Mutex::new(None);
+
+thread_local! {
+    // the interned names this thread has seen, so looking these up again does not have to take the lock on `NAMES`
+    static SEEN: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+}
+
+/// Returns a `&'static str` with the same contents as `name`, so it can be used as the name of a global label.
+/// Names are interned: every distinct name is only allocated once, and is never freed. The memory this leaks is
+/// therefore bounded by the total length of all distinct names, so names should be drawn from a limited set, like the
+/// names of the functions of a program, rather than be unique for every piece of assembled code. Only defining or
+/// referencing a label interns its name, looking a label up with `LabelRegistry::resolve_global_named` does not.
+/// The interned names are shared by all threads, but a thread only has to synchronize with the others the first
+/// time it interns a name.
+pub fn intern(name: &str) -> &'static str {
+    if let Some(interned) = SEEN.with(|seen| seen.borrow().get(name).cloned()) {
+        return interned;
+    }
+
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    let names = names.get_or_insert_with(HashSet::new);
+    let interned = match names.get(name) {
+        Some(&interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.to_string().into_boxed_str());
+            names.insert(interned);
+            interned
+        }
+    };
+    SEEN.with(|seen| seen.borrow_mut().insert(interned));
+    interned
+}
+
+/// Returns the interned copy of `name`, if `intern` was called for it before. Unlike `intern`, this never allocates.
+pub fn interned(name: &str) -> Option<&'static str> {
+    if let Some(interned) = SEEN.with(|seen| seen.borrow().get(name).cloned()) {
+        return Some(interned);
+    }
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    names.as_ref().and_then(|names| names.get(name).cloned())
+}
+
+
+/// The amount of bits of an offset in a provisional section. The remaining bits select the section.
+const SECTION_SHIFT: u32 = usize::BITS - 2;
+
+/// The uncommitted contents of a section of an assembler other than its hot section.
+#[derive(Debug, Clone)]
+pub(crate) struct SectionBuffer {
+    // the range of offsets the section occupies
+    base: usize,
+    limit: usize,
+    // the offset the uncommitted contents start at, and these contents
+    start: usize,
+    pub ops: Vec<u8>,
+    // the largest alignment requested in the uncommitted contents
+    pub align: usize,
+    // if the section was switched to since its contents were last placed
+    pub used: bool,
+}
+
+/// The state of a `SectionBuffer` at a checkpoint.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SectionCheckpoint {
+    base: usize,
+    limit: usize,
+    start: usize,
+    end: usize,
+    align: usize,
+    used: bool,
+}
+
+impl SectionBuffer {
+    /// Create a section that occupies the offsets `base .. limit`.
+    pub fn new(base: usize, limit: usize) -> Self {
+        Self {
+            base,
+            limit,
+            start: base,
+            ops: Vec::new(),
+            align: 1,
+            used: false,
+        }
+    }
+
+    /// Create a section whose contents only get their final offsets when they are placed after the hot code. Until
+    /// then, they are assembled at offsets derived from `section` that cannot be confused with any other offsets.
+    pub fn provisional(section: Section) -> Self {
+        let base = (section as usize) << SECTION_SHIFT;
+        Self::new(base, base + ((1 << SECTION_SHIFT) - 1))
+    }
+
+    /// Returns if this section occupies any offsets.
+    pub fn exists(&self) -> bool {
+        self.limit > self.base
+    }
+
+    /// Returns if `offset` lies in this section.
+    pub fn contains(&self, offset: usize) -> bool {
+        offset >= self.base && offset < self.limit
+    }
+
+    /// Returns if the uncommitted contents of this section fit in it.
+    pub fn fits(&self) -> bool {
+        self.start + self.ops.len() <= self.limit
+    }
+
+    /// Returns the offset the next byte assembled into this section ends up at.
+    pub fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.start + self.ops.len())
+    }
+
+    /// Returns the range of offsets this section occupies.
+    pub fn range(&self) -> Range<usize> {
+        self.base .. self.limit
+    }
+
+    /// Returns the offset the uncommitted contents of this section start at.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Create an `UncommittedModifier` for the uncommitted contents of this section.
+    pub fn modifier(&mut self) -> UncommittedModifier<'_> {
+        UncommittedModifier::new(&mut self.ops, AssemblyOffset(self.start))
+    }
+
+    /// Take the uncommitted contents of this provisional section and their alignment, to place them elsewhere.
+    pub fn take(&mut self) -> (Vec<u8>, usize) {
+        (mem::take(&mut self.ops), mem::replace(&mut self.align, 1))
+    }
+
+    /// Returns a function that maps offsets in this provisional section to where they end up once the contents taken
+    /// from it are placed at `start`. Other offsets are left unchanged.
+    pub fn placement(&self, start: usize) -> impl Fn(AssemblyOffset) -> AssemblyOffset + Copy {
+        let (base, limit) = (self.base, self.limit);
+        move |offset| if offset.0 >= base && offset.0 < limit {
+            AssemblyOffset(offset.0 - base + start)
+        } else {
+            offset
+        }
+    }
+
+    /// Write the uncommitted contents of this data section to `memory`, after which they are committed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the contents do not fit in the section, which can be checked with `fits` beforehand.
+    pub fn commit(&mut self, memory: &mut MemoryManager) {
+        if self.ops.is_empty() {
+            return;
+        }
+
+        let end = self.start + self.ops.len();
+        assert!(end <= self.limit, "Data section exhausted: {} bytes required, {} bytes reserved", end - self.base, self.limit - self.base);
+        memory.write_data(self.start, &self.ops);
+        self.start = end;
+        self.ops.clear();
+        self.align = 1;
+    }
+
+    /// Create a checkpoint of the current state of this section.
+    pub fn checkpoint(&self) -> SectionCheckpoint {
+        SectionCheckpoint {
+            base: self.base,
+            limit: self.limit,
+            start: self.start,
+            end: self.offset().0,
+            align: self.align,
+            used: self.used,
+        }
+    }
+
+    /// Returns if contents of this section were committed since `checkpoint` was created.
+    pub fn committed_since(&self, checkpoint: &SectionCheckpoint) -> bool {
+        self.start != checkpoint.start
+    }
+
+    /// Discard the contents assembled into this section since `checkpoint` was created.
+    pub fn rollback(&mut self, checkpoint: &SectionCheckpoint) {
+        self.ops.truncate(checkpoint.end - self.start);
+        self.align = checkpoint.align;
+        self.used = checkpoint.used;
+    }
+}
+
+impl SectionCheckpoint {
+    /// Returns if `offset` lies in the section this checkpoint was created for.
+    pub fn contains(&self, offset: usize) -> bool {
+        offset >= self.base && offset < self.limit
+    }
+
+    /// Returns the offset the section ended at when this checkpoint was created.
+    pub fn end(&self) -> usize {
+        self.end
     }
 }
 
@@ -134,8 +809,32 @@ pub struct LabelRegistry {
     global_labels: HashMap<&'static str, AssemblyOffset>,
     // mapping of local labels to offsets
     local_labels: HashMap<&'static str, AssemblyOffset>,
+    // local labels of the enclosing label scopes
+    local_scopes: Vec<HashMap<&'static str, AssemblyOffset>>,
     // mapping of dynamic label ids to offsets
     dynamic_labels: Vec<Option<AssemblyOffset>>,
+    // for every dynamic label id, its current version and the generation it was created in, if it is not freed
+    dynamic_versions: Vec<(u32, Option<usize>)>,
+    // the ids of the live dynamic labels of every generation, and for every live id its index in these
+    generations: HashMap<usize, Vec<usize>>,
+    generation_slots: Vec<usize>,
+    // ids of freed dynamic labels, which are reused before new ids are created
+    free_dynamics: Vec<usize>,
+    // the generation new dynamic labels are created in
+    generation: usize,
+    // global and dynamic labels defined since `forget_history` was last called, in order
+    history: Vec<LabelKind>,
+    // global and dynamic labels whose definitions changed since they were last published, if changes are tracked
+    changes: Option<Vec<LabelKind>>,
+}
+
+
+/// The state of a `LabelRegistry` at some point, created by `LabelRegistry::checkpoint`.
+#[derive(Debug, Clone)]
+pub struct LabelCheckpoint {
+    local_labels: HashMap<&'static str, AssemblyOffset>,
+    local_scopes: Vec<HashMap<&'static str, AssemblyOffset>>,
+    history: usize,
 }
 
 impl LabelRegistry {
@@ -144,25 +843,127 @@ impl LabelRegistry {
         LabelRegistry {
             global_labels: HashMap::new(),
             local_labels: HashMap::new(),
+            local_scopes: Vec::new(),
             dynamic_labels: Vec::new(),
+            dynamic_versions: Vec::new(),
+            generations: HashMap::new(),
+            generation_slots: Vec::new(),
+            free_dynamics: Vec::new(),
+            generation: 0,
+            history: Vec::new(),
+            changes: None,
         }
     }
 
-    /// Create a new dynamic label id
+    /// Create a new dynamic label id. Ids of freed dynamic labels are reused.
     pub fn new_dynamic_label(&mut self) -> DynamicLabel {
-        let id = self.dynamic_labels.len();
-        self.dynamic_labels.push(None);
-        DynamicLabel(id)
+        let id = match self.free_dynamics.pop() {
+            Some(id) => id,
+            None => {
+                self.dynamic_labels.push(None);
+                self.dynamic_versions.push((0, None));
+                self.generation_slots.push(0);
+                self.dynamic_labels.len() - 1
+            }
+        };
+
+        let entry = &mut self.dynamic_versions[id];
+        entry.1 = Some(self.generation);
+        let members = self.generations.entry(self.generation).or_default();
+        self.generation_slots[id] = members.len();
+        members.push(id);
+        DynamicLabel(id, entry.0)
+    }
+
+    /// Free the dynamic label `id`, allowing its id to be reused by `new_dynamic_label`. No relocations to `id` may be
+    /// pending, and `id` must not be used afterwards. In debug builds, using a freed dynamic label panics.
+    pub fn free_dynamic(&mut self, id: DynamicLabel) {
+        if !self.check_dynamic(id) {
+            return;
+        }
+
+        // remove it from the live labels of its generation
+        if let Some(generation) = self.dynamic_versions[id.0].1 {
+            if let Entry::Occupied(mut members) = self.generations.entry(generation) {
+                let slot = self.generation_slots[id.0];
+                members.get_mut().swap_remove(slot);
+                match members.get().get(slot) {
+                    Some(&moved) => self.generation_slots[moved] = slot,
+                    None if members.get().is_empty() => {
+                        members.remove();
+                    },
+                    None => ()
+                }
+            }
+        }
+        self.release_dynamic(id.0);
+    }
+
+    // free the dynamic label id `id`, without removing it from its generation
+    fn release_dynamic(&mut self, id: usize) {
+        self.dynamic_labels[id] = None;
+        let entry = &mut self.dynamic_versions[id];
+        if let Some(changes) = &mut self.changes {
+            changes.push(LabelKind::Dynamic(DynamicLabel(id, entry.0)));
+        }
+        *entry = (entry.0.wrapping_add(1), None);
+        self.free_dynamics.push(id);
+    }
+
+    /// Start a new generation of dynamic labels and return it. Dynamic labels created from now on belong to this
+    /// generation, until the next one is started.
+    pub fn new_generation(&mut self) -> usize {
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Free all dynamic labels created in `generation` that were not freed yet. The same rules as for `free_dynamic`
+    /// apply to each of them.
+    pub fn free_generation(&mut self, generation: usize) {
+        for id in self.generations.remove(&generation).unwrap_or_default() {
+            self.release_dynamic(id);
+        }
+    }
+
+    /// Returns if the dynamic label `id` was created by this registry and has not been freed.
+    pub fn is_live(&self, id: DynamicLabel) -> bool {
+        self.dynamic_versions.get(id.0).is_some_and(|&(version, created)| version == id.1 && created.is_some())
+    }
+
+    // returns if `id` is live. Using a freed dynamic label is a bug, which panics in debug builds. Ids this registry
+    // never created are not reported here, as these are caught by the caller.
+    fn check_dynamic(&self, id: DynamicLabel) -> bool {
+        let live = self.is_live(id);
+        debug_assert!(live || id.0 >= self.dynamic_versions.len(), "use of freed dynamic label {:?}", id);
+        live
     }
 
     /// Define a the dynamic label `id` to be located at `offset`.
     pub fn define_dynamic(&mut self, id: DynamicLabel, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        if !self.check_dynamic(id) {
+            return Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id)));
+        }
+
         let entry = &mut self.dynamic_labels[id.0];
         if entry.is_some() {
             return Err(DynasmError::DuplicateLabel(LabelKind::Dynamic(id)));
         }
 
         *entry = Some(offset);
+        self.history.push(LabelKind::Dynamic(id));
+        self.record_change(LabelKind::Dynamic(id));
+        Ok(())
+    }
+
+    /// Define the dynamic label `id` to be located at `offset`, replacing any previous definition. References to `id`
+    /// that were already encoded are not updated by this.
+    pub fn redefine_dynamic(&mut self, id: DynamicLabel, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        if !self.check_dynamic(id) {
+            return Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id)));
+        }
+
+        self.dynamic_labels[id.0] = Some(offset);
+        self.record_change(LabelKind::Dynamic(id));
         Ok(())
     }
 
@@ -172,19 +973,57 @@ impl LabelRegistry {
             Entry::Occupied(_) => Err(DynasmError::DuplicateLabel(LabelKind::Global(name))),
             Entry::Vacant(v) => {
                 v.insert(offset);
+                self.history.push(LabelKind::Global(name));
+                self.record_change(LabelKind::Global(name));
                 Ok(())
             }
         }
     }
 
+    /// Define the global label `name`, which does not have to be known at compile time, to be located at `offset`.
+    /// The name is interned with `intern`, so it is never freed.
+    pub fn define_global_named(&mut self, name: &str, offset: AssemblyOffset) -> Result<(), DynasmError> {
+        self.define_global(intern(name), offset)
+    }
+
     /// Define a the local label `name` to be located at `offset`.
     pub fn define_local(&mut self, name: &'static str, offset: AssemblyOffset) {
         self.local_labels.insert(name, offset);
     }
 
-    /// Returns the offset at which the dynamic label `id` was defined, if one was defined.
+    /// Enter a new scope for local labels. Local labels defined before cannot be resolved until the scope is left,
+    /// and local labels defined in the scope are forgotten when it is left.
+    pub fn push_scope(&mut self) {
+        self.local_scopes.push(mem::take(&mut self.local_labels));
+    }
+
+    /// Leave the current scope for local labels.
+    pub fn pop_scope(&mut self) -> Result<(), DynasmError> {
+        self.local_labels = self.local_scopes.pop().ok_or(DynasmError::UnbalancedScope)?;
+        Ok(())
+    }
+
+    /// Enter a new scope for local labels in this registry and in `relocs`, which holds the relocations targeting
+    /// its labels. This implements `DynasmLabelApi::push_label_scope`.
+    pub fn push_label_scope<R: Relocation>(&mut self, relocs: &mut RelocRegistry<R>) {
+        self.push_scope();
+        relocs.push_scope();
+    }
+
+    /// Leave the current scope for local labels in this registry and in `relocs`. This implements
+    /// `DynasmLabelApi::pop_label_scope`.
+    pub fn pop_label_scope<R: Relocation>(&mut self, relocs: &mut RelocRegistry<R>) -> Result<(), DynasmError> {
+        let labels = self.pop_scope();
+        relocs.pop_scope().and(labels)
+    }
+
+    /// Returns the offset at which the dynamic label `id` was defined, if one was defined. Dynamic labels that were
+    /// freed or that were not created by this registry are unknown.
     pub fn resolve_dynamic(&self, id: DynamicLabel) -> Result<AssemblyOffset, DynasmError> {
-        self.dynamic_labels.get(id.0).and_then(|&e| e).ok_or_else(|| DynasmError::UnknownLabel(LabelKind::Dynamic(id)))
+        match self.dynamic_labels.get(id.0) {
+            Some(&Some(offset)) if self.is_live(id) => Ok(offset),
+            _ => Err(DynasmError::UnknownLabel(LabelKind::Dynamic(id)))
+        }
     }
 
     /// Returns the offset at which the global label `name` was defined, if one was defined.
@@ -192,10 +1031,91 @@ impl LabelRegistry {
         self.global_labels.get(&name).cloned().ok_or_else(|| DynasmError::UnknownLabel(LabelKind::Global(name)))
     }
 
+    /// Returns the offset at which the global label `name` was defined, if one was defined. Unlike `resolve_global`,
+    /// `name` does not have to be known at compile time. As reporting an unknown label as an error would require
+    /// interning its name, this returns `None` instead.
+    pub fn resolve_global_named(&self, name: &str) -> Option<AssemblyOffset> {
+        self.global_labels.get(name).cloned()
+    }
+
     /// Returns the offset at which the last local label named `id` was defined, if one was defined.
     pub fn resolve_local(&self, name: &'static str) -> Result<AssemblyOffset, DynasmError> {
         self.local_labels.get(&name).cloned().ok_or_else(|| DynasmError::UnknownLabel(LabelKind::Local(name)))
     }
+
+    /// Iterate through all defined global labels and the offsets they were defined at.
+    pub fn globals<'a>(&'a self) -> impl Iterator<Item=(&'static str, AssemblyOffset)> + 'a {
+        self.global_labels.iter().map(|(&name, &offset)| (name, offset))
+    }
+
+    /// Move all defined labels to the offsets returned by `f` for their current offsets.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for offset in self.global_labels.values_mut().chain(self.local_labels.values_mut()) {
+            *offset = f(*offset);
+        }
+        for offset in self.local_scopes.iter_mut().flat_map(|scope| scope.values_mut()) {
+            *offset = f(*offset);
+        }
+        for offset in self.dynamic_labels.iter_mut().flatten() {
+            *offset = f(*offset);
+        }
+    }
+
+    /// Create a checkpoint of the labels of this registry, which it can be rolled back to with `rollback`.
+    pub fn checkpoint(&self) -> LabelCheckpoint {
+        LabelCheckpoint {
+            local_labels: self.local_labels.clone(),
+            local_scopes: self.local_scopes.clone(),
+            history: self.history.len(),
+        }
+    }
+
+    /// Forget all global and dynamic labels defined since `checkpoint` was created, and restore the local labels
+    /// from it. Dynamic label ids created since stay valid, but are undefined. Labels defined before the last call
+    /// to `forget_history` are never forgotten.
+    pub fn rollback(&mut self, checkpoint: &LabelCheckpoint) {
+        while self.history.len() > checkpoint.history {
+            match self.history.pop() {
+                Some(LabelKind::Global(name)) => {
+                    self.global_labels.remove(name);
+                },
+                Some(LabelKind::Dynamic(id)) => self.dynamic_labels[id.0] = None,
+                _ => ()
+            }
+        }
+        self.local_labels = checkpoint.local_labels.clone();
+        self.local_scopes = checkpoint.local_scopes.clone();
+    }
+
+    /// Forget the order in which labels were defined so far, after which they can no longer be rolled back.
+    /// This should be called whenever code is committed.
+    pub fn forget_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Record which global and dynamic labels are defined, redefined or freed from now on, so `LabelExports::publish`
+    /// only has to publish these. All labels defined so far are recorded as changed.
+    pub fn track_changes(&mut self) {
+        if self.changes.is_none() {
+            let globals = self.global_labels.keys().map(|&name| LabelKind::Global(name));
+            let dynamics = self.dynamics().filter(|(_, offset)| offset.is_some()).map(|(id, _)| LabelKind::Dynamic(id));
+            self.changes = Some(globals.chain(dynamics).collect());
+        }
+    }
+
+    // record that the definition of `label` changed, if changes are tracked
+    fn record_change(&mut self, label: LabelKind) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(label);
+        }
+    }
+
+    /// Iterate through all created dynamic labels and the offsets they were defined at, if they were defined.
+    /// Freed dynamic labels are included as undefined ones, so every id is yielded at its own index.
+    pub fn dynamics<'a>(&'a self) -> impl Iterator<Item=(DynamicLabel, Option<AssemblyOffset>)> + 'a {
+        self.dynamic_labels.iter().zip(&self.dynamic_versions).enumerate()
+            .map(|(id, (&offset, &(version, _)))| (DynamicLabel(id, version), offset))
+    }
 }
 
 
@@ -227,11 +1147,7 @@ impl<R: Relocation> PatchLoc<R> {
     }
 
     fn value(&self, target: usize, buf_addr: usize) -> isize {
-        (match self.relocation.kind() {
-            RelocationKind::Relative => target.wrapping_sub(self.location.0 - self.relocation.start_offset()),
-            RelocationKind::RelToAbs => target.wrapping_sub(self.location.0 - self.relocation.start_offset() + buf_addr),
-            RelocationKind::AbsToRel => target + buf_addr
-        }) as isize + self.offset
+        relocation_value(&self.relocation, self.location, self.offset, target, buf_addr)
     }
 
     /// Patch `buffer` so that this relocation patch will point to `target`.
@@ -268,12 +1184,25 @@ impl<R: Relocation> PatchLoc<R> {
 }
 
 
+// The value a relocation at `location` with the specified offset should be encoded with to point to `target`.
+fn relocation_value<R: Relocation>(relocation: &R, location: AssemblyOffset, offset: isize, target: usize, buf_addr: usize) -> isize {
+    (match relocation.kind() {
+        RelocationKind::Relative => target.wrapping_sub(location.0 - relocation.start_offset()),
+        RelocationKind::RelToAbs => target.wrapping_sub(location.0 - relocation.start_offset() + buf_addr),
+        RelocationKind::AbsToRel => target.wrapping_add(buf_addr)
+    }) as isize + offset
+}
+
+
 /// A registry of relocations and the respective labels they point towards.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct RelocRegistry<R: Relocation> {
     global: Vec<(PatchLoc<R>, &'static str)>,
     dynamic: Vec<(PatchLoc<R>, DynamicLabel)>,
-    local: HashMap<&'static str, Vec<PatchLoc<R>>>
+    symbol: Vec<(PatchLoc<R>, &'static str)>,
+    local: HashMap<&'static str, Vec<PatchLoc<R>>>,
+    // relocations targeting local labels of the enclosing label scopes
+    local_scopes: Vec<HashMap<&'static str, Vec<PatchLoc<R>>>>
 }
 
 impl<R: Relocation> RelocRegistry<R> {
@@ -282,7 +1211,9 @@ impl<R: Relocation> RelocRegistry<R> {
         RelocRegistry {
             global: Vec::new(),
             dynamic: Vec::new(),
-            local: HashMap::new()
+            symbol: Vec::new(),
+            local: HashMap::new(),
+            local_scopes: Vec::new()
         }
     }
 
@@ -291,11 +1222,22 @@ impl<R: Relocation> RelocRegistry<R> {
         self.global.push((patchloc, name));
     }
 
+    /// Add a new patch targetting the global label `name`, which does not have to be known at compile time.
+    /// The name is interned with `intern`, so it is never freed.
+    pub fn add_global_named(&mut self, name: &str, patchloc: PatchLoc<R>) {
+        self.add_global(intern(name), patchloc);
+    }
+
     /// Add a new patch targetting the dynamic label `id`.
     pub fn add_dynamic(&mut self, id: DynamicLabel, patchloc: PatchLoc<R>) {
         self.dynamic.push((patchloc, id))
     }
 
+    /// Add a new patch targetting the symbol `name`.
+    pub fn add_symbol(&mut self, name: &'static str, patchloc: PatchLoc<R>) {
+        self.symbol.push((patchloc, name))
+    }
+
     /// Add a new patch targetting the next local label `name`.
     /// As any relocation targetting a previous local label can be immediately resolved these should not be recorded.
     pub fn add_local(&mut self, name: &'static str, patchloc: PatchLoc<R>) {
@@ -307,6 +1249,23 @@ impl<R: Relocation> RelocRegistry<R> {
         }
     }
 
+    /// Enter a new scope for local labels. Relocations targeting the next local label recorded in the scope can only
+    /// be resolved to labels defined in it, and relocations recorded before are not resolved until the scope is left.
+    pub fn push_scope(&mut self) {
+        self.local_scopes.push(mem::take(&mut self.local));
+    }
+
+    /// Leave the current scope for local labels. Any relocations in it targeting local labels that were not defined
+    /// in it are removed, and reported as an error.
+    pub fn pop_scope(&mut self) -> Result<(), DynasmError> {
+        let outer = self.local_scopes.pop().ok_or(DynasmError::UnbalancedScope)?;
+        let inner = mem::replace(&mut self.local, outer);
+        match inner.into_iter().find(|(_, locs)| !locs.is_empty()) {
+            Some((name, _)) => Err(DynasmError::UnknownLabel(LabelKind::Local(name))),
+            None => Ok(())
+        }
+    }
+
     /// Return an iterator through all defined relocations targetting local label `name`.
     /// These relocations are removed from the registry.
     pub fn take_locals_named<'a>(&'a mut self, name: &'static str) -> impl Iterator<Item=PatchLoc<R>> + 'a {
@@ -325,37 +1284,143 @@ impl<R: Relocation> RelocRegistry<R> {
         self.dynamic.drain(..)
     }
 
-    /// Return an iterator through all defined relocations targeting local labels and the labels they target.
+    /// Return an iterator through the symbols targeted by all defined relocations targeting symbols, in the order
+    /// `take_symbols` returns these relocations.
+    pub fn symbols<'a>(&'a self) -> impl Iterator<Item=&'static str> + 'a {
+        self.symbol.iter().map(|&(_, name)| name)
+    }
+
+    /// Return an iterator through all defined relocations targeting symbols, in the order `take_symbols` returns them.
+    pub fn symbol_relocations<'a>(&'a self) -> impl Iterator<Item=&'a PatchLoc<R>> + 'a {
+        self.symbol.iter().map(|(loc, _)| loc)
+    }
+
+    /// Return an iterator through all defined relocations targeting symbols and the symbols they target.
     /// These relocations are removed from the registry.
+    pub fn take_symbols<'a>(&'a mut self) -> impl Iterator<Item=(PatchLoc<R>, &'static str)> + 'a {
+        self.symbol.drain(..)
+    }
+
+    /// Return an iterator through all defined relocations targeting local labels and the labels they target,
+    /// including those in enclosing label scopes. These relocations are removed from the registry.
     pub fn take_locals<'a>(&'a mut self) -> impl Iterator<Item=(PatchLoc<R>, &'static str)> + 'a {
-        self.local.iter_mut().flat_map(|(&k, v)| v.drain(..).map(move |p| (p, k)))
+        self.local_scopes.iter_mut().chain(Some(&mut self.local))
+            .flat_map(|scope| scope.iter_mut())
+            .flat_map(|(&k, v)| v.drain(..).map(move |p| (p, k)))
     }
-}
+
+    /// Update the locations of all relocations with `f`, after the code they reside in has been moved.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        let locals = self.local_scopes.iter_mut().chain(Some(&mut self.local))
+            .flat_map(|scope| scope.values_mut())
+            .flatten();
+        let locs = self.global.iter_mut().map(|(loc, _)| loc)
+            .chain(self.dynamic.iter_mut().map(|(loc, _)| loc))
+            .chain(self.symbol.iter_mut().map(|(loc, _)| loc))
+            .chain(locals);
+        for loc in locs {
+            loc.location = f(loc.location);
+        }
+    }
+}
+
+
+/// Resolves the names of symbols targeted by `extern "name"` to their addresses.
+pub trait SymbolResolver {
+    /// Returns the address of the symbol `name`, if it is known.
+    fn resolve(&self, name: &str) -> Option<usize>;
+}
+
+impl<F: Fn(&str) -> Option<usize>> SymbolResolver for F {
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self(name)
+    }
+}
+
+impl<K: Borrow<str> + Hash + Eq, S: BuildHasher> SymbolResolver for HashMap<K, usize, S> {
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.get(name).cloned()
+    }
+}
+
+
+/// Holds the `SymbolResolver` of an assembler. Without a resolver, no symbols are known.
+#[derive(Default)]
+pub struct SymbolRegistry {
+    resolver: Option<Box<dyn SymbolResolver + Send>>
+}
+
+impl SymbolRegistry {
+    /// Create a new symbol registry without a resolver.
+    pub fn new() -> SymbolRegistry {
+        SymbolRegistry {
+            resolver: None
+        }
+    }
+
+    /// Use `resolver` to resolve symbols from now on.
+    pub fn set_resolver<S: SymbolResolver + Send + 'static>(&mut self, resolver: S) {
+        self.resolver = Some(Box::new(resolver));
+    }
+
+    /// Resolve the symbol `name` to its address.
+    pub fn resolve(&self, name: &'static str) -> Result<usize, DynasmError> {
+        self.resolver.as_ref()
+            .and_then(|resolver| resolver.resolve(name))
+            .ok_or(DynasmError::UnknownSymbol(name))
+    }
+}
+
+impl fmt::Debug for SymbolRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SymbolRegistry")
+            .field("resolver", &self.resolver.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
 
 
 /// A registry of relocations that have been encoded previously, but need to be adjusted when the address of the buffer they
 /// reside in changes.
 #[derive(Debug, Default)]
 pub struct ManagedRelocs<R: Relocation> {
-    managed: BTreeMap<usize, PatchLoc<R>>
+    managed: BTreeMap<usize, PatchLoc<R>>,
+    // if relocations are not recorded, as the buffer they reside in never moves
+    disabled: bool,
 }
 
 impl<R: Relocation> ManagedRelocs<R> {
     /// Create a new, empty managed relocation registry.
     pub fn new() -> Self {
         Self {
-            managed: BTreeMap::new()
+            managed: BTreeMap::new(),
+            disabled: false,
+        }
+    }
+
+    /// Create a new managed relocation registry that ignores all relocations added to it, for code in a buffer that
+    /// is never moved.
+    pub fn disabled() -> Self {
+        Self {
+            managed: BTreeMap::new(),
+            disabled: true,
         }
     }
 
     /// Add a relocation to this registry.
     pub fn add(&mut self, patchloc: PatchLoc<R>) {
-        self.managed.insert(patchloc.location.0 - patchloc.relocation.field_offset(), patchloc);
+        if !self.disabled {
+            self.managed.insert(patchloc.location.0 - patchloc.relocation.field_offset(), patchloc);
+        }
     }
 
     /// Take all items from another registry and add them to this registry
     pub fn append(&mut self, other: &mut ManagedRelocs<R>) {
-        self.managed.append(&mut other.managed);
+        if self.disabled {
+            other.managed.clear();
+        } else {
+            self.managed.append(&mut other.managed);
+        }
     }
 
     /// Remove all managed relocations whose byte fields start in the range start .. end.
@@ -373,6 +1438,11 @@ impl<R: Relocation> ManagedRelocs<R> {
         }
     }
 
+    /// Remove all managed relocations whose byte fields start at offsets for which `f` returns true.
+    pub fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.managed.retain(|&field, _| !f(field));
+    }
+
     /// Iterate through all defined managed relocations.
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a PatchLoc<R>> + 'a {
         self.managed.values()
@@ -380,6 +1450,842 @@ impl<R: Relocation> ManagedRelocs<R> {
 }
 
 
+// Copies a relocation. Relocations are only required to be cloneable by the features that remember copies of them,
+// which obtain this when they are enabled.
+pub(crate) type CopyPatchLoc<R> = fn(&PatchLoc<R>) -> PatchLoc<R>;
+
+
+/// A registry of references to retargetable dynamic labels. Unlike other relocations, references to these labels are
+/// remembered after they have been encoded, so they can be encoded again when their label is redefined.
+#[derive(Debug, Default)]
+pub struct RetargetRegistry<R: Relocation> {
+    // the dynamic labels that are retargetable
+    labels: HashSet<DynamicLabel>,
+    // copies references to be remembered, which is only needed once a label has been made retargetable
+    copy: Option<CopyPatchLoc<R>>,
+    // references that have not been committed yet
+    pending: Vec<(PatchLoc<R>, DynamicLabel)>,
+    // committed references and the labels they target, by the start of their field
+    references: BTreeMap<usize, (PatchLoc<R>, DynamicLabel)>,
+    // the starts of the fields of the committed references to every label
+    fields: HashMap<DynamicLabel, BTreeSet<usize>>,
+}
+
+impl<R: Relocation> RetargetRegistry<R> {
+    /// Create a new, empty retarget registry.
+    pub fn new() -> Self {
+        Self {
+            labels: HashSet::new(),
+            copy: None,
+            pending: Vec::new(),
+            references: BTreeMap::new(),
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Mark the dynamic label `id` as retargetable.
+    pub fn add_label(&mut self, id: DynamicLabel) where R: Clone {
+        self.copy = Some(PatchLoc::clone);
+        self.labels.insert(id);
+    }
+
+    /// Returns if the dynamic label `id` is retargetable.
+    pub fn is_retargetable(&self, id: DynamicLabel) -> bool {
+        self.labels.contains(&id)
+    }
+
+    /// Remember a copy of an encoded reference to the dynamic label `id`, if that label is retargetable.
+    pub fn add(&mut self, patchloc: &PatchLoc<R>, id: DynamicLabel) {
+        match self.copy {
+            Some(copy) if self.labels.contains(&id) => self.pending.push((copy(patchloc), id)),
+            _ => ()
+        }
+    }
+
+    /// Mark all pending references as committed. References added after a call to `remove_between` are not affected by it
+    /// until this is called.
+    pub fn commit(&mut self) {
+        for (patchloc, id) in self.pending.drain(..) {
+            let field = patchloc.location.0 - patchloc.relocation.field_offset();
+            if let Some((_, previous)) = self.references.insert(field, (patchloc, id)) {
+                Self::unlink(&mut self.fields, previous, field);
+            }
+            self.fields.entry(id).or_default().insert(field);
+        }
+    }
+
+    // forget that the reference with its field at `field` targets `id`
+    fn unlink(fields: &mut HashMap<DynamicLabel, BTreeSet<usize>>, id: DynamicLabel, field: usize) {
+        if let Entry::Occupied(mut entry) = fields.entry(id) {
+            entry.get_mut().remove(&field);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Remove all committed references whose byte fields start in the range start .. end, as they were overwritten.
+    pub fn remove_between(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        let keys: Vec<_> = self.references.range(start .. end).map(|(&k, _)| k).collect();
+        for k in keys {
+            if let Some((_, id)) = self.references.remove(&k) {
+                Self::unlink(&mut self.fields, id, k);
+            }
+        }
+    }
+
+    /// Forget the labels that were freed in `labels`, and all references to them.
+    pub fn remove_freed(&mut self, labels: &LabelRegistry) {
+        self.labels.retain(|&id| labels.is_live(id));
+        self.references.retain(|_, &mut (_, id)| labels.is_live(id));
+        self.fields.retain(|&id, _| labels.is_live(id));
+    }
+
+    /// Encode all committed references to the dynamic label `id` in `buffer`, which resides at `buf_addr`, again to
+    /// point to `target`. If any reference cannot reach `target`, this is reported as an error and no reference is
+    /// changed.
+    pub fn patch(&self, id: DynamicLabel, buf_addr: usize, buffer: &mut [u8], target: usize) -> Result<(), DynasmError> {
+        let fields = match self.fields.get(&id) {
+            Some(fields) => fields,
+            None => return Ok(())
+        };
+
+        // encode every reference into a copy of its field first
+        let mut encoded = Vec::with_capacity(fields.len());
+        for &field in fields {
+            let (patchloc, _) = &self.references[&field];
+            let mut copy = buffer[field .. field + patchloc.relocation.size()].to_vec();
+            if patchloc.patch(field, buf_addr, &mut copy, target).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
+            }
+            encoded.push((field, copy));
+        }
+
+        for (field, copy) in encoded {
+            buffer[field .. field + copy.len()].copy_from_slice(&copy);
+        }
+        Ok(())
+    }
+}
+
+
+/// A registry of patchpoints: naturally aligned blocks of code of at most 8 bytes, containing a single relocation that
+/// can be encoded again with a single atomic store after the code has been committed.
+#[derive(Debug, Default)]
+pub struct PatchpointRegistry<R: Relocation> {
+    // for every patchpoint id, the patchpoint using it if it was not discarded: its relocation, and the offset and
+    // size of the block containing it
+    patchpoints: Vec<Option<(PatchLoc<R>, usize, usize)>>,
+    // the current version of every patchpoint id, which changes whenever a patchpoint using it is discarded
+    versions: Vec<u32>,
+    // ids of discarded patchpoints, which are reused before new ids are created
+    free: Vec<usize>,
+}
+
+impl<R: Relocation> PatchpointRegistry<R> {
+    /// Create a new, empty patchpoint registry.
+    pub fn new() -> Self {
+        Self {
+            patchpoints: Vec::new(),
+            versions: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Add a patchpoint of the relocation `patchloc`, contained in the `size` bytes of code at `start`.
+    pub fn add(&mut self, patchloc: PatchLoc<R>, start: AssemblyOffset, size: usize) -> Patchpoint {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.patchpoints.push(None);
+                self.versions.push(0);
+                self.patchpoints.len() - 1
+            }
+        };
+
+        self.patchpoints[id] = Some((patchloc, start.0, size));
+        Patchpoint(id, self.versions[id])
+    }
+
+    /// Returns the relocation of `patchpoint`, and the offset and size of the block containing it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `patchpoint` was discarded.
+    pub fn get(&self, patchpoint: Patchpoint) -> (&PatchLoc<R>, usize, usize) {
+        match self.patchpoints.get(patchpoint.0) {
+            Some(&Some((ref loc, start, size))) if self.versions[patchpoint.0] == patchpoint.1 => (loc, start, size),
+            _ => panic!("use of discarded patchpoint {:?}", patchpoint)
+        }
+    }
+
+    /// Forget all patchpoints located at offsets for which `f` returns true. Handles to them become invalid, even
+    /// once their ids are reused.
+    pub fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        for (id, entry) in self.patchpoints.iter_mut().enumerate() {
+            if entry.as_ref().is_some_and(|&(_, start, _)| f(start)) {
+                *entry = None;
+                self.versions[id] = self.versions[id].wrapping_add(1);
+                self.free.push(id);
+            }
+        }
+    }
+
+    /// Update the locations of all patchpoints with `f`, after the code they reside in has been moved.
+    /// The code of patchpoints is never relaxed, so their blocks move along with their relocations.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for (loc, start, _) in self.patchpoints.iter_mut().flatten() {
+            let location = f(loc.location);
+            *start = location.0 - (loc.location.0 - *start);
+            loc.location = location;
+        }
+    }
+}
+
+
+/// The alignment of veneers created by a `VeneerRegistry`.
+pub const VENEER_ALIGNMENT: usize = 8;
+
+/// A registry of branches to absolute addresses. Branches like these can only reach targets within a limited
+/// distance, so a veneer is created for every target they might not reach: a small stub appended to the assembled
+/// code, that can jump to its target from anywhere in memory. Veneers are placed in islands preceded by a jump over
+/// them, so they can be appended to code that falls through. Veneers are shared by all branches to the same target.
+/// Branches only use their veneer if they cannot reach their target directly, so they have to be patched again
+/// whenever the address of the code they reside in changes.
+#[derive(Debug, Default)]
+pub struct VeneerRegistry<R: Relocation> {
+    // branches that have not been resolved yet, and their targets
+    pending: Vec<(PatchLoc<R>, usize)>,
+    // resolved branches and their targets, by the start of their field
+    branches: BTreeMap<usize, (PatchLoc<R>, usize)>,
+    // the offsets of the veneers for each target
+    veneers: HashMap<usize, usize>,
+}
+
+impl<R: Relocation> VeneerRegistry<R> {
+    /// Create a new, empty veneer registry.
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            branches: BTreeMap::new(),
+            veneers: HashMap::new(),
+        }
+    }
+
+    /// Add a branch to the absolute address `target`.
+    pub fn add(&mut self, patchloc: PatchLoc<R>, target: usize) {
+        self.pending.push((patchloc, target));
+    }
+
+    /// Forget all branches that have not been resolved yet whose byte fields start at offsets for which `f` returns true.
+    pub fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.pending.retain(|(loc, _)| !f(loc.location.0 - loc.relocation.field_offset()));
+    }
+
+    /// Add a branch to the absolute address `target` that has already been encoded to jump to it directly, so it is
+    /// patched again when the address of the code it resides in changes.
+    pub fn add_resolved(&mut self, patchloc: PatchLoc<R>, target: usize) {
+        self.branches.insert(patchloc.location.0 - patchloc.relocation.field_offset(), (patchloc, target));
+    }
+
+    /// Update the locations of the branches that have not been resolved yet with `f`, after the code they
+    /// reside in was moved by branch relaxation.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for (loc, _) in &mut self.pending {
+            loc.location = f(loc.location);
+        }
+    }
+
+    /// Append an island to `buffer` with a veneer for every target of the branches that have not been resolved yet
+    /// which does not have a veneer yet, if their relocation supports veneers. The island starts with a jump over it,
+    /// so execution continues after it. `buffer` is located at `buf_offset` in the assembled code. If the code will
+    /// never be moved from `buf_addr`, veneers are only added for branches that cannot reach their target from there.
+    /// Otherwise, every target gets a veneer, so branches can still reach it once their code has been moved.
+    pub fn emit(&mut self, buf_offset: usize, buf_addr: Option<usize>, buffer: &mut Vec<u8>) {
+        let jump = match R::jump_over(0) {
+            Some(jump) => jump.len(),
+            None => return
+        };
+
+        let start = buf_offset + buffer.len() + jump;
+        let mut island = Vec::new();
+        for &(ref loc, target) in &self.pending {
+            let direct = buf_addr.is_some_and(|addr| loc.patch(buf_offset, addr, buffer, target.wrapping_sub(addr)).is_ok());
+            if direct {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.veneers.entry(target) {
+                if let Some(code) = loc.relocation.veneer(target) {
+                    while !(start + island.len()).is_multiple_of(VENEER_ALIGNMENT) {
+                        island.push(0);
+                    }
+                    entry.insert(start + island.len());
+                    island.extend_from_slice(&code);
+                }
+            }
+        }
+
+        if !island.is_empty() {
+            buffer.extend(R::jump_over(island.len()).unwrap());
+            buffer.append(&mut island);
+        }
+    }
+
+    /// Returns the most bytes `emit` can append for the branches that have not been resolved yet, and for the
+    /// relocations `others` that are about to be added as branches to absolute addresses.
+    pub fn island_size<'a, I: IntoIterator<Item=&'a PatchLoc<R>>>(&self, others: I) -> usize where R: 'a {
+        let jump = match R::jump_over(0) {
+            Some(jump) => jump.len(),
+            None => return 0
+        };
+
+        // the size of a veneer does not depend on its target
+        let pending = self.pending.iter()
+            .filter(|&(_, target)| !self.veneers.contains_key(target))
+            .map(|(loc, _)| loc.relocation.veneer(0));
+        let others = others.into_iter()
+            .filter(|loc| loc.relocation.kind() == RelocationKind::Relative)
+            .map(|loc| loc.relocation.veneer(0));
+        let veneers: usize = pending.chain(others).flatten().map(|code| code.len() + VENEER_ALIGNMENT - 1).sum();
+
+        if veneers == 0 { 0 } else { jump + veneers }
+    }
+
+    /// Patch all branches located in `buffer`, and resolve the branches that have not been resolved yet.
+    /// `buffer` is located at `buf_offset` in the assembled code, and resides at `buf_addr`. Branches are
+    /// patched to jump to their target directly if possible, and to their veneer otherwise. If `buf_addr` is
+    /// `None`, the address of the code is not known yet and all branches are patched to jump to their veneer.
+    pub fn patch(&mut self, buf_offset: usize, buf_addr: Option<usize>, buffer: &mut [u8]) -> Result<(), DynasmError> {
+        let end = buf_offset + buffer.len();
+        let branches = self.branches.range(buf_offset .. end).map(|(_, branch)| branch);
+        for (loc, target) in branches.chain(self.pending.iter()) {
+            // the target of a relative relocation is an offset, so express the address relative to the buffer
+            let direct = buf_addr.is_some_and(|addr| loc.patch(buf_offset, addr, buffer, target.wrapping_sub(addr)).is_ok());
+            if direct {
+                continue;
+            }
+            match self.veneers.get(target) {
+                Some(&veneer) if loc.patch(buf_offset, 0, buffer, veneer).is_ok() => (),
+                _ => return Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(*target)))
+            }
+        }
+
+        for (loc, target) in self.pending.drain(..) {
+            self.branches.insert(loc.location.0 - loc.relocation.field_offset(), (loc, target));
+        }
+        Ok(())
+    }
+
+    /// Remove all branches whose byte fields start in the range start .. end. Like `ManagedRelocs::remove_between`,
+    /// this should be used when code containing branches is overwritten.
+    pub fn remove_between(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        let keys: Vec<_> = self.branches.range(start .. end).map(|(&k, _)| k).collect();
+        for k in keys {
+            self.branches.remove(&k);
+        }
+    }
+}
+
+
+/// A table of labels published by an `Assembler` for other assemblers to link against, as created by
+/// `Assembler::exports`. This is a shared handle: all clones observe the labels the exporting assembler
+/// publishes, together with the address its executable buffer currently resides at.
+///
+/// When the exporting assembler moves its executable buffer, code that was linked against the old buffer keeps
+/// running the code as it was until it has been relinked, as the old buffer is only released after that.
+#[derive(Debug, Clone, Default)]
+pub struct LabelExports {
+    table: Arc<RwLock<ExportTable>>
+}
+
+#[derive(Debug, Default)]
+struct ExportTable {
+    // the address of the code the labels are defined in
+    addr: usize,
+    // the published labels
+    global_labels: HashMap<&'static str, AssemblyOffset>,
+    dynamic_labels: HashMap<DynamicLabel, AssemblyOffset>,
+    // for every address of the code that importers are linked against, the amount of importers holding it
+    holds: HashMap<usize, usize>,
+    // buffers the code was moved out of that are still held by importers
+    retired: Vec<Arc<ExecutableBuffer>>,
+}
+
+impl LabelExports {
+    /// Create a new, empty table of exported labels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of buffers the exporting code was moved out of that are still kept alive, because an
+    /// importer has not been patched against the new address yet.
+    pub fn retained_buffers(&self) -> usize {
+        self.table.read().unwrap().retired.len()
+    }
+
+    /// Publish the global and dynamic labels in `labels` whose definitions changed since they were last published,
+    /// as recorded since `LabelRegistry::track_changes` was called. Their code now resides at `addr`. Labels defined
+    /// past the first `committed` bytes of the code stay recorded until a later call, and labels that are no longer
+    /// defined are withdrawn. `replaced` are the buffers the code was moved out of since the last call, which are
+    /// kept until no importer holds their address anymore.
+    pub fn publish(&self, addr: usize, labels: &mut LabelRegistry, committed: usize, replaced: Vec<Arc<ExecutableBuffer>>) {
+        let mut changes = labels.changes.take().unwrap_or_default();
+        let mut table = self.table.write().unwrap();
+
+        changes.retain(|&label| {
+            let offset = match label {
+                LabelKind::Global(name) => labels.resolve_global(name).ok(),
+                LabelKind::Dynamic(id) => labels.resolve_dynamic(id).ok(),
+                LabelKind::Local(_) => None
+            };
+            match (label, offset) {
+                (_, Some(offset)) if offset.0 >= committed => return true,
+                (LabelKind::Global(name), Some(offset)) => {
+                    table.global_labels.insert(name, offset);
+                },
+                (LabelKind::Global(name), None) => {
+                    table.global_labels.remove(name);
+                },
+                (LabelKind::Dynamic(id), Some(offset)) => {
+                    table.dynamic_labels.insert(id, offset);
+                },
+                (LabelKind::Dynamic(id), None) => {
+                    table.dynamic_labels.remove(&id);
+                },
+                (LabelKind::Local(_), _) => ()
+            }
+            false
+        });
+        labels.changes = Some(changes);
+
+        table.addr = addr;
+        let ExportTable { holds, retired, .. } = &mut *table;
+        retired.extend(replaced.into_iter().filter(|buffer| holds.contains_key(&buffer.base_addr())));
+    }
+
+    /// Returns the address of the code the published labels are defined in.
+    pub fn addr(&self) -> usize {
+        self.table.read().unwrap().addr
+    }
+
+    /// Returns the address of the code the published labels are defined in, like `addr`. The code at this address is
+    /// kept mapped, even after the exporting assembler moved it, until `release` is called with the address.
+    pub fn hold(&self) -> usize {
+        let mut table = self.table.write().unwrap();
+        let addr = table.addr;
+        *table.holds.entry(addr).or_insert(0) += 1;
+        addr
+    }
+
+    /// Release a hold on the code at `addr` that was obtained with `hold`.
+    pub fn release(&self, addr: usize) {
+        let mut table = self.table.write().unwrap();
+        if let Entry::Occupied(mut count) = table.holds.entry(addr) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+                table.retired.retain(|buffer| buffer.base_addr() != addr);
+            }
+        }
+    }
+
+    /// Returns the offset at which the published global label `name` was defined, if it was published.
+    pub fn resolve_global(&self, name: &'static str) -> Option<AssemblyOffset> {
+        self.table.read().unwrap().global_labels.get(name).cloned()
+    }
+
+    /// Returns the offset at which the published dynamic label `id` was defined, if it was published.
+    pub fn resolve_dynamic(&self, id: DynamicLabel) -> Option<AssemblyOffset> {
+        self.table.read().unwrap().dynamic_labels.get(&id).cloned()
+    }
+
+    /// Returns if `self` and `other` refer to the same table.
+    pub fn same_table(&self, other: &LabelExports) -> bool {
+        Arc::ptr_eq(&self.table, &other.table)
+    }
+}
+
+
+/// A registry of relocations targeting labels imported from other assemblers through `LabelExports`.
+/// As the value of these relocations depends on the addresses of both the code they reside in and the code they
+/// target, they are patched again whenever either of these moves.
+#[derive(Debug, Default)]
+pub struct ImportRegistry<R: Relocation> {
+    // imported tables, their addresses when all linked relocations were last patched, and the addresses of their code
+    // that relocations were patched for since, which are held until all relocations are patched again
+    imports: Vec<(LabelExports, usize, Vec<usize>)>,
+    // local dynamic labels that refer to a dynamic label in an imported table
+    aliases: HashMap<DynamicLabel, (usize, DynamicLabel)>,
+    // relocations that have not been patched yet, the table they target and the offset of their target in it
+    pending: Vec<(PatchLoc<R>, usize, usize, TargetKind)>,
+    // patched relocations, by the start of their field
+    linked: BTreeMap<usize, (PatchLoc<R>, usize, usize, TargetKind)>,
+}
+
+impl<R: Relocation> ImportRegistry<R> {
+    /// Create a new, empty import registry.
+    pub fn new() -> Self {
+        Self {
+            imports: Vec::new(),
+            aliases: HashMap::new(),
+            pending: Vec::new(),
+            linked: BTreeMap::new(),
+        }
+    }
+
+    /// Import the labels published in `exports`, if they were not imported yet. Returns the index of the import.
+    pub fn import(&mut self, exports: &LabelExports) -> usize {
+        if let Some(index) = self.imports.iter().position(|(e, _, _)| e.same_table(exports)) {
+            return index;
+        }
+        self.imports.push((exports.clone(), exports.addr(), Vec::new()));
+        self.imports.len() - 1
+    }
+
+    /// Make the local dynamic label `alias` refer to the dynamic label `id` published in `exports`.
+    pub fn alias_dynamic(&mut self, alias: DynamicLabel, exports: &LabelExports, id: DynamicLabel) {
+        let index = self.import(exports);
+        self.aliases.insert(alias, (index, id));
+    }
+
+    /// Forget the aliases of dynamic labels that were freed in `labels`.
+    pub fn remove_freed_aliases(&mut self, labels: &LabelRegistry) {
+        self.aliases.retain(|&alias, _| labels.is_live(alias));
+    }
+
+    /// Find the global label `name` in the imported tables. Returns the index of the import it was found in,
+    /// and its offset in the code of that import.
+    pub fn resolve_global(&self, name: &'static str) -> Option<(usize, AssemblyOffset)> {
+        self.imports.iter().enumerate().find_map(|(index, (exports, _, _))| exports.resolve_global(name).map(|offset| (index, offset)))
+    }
+
+    /// Find the target of the dynamic label `alias`, if it refers to an imported label. Returns the index of the
+    /// import it was found in, and its offset in the code of that import.
+    pub fn resolve_dynamic(&self, alias: DynamicLabel) -> Option<(usize, AssemblyOffset)> {
+        let &(index, id) = self.aliases.get(&alias)?;
+        self.imports[index].0.resolve_dynamic(id).map(|offset| (index, offset))
+    }
+
+    /// Add a relocation to the label at offset `target` in the code of import `index`. `kind` is used to report errors.
+    pub fn add(&mut self, patchloc: PatchLoc<R>, index: usize, target: AssemblyOffset, kind: TargetKind) {
+        self.pending.push((patchloc, index, target.0, kind));
+    }
+
+    /// Update the locations of the relocations that have not been patched yet with `f`, after the code they
+    /// reside in was moved by branch relaxation.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for (loc, _, _, _) in &mut self.pending {
+            loc.location = f(loc.location);
+        }
+    }
+
+    /// Forget all relocations that have not been patched yet whose byte fields start at offsets for which `f` returns true.
+    pub fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.pending.retain(|(loc, _, _, _)| !f(loc.location.0 - loc.relocation.field_offset()));
+    }
+
+    /// Returns if any imported code targeted by patched relocations moved since all relocations were last patched.
+    pub fn moved(&self) -> bool {
+        !self.linked.is_empty() && self.imports.iter().any(|(exports, addr, _)| exports.addr() != *addr)
+    }
+
+    /// Patch all relocations located in `buffer` for the current addresses of the imported code, and the relocations
+    /// that were not patched yet. `buffer` is located at `buf_offset` in the assembled code, and resides at `buf_addr`.
+    /// If `buf_offset` is zero, `buffer` has to contain all assembled code. The imported code is kept mapped at the
+    /// addresses relocations were patched for, until all relocations have been patched for new addresses.
+    pub fn patch(&mut self, buf_offset: usize, buf_addr: usize, buffer: &mut [u8]) -> Result<(), DynasmError> {
+        let addrs: Vec<_> = self.imports.iter_mut().map(|(exports, _, held)| {
+            let addr = exports.hold();
+            if held.contains(&addr) {
+                exports.release(addr);
+            } else {
+                held.push(addr);
+            }
+            addr
+        }).collect();
+        let end = buf_offset + buffer.len();
+        let linked = self.linked.range(buf_offset .. end).map(|(_, link)| link);
+        for (loc, index, target, kind) in linked.chain(self.pending.iter()) {
+            // targets are addresses for RelToAbs relocations, and otherwise offsets from the start of the buffer
+            let target = addrs[*index].wrapping_add(*target);
+            let target = match loc.relocation.kind() {
+                RelocationKind::RelToAbs => target,
+                RelocationKind::Relative
+                | RelocationKind::AbsToRel => target.wrapping_sub(buf_addr)
+            };
+            if loc.patch(buf_offset, buf_addr, buffer, target).is_err() {
+                return Err(DynasmError::ImpossibleRelocation(*kind));
+            }
+        }
+
+        for link in self.pending.drain(..) {
+            self.linked.insert(link.0.location.0 - link.0.relocation.field_offset(), link);
+        }
+        // all relocations are up to date only if all code was patched, after which older code can be released
+        if buf_offset == 0 {
+            for ((exports, addr, held), new_addr) in self.imports.iter_mut().zip(addrs) {
+                *addr = new_addr;
+                held.retain(|&old| old == new_addr || {
+                    exports.release(old);
+                    false
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all relocations whose byte fields start in the range start .. end. Like `ManagedRelocs::remove_between`,
+    /// this should be used when code containing such relocations is overwritten.
+    pub fn remove_between(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        let keys: Vec<_> = self.linked.range(start .. end).map(|(&k, _)| k).collect();
+        for k in keys {
+            self.linked.remove(&k);
+        }
+    }
+}
+
+impl<R: Relocation> Drop for ImportRegistry<R> {
+    fn drop(&mut self) {
+        for (exports, _, held) in &self.imports {
+            for &addr in held {
+                exports.release(addr);
+            }
+        }
+    }
+}
+
+
+/// A registry used to implement branch relaxation. Instead of being encoded immediately, relocations in uncommitted code
+/// are recorded here together with their targets. Before they are encoded, `relax` replaces every jump that was marked as
+/// relaxable with a shorter encoding if its target is in range, moves the code after it and recalculates any alignment padding.
+#[derive(Debug, Clone, Default)]
+pub struct RelaxRegistry<R: Relocation> {
+    // relocations, the offsets they target (or addresses, for RelToAbs relocations), and what they target
+    relocs: Vec<(PatchLoc<R>, usize, TargetKind)>,
+    // locations of relocations belonging to jumps that can be relaxed
+    relaxable: Vec<usize>,
+    // alignment padding: its offset, its length, the alignment and the byte it was filled with, if it was not
+    // filled with no-op instructions
+    aligns: Vec<(usize, usize, usize, Option<u8>)>,
+    // if jumps are relaxed, or relocations are only deferred until the code is committed
+    relaxing: bool,
+}
+
+impl<R: Relocation> RelaxRegistry<R> {
+    /// Create a new, empty relaxation registry.
+    pub fn new() -> Self {
+        Self {
+            relocs: Vec::new(),
+            relaxable: Vec::new(),
+            aligns: Vec::new(),
+            relaxing: true,
+        }
+    }
+
+    /// Create a new, empty relaxation registry that only defers the encoding of relocations, without relaxing any jumps
+    /// until `enable` is called.
+    pub fn deferring() -> Self {
+        Self {
+            relaxing: false,
+            ..Self::new()
+        }
+    }
+
+    /// Relax the jumps that are marked as relaxable from now on.
+    pub fn enable(&mut self) {
+        self.relaxing = true;
+    }
+
+    /// Returns if jumps are relaxed, rather than relocations only being deferred.
+    pub fn is_relaxing(&self) -> bool {
+        self.relaxing
+    }
+
+    /// Add a relocation that should be patched to point to `target`. `kind` is used to report errors.
+    pub fn add(&mut self, patchloc: PatchLoc<R>, target: usize, kind: TargetKind) {
+        self.relocs.push((patchloc, target, kind));
+    }
+
+    /// Mark the relocation at `location` as belonging to a jump that can be relaxed.
+    pub fn mark_relaxable(&mut self, location: AssemblyOffset) {
+        if self.relaxing {
+            self.relaxable.push(location.0);
+        }
+    }
+
+    /// Record that `padding` bytes of `with`, or of no-op instructions if it is `None`, were emitted at `offset` to
+    /// align the code to `alignment`.
+    pub fn add_align(&mut self, offset: AssemblyOffset, padding: usize, alignment: usize, with: Option<u8>) {
+        self.aligns.push((offset.0, padding, alignment, with));
+    }
+
+    /// Relax jumps in `buffer`, which contains the code from offset `buf_offset` onwards. All recorded relocations are
+    /// adjusted to the new layout of the code. Offsets past the end of `buffer` are not affected. If the layout changed, it is returned so that anything else that refers
+    /// to offsets in `buffer` (like labels) can be adjusted too.
+    pub fn relax(&mut self, buf_offset: usize, buffer: &mut Vec<u8>) -> Option<CodeLayout> {
+        // find the jumps that have shorter encodings
+        let mut jumps = Vec::new();
+        for (i, (loc, _, _)) in self.relocs.iter().enumerate() {
+            if self.relaxable.binary_search(&loc.location.0).is_err() {
+                continue;
+            }
+            if let Some(relaxed) = loc.relocation.relax(&buffer[.. loc.location.0 - buf_offset]) {
+                jumps.push((i, relaxed));
+            }
+        }
+        if jumps.is_empty() {
+            return None;
+        }
+
+        // the parts of the code that can change size, as (start, length, item)
+        let mut items: Vec<_> = jumps.iter().enumerate().map(|(j, (i, relaxed))| {
+            let end = self.relocs[*i].0.location.0;
+            (end - relaxed.length, relaxed.length, Ok(j))
+        }).collect();
+        items.extend(self.aligns.iter().enumerate().map(|(a, &(offset, padding, _, _))| (offset, padding, Err(a))));
+        items.sort_by_key(|&(start, _, _)| start);
+
+        // start with all jumps relaxed, and expand the ones that cannot reach their targets until everything fits.
+        // As jumps are only ever expanded, this terminates.
+        let mut short = vec![true; jumps.len()];
+        let mut lengths = vec![0; items.len()];
+        let layout = loop {
+            let mut layout = CodeLayout { ends: Vec::with_capacity(items.len()) };
+            let mut delta = 0isize;
+            for (&(start, length, item), new_length) in items.iter().zip(lengths.iter_mut()) {
+                *new_length = match item {
+                    Ok(j) if short[j] => jumps[j].1.code.len(),
+                    Ok(_) => length,
+                    Err(a) => {
+                        let alignment = self.aligns[a].2;
+                        let new_start = (start as isize - delta) as usize;
+                        (alignment - new_start % alignment) % alignment
+                    }
+                };
+                delta += length as isize - *new_length as isize;
+                layout.ends.push((start + length, delta));
+            }
+            // anything past the end of the code, like the contents of data sections, stays in place
+            layout.ends.push((buf_offset + buffer.len() + 1, 0));
+
+            let mut changed = false;
+            for (j, (i, relaxed)) in jumps.iter().enumerate() {
+                if !short[j] {
+                    continue;
+                }
+                let (loc, target, _) = &self.relocs[*i];
+                let value = relocation_value(&relaxed.relocation, layout.map(loc.location), loc.offset, layout.map_target(loc, *target), 0);
+                let mut field = [0u8; 8];
+                if relaxed.relocation.write_value(&mut field[.. relaxed.relocation.size()], value).is_err() {
+                    short[j] = false;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break layout;
+            }
+        };
+
+        if !short.contains(&true) {
+            return None;
+        }
+
+        // rewrite the code
+        let mut new_buffer = Vec::with_capacity(buffer.len());
+        let mut position = buf_offset;
+        for (&(start, length, item), &new_length) in items.iter().zip(lengths.iter()) {
+            new_buffer.extend_from_slice(&buffer[position - buf_offset .. start - buf_offset]);
+            match item {
+                Ok(j) if short[j] => new_buffer.extend_from_slice(&jumps[j].1.code),
+                Ok(_) => new_buffer.extend_from_slice(&buffer[start - buf_offset .. start + length - buf_offset]),
+                Err(a) => pad::<R>(&mut new_buffer, new_length, self.aligns[a].3)
+            }
+            position = start + length;
+        }
+        new_buffer.extend_from_slice(&buffer[position - buf_offset ..]);
+        *buffer = new_buffer;
+
+        // and adjust the relocations
+        let mut relaxed: Vec<_> = jumps.into_iter().zip(short).filter(|&(_, short)| short).map(|((i, relaxed), _)| (i, relaxed.relocation)).collect();
+        for (i, (loc, target, _)) in self.relocs.iter_mut().enumerate().rev() {
+            *target = layout.map_target(loc, *target);
+            loc.location = layout.map(loc.location);
+            if relaxed.last().is_some_and(|&(j, _)| j == i) {
+                loc.relocation = relaxed.pop().unwrap().1;
+            }
+        }
+
+        Some(layout)
+    }
+
+    /// Update the locations and targets of all recorded relocations and the offsets of all recorded alignment padding
+    /// with `f`, after the code they refer to has been moved.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for (loc, target, _) in &mut self.relocs {
+            if loc.relocation.kind() != RelocationKind::RelToAbs {
+                *target = f(AssemblyOffset(*target)).0;
+            }
+            loc.location = f(loc.location);
+        }
+        for location in &mut self.relaxable {
+            *location = f(AssemblyOffset(*location)).0;
+        }
+        self.relaxable.sort_unstable();
+        for (offset, _, _, _) in &mut self.aligns {
+            *offset = f(AssemblyOffset(*offset)).0;
+        }
+    }
+
+    /// Return an iterator through all recorded relocations, their targets, and what they target.
+    /// These relocations are removed from the registry, together with any other recorded information.
+    pub fn take<'a>(&'a mut self) -> impl Iterator<Item=(PatchLoc<R>, usize, TargetKind)> + 'a {
+        self.relaxable.clear();
+        self.aligns.clear();
+        self.relocs.drain(..)
+    }
+}
+
+
+/// Describes how code was moved by branch relaxation.
+#[derive(Debug, Clone)]
+pub struct CodeLayout {
+    // the ends of all parts of the code that changed size, and how much the code after them moved backwards
+    ends: Vec<(usize, isize)>,
+}
+
+impl CodeLayout {
+    /// Returns the new offset of the code that was located at `offset`.
+    pub fn map(&self, offset: AssemblyOffset) -> AssemblyOffset {
+        let index = self.ends.partition_point(|&(end, _)| end <= offset.0);
+        match index.checked_sub(1) {
+            Some(index) => AssemblyOffset((offset.0 as isize - self.ends[index].1) as usize),
+            None => offset
+        }
+    }
+
+    /// Returns the new target of `loc`. These are offsets, except for RelToAbs relocations.
+    pub(crate) fn map_target<R: Relocation>(&self, loc: &PatchLoc<R>, target: usize) -> usize {
+        match loc.relocation.kind() {
+            RelocationKind::RelToAbs => target,
+            RelocationKind::Relative
+            | RelocationKind::AbsToRel => self.map(AssemblyOffset(target)).0
+        }
+    }
+}
+
+
 #[derive(Clone, Debug)]
 enum LitPoolEntry {
     U8(u8),
@@ -0,0 +1,325 @@
+// This module implements a minimal writer for 64-bit little endian ELF files, as used to describe
+// assembled code to debuggers and linkers.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+pub(crate) const ET_REL: u16 = 1;
+
+#[cfg(feature = "gdb-jit")]
+pub(crate) const EM_386: u16 = 3;
+pub(crate) const EM_X86_64: u16 = 62;
+pub(crate) const EM_AARCH64: u16 = 183;
+
+pub(crate) const SHT_PROGBITS: u32 = 1;
+pub(crate) const SHT_SYMTAB: u32 = 2;
+pub(crate) const SHT_STRTAB: u32 = 3;
+pub(crate) const SHT_RELA: u32 = 4;
+pub(crate) const SHT_NOBITS: u32 = 8;
+
+pub(crate) const SHF_ALLOC: u64 = 2;
+pub(crate) const SHF_EXECINSTR: u64 = 4;
+pub(crate) const SHF_INFO_LINK: u64 = 0x40;
+
+pub(crate) const STB_LOCAL: u8 = 0;
+pub(crate) const STB_GLOBAL: u8 = 1;
+pub(crate) const STT_NOTYPE: u8 = 0;
+pub(crate) const STT_FUNC: u8 = 2;
+pub(crate) const STT_SECTION: u8 = 3;
+
+pub(crate) const SHN_UNDEF: u16 = 0;
+
+pub(crate) const R_X86_64_64: u32 = 1;
+pub(crate) const R_X86_64_PC32: u32 = 2;
+pub(crate) const R_X86_64_PC16: u32 = 13;
+pub(crate) const R_X86_64_PC8: u32 = 15;
+pub(crate) const R_X86_64_PC64: u32 = 24;
+
+pub(crate) const R_AARCH64_ABS64: u32 = 257;
+pub(crate) const R_AARCH64_PREL64: u32 = 260;
+pub(crate) const R_AARCH64_PREL32: u32 = 261;
+pub(crate) const R_AARCH64_PREL16: u32 = 262;
+pub(crate) const R_AARCH64_ADR_PREL_LO21: u32 = 274;
+pub(crate) const R_AARCH64_TSTBR14: u32 = 279;
+pub(crate) const R_AARCH64_CONDBR19: u32 = 280;
+pub(crate) const R_AARCH64_CALL26: u32 = 283;
+
+const HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+pub(crate) const RELA_SIZE: usize = 24;
+
+/// The ELF machine type of the target this crate is compiled for.
+#[cfg(feature = "gdb-jit")]
+pub(crate) fn native_machine() -> u16 {
+    if cfg!(target_arch = "x86_64") {
+        EM_X86_64
+    } else if cfg!(target_arch = "x86") {
+        EM_386
+    } else if cfg!(target_arch = "aarch64") {
+        EM_AARCH64
+    } else {
+        0
+    }
+}
+
+
+/// A single section in an ELF file.
+#[derive(Debug, Clone)]
+pub(crate) struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub data: Vec<u8>,
+    // the size of the section, which differs from the length of data for SHT_NOBITS sections
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entsize: u64,
+}
+
+impl Section {
+    /// Create a new section named `name` of type `kind`, containing `data`.
+    pub fn new(name: &str, kind: u32, data: Vec<u8>) -> Section {
+        Section {
+            name: name.to_string(),
+            kind,
+            flags: 0,
+            addr: 0,
+            size: data.len() as u64,
+            data,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        }
+    }
+}
+
+
+/// A string table, as used for symbol and section names.
+#[derive(Debug, Clone)]
+pub(crate) struct StringTable {
+    data: Vec<u8>
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable {
+            data: vec![0]
+        }
+    }
+
+    /// Add `s` to the table, returning its offset.
+    pub fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+        offset
+    }
+
+    pub fn into_section(self, name: &str) -> Section {
+        Section::new(name, SHT_STRTAB, self.data)
+    }
+}
+
+
+/// A symbol table. All local symbols have to be added before any global symbols.
+#[derive(Debug, Clone)]
+pub(crate) struct SymbolTable {
+    names: StringTable,
+    data: Vec<u8>,
+    count: u32,
+    locals: u32,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            names: StringTable::new(),
+            data: vec![0; SYMBOL_SIZE],
+            count: 1,
+            locals: 1,
+        }
+    }
+
+    /// Add a symbol, returning its index.
+    pub fn add(&mut self, name: &str, binding: u8, kind: u8, section: u16, value: u64, size: u64) -> u32 {
+        let name = self.names.add(name);
+        self.data.write_u32::<LittleEndian>(name).unwrap();
+        self.data.push((binding << 4) | kind);
+        self.data.push(0);
+        self.data.write_u16::<LittleEndian>(section).unwrap();
+        self.data.write_u64::<LittleEndian>(value).unwrap();
+        self.data.write_u64::<LittleEndian>(size).unwrap();
+
+        if binding == STB_LOCAL {
+            self.locals = self.count + 1;
+        }
+        self.count += 1;
+        self.count - 1
+    }
+
+    /// Turn this table into a `.symtab` and `.strtab` section. The `.symtab` section links to the section
+    /// with index `strtab_index`, which should be the `.strtab` section.
+    pub fn into_sections(self, strtab_index: u16) -> (Section, Section) {
+        let mut symtab = Section::new(".symtab", SHT_SYMTAB, self.data);
+        symtab.link = u32::from(strtab_index);
+        symtab.info = self.locals;
+        symtab.align = 8;
+        symtab.entsize = SYMBOL_SIZE as u64;
+        (symtab, self.names.into_section(".strtab"))
+    }
+}
+
+
+/// Append an `Elf64_Rela` entry to `buf`.
+pub(crate) fn write_rela(buf: &mut Vec<u8>, offset: u64, symbol: u32, kind: u32, addend: i64) {
+    buf.write_u64::<LittleEndian>(offset).unwrap();
+    buf.write_u64::<LittleEndian>((u64::from(symbol) << 32) | u64::from(kind)).unwrap();
+    buf.write_i64::<LittleEndian>(addend).unwrap();
+}
+
+
+/// Builds an ELF file out of a list of sections.
+#[derive(Debug, Clone)]
+pub(crate) struct ElfWriter {
+    elf_type: u16,
+    machine: u16,
+    sections: Vec<Section>,
+}
+
+impl ElfWriter {
+    pub fn new(elf_type: u16, machine: u16) -> ElfWriter {
+        ElfWriter {
+            elf_type,
+            machine,
+            sections: vec![Section::new("", 0, Vec::new())],
+        }
+    }
+
+    /// Returns the index the next added section will get.
+    pub fn next_index(&self) -> u16 {
+        self.sections.len() as u16
+    }
+
+    /// Add `section`, returning its index.
+    pub fn add_section(&mut self, section: Section) -> u16 {
+        self.sections.push(section);
+        self.sections.len() as u16 - 1
+    }
+
+    /// Lay out the file and write it out.
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut shstrtab = StringTable::new();
+        let mut names: Vec<u32> = self.sections.iter().map(|s| shstrtab.add(&s.name)).collect();
+        names.push(shstrtab.add(".shstrtab"));
+        let shstrndx = self.add_section(shstrtab.into_section(".shstrtab"));
+
+        // lay out the section contents after the header
+        let mut buf = vec![0; HEADER_SIZE];
+        let mut offsets = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            let align = section.align.max(1) as usize;
+            let padding = (align - buf.len() % align) % align;
+            buf.resize(buf.len() + padding, 0);
+            offsets.push(buf.len() as u64);
+            if section.kind != SHT_NOBITS {
+                buf.extend_from_slice(&section.data);
+            }
+        }
+
+        // followed by the section headers
+        let padding = (8 - buf.len() % 8) % 8;
+        buf.resize(buf.len() + padding, 0);
+        let shoff = buf.len() as u64;
+        for ((section, name), offset) in self.sections.iter().zip(names).zip(offsets) {
+            let offset = if section.kind == 0 { 0 } else { offset };
+            buf.write_u32::<LittleEndian>(name).unwrap();
+            buf.write_u32::<LittleEndian>(section.kind).unwrap();
+            buf.write_u64::<LittleEndian>(section.flags).unwrap();
+            buf.write_u64::<LittleEndian>(section.addr).unwrap();
+            buf.write_u64::<LittleEndian>(offset).unwrap();
+            buf.write_u64::<LittleEndian>(section.size).unwrap();
+            buf.write_u32::<LittleEndian>(section.link).unwrap();
+            buf.write_u32::<LittleEndian>(section.info).unwrap();
+            buf.write_u64::<LittleEndian>(section.align).unwrap();
+            buf.write_u64::<LittleEndian>(section.entsize).unwrap();
+        }
+
+        // and finally the file header
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        header.write_u16::<LittleEndian>(self.elf_type).unwrap();
+        header.write_u16::<LittleEndian>(self.machine).unwrap();
+        header.write_u32::<LittleEndian>(1).unwrap();
+        header.write_u64::<LittleEndian>(0).unwrap();
+        header.write_u64::<LittleEndian>(0).unwrap();
+        header.write_u64::<LittleEndian>(shoff).unwrap();
+        header.write_u32::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(HEADER_SIZE as u16).unwrap();
+        header.write_u16::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(0).unwrap();
+        header.write_u16::<LittleEndian>(SECTION_HEADER_SIZE as u16).unwrap();
+        header.write_u16::<LittleEndian>(self.sections.len() as u16).unwrap();
+        header.write_u16::<LittleEndian>(shstrndx).unwrap();
+        buf[.. HEADER_SIZE].copy_from_slice(&header);
+
+        buf
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{VecAssembler, DynasmApi, DynasmLabelApi, DynasmError, TargetKind};
+    use crate::relocations::{RelocationKind, RelocationSize};
+    use crate::x64::X64Relocation;
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_finalize_elf() {
+        use std::process::Command;
+
+        let mut ops = VecAssembler::<X64Relocation>::new(0);
+        ops.global_label("dynasm_answer");
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call ->dynasm_local
+        ops.global_reloc("dynasm_local", 0, (0, 4));
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->dynasm_forty_two
+        ops.global_reloc("dynasm_forty_two", 0, (0, 4));
+        ops.global_label("dynasm_local");
+        ops.push(0xC3); // ret
+        ops.align(8, 0);
+        ops.global_label("dynasm_pointer");
+        ops.extend(&[0; 8]); // .qword abs ->dynasm_answer
+        ops.global_relocation("dynasm_answer", 0, X64Relocation::with_kind(RelocationSize::QWord, RelocationKind::AbsToRel));
+        let object = ops.finalize_elf().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("dynasmrt-elf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("answer.o"), &object).unwrap();
+        std::fs::write(dir.join("main.c"), "
+            extern int (*const dynasm_pointer)(void);
+            int dynasm_forty_two(void) { return 42; }
+            int main(void) { return dynasm_pointer(); }
+        ").unwrap();
+
+        // the absolute pointer is relocated by the linker
+        let linked = Command::new("cc").current_dir(&dir).args(["main.c", "answer.o", "-no-pie", "-o", "answer"]).status();
+        assert!(linked.expect("linking the object requires a system C compiler").success());
+        let status = Command::new(dir.join("answer")).status().unwrap();
+        assert_eq!(status.code(), Some(42));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // relocations depending on the address of the code that have no ELF equivalent cannot be expressed
+        let mut ops = VecAssembler::<X64Relocation>::new(0);
+        ops.extend(&[0, 0, 0, 0]); // .dword rel 0x1234
+        ops.bare_relocation(0x1234, X64Relocation::with_kind(RelocationSize::DWord, RelocationKind::RelToAbs));
+        assert_eq!(ops.finalize_elf().unwrap_err(), DynasmError::ImpossibleRelocation(TargetKind::Managed));
+    }
+}
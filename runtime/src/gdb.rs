@@ -0,0 +1,247 @@
+//! This module implements the GDB JIT interface, which lets debuggers symbolize assembled code.
+//!
+//! Debuggers that support this interface put a breakpoint on `__jit_debug_register_code`, and read the
+//! list of in-memory symbol files that `__jit_debug_descriptor` points to whenever it is called. A
+//! `GdbJitEntry` generates such a symbol file for a set of functions, and keeps it registered for as long as it lives.
+//!
+//! Entries can be created directly, or automatically by enabling them on a `PerfRecorder` attached to an `Assembler`
+//! or on a `CodeHeap`.
+//!
+//! As the symbols debuggers look for have to be unique in a program, this module is only available with the
+//! `gdb-jit` feature.
+
+use crate::elf::{self, ElfWriter, Section, SymbolTable};
+
+use std::ptr;
+use std::sync::Mutex;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+/// The descriptor debuggers read the list of registered symbol files from. This has to be exported
+/// under this exact name.
+#[repr(C)]
+#[derive(Debug)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// The global GDB JIT interface descriptor.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// Debuggers put a breakpoint on this function to be notified of changes to `__jit_debug_descriptor`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // make sure calls to this function are not optimized out
+    unsafe {
+        ptr::read_volatile(ptr::addr_of!(__jit_debug_descriptor.action_flag));
+    }
+}
+
+// serializes access to the descriptor
+static REGISTRY: Mutex<()> = Mutex::new(());
+
+
+/// A symbol file describing a set of assembled functions, registered with the GDB JIT interface.
+/// The symbol file is unregistered again when this is dropped.
+#[derive(Debug)]
+pub struct GdbJitEntry {
+    entry: *mut JitCodeEntry,
+    symfile: Box<[u8]>,
+}
+
+// The entry is only accessed while holding the registry lock.
+unsafe impl Send for GdbJitEntry {}
+unsafe impl Sync for GdbJitEntry {}
+
+impl GdbJitEntry {
+    /// Generate a symbol file for the functions in `symbols`, given as their name, address and size,
+    /// and register it.
+    pub fn register(symbols: &[(&str, usize, usize)]) -> GdbJitEntry {
+        let symfile = symbol_file(symbols).into_boxed_slice();
+
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        }));
+
+        let _guard = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            (*entry).next_entry = (*descriptor).first_entry;
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = entry;
+            }
+            (*descriptor).first_entry = entry;
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+
+        GdbJitEntry {
+            entry,
+            symfile
+        }
+    }
+
+    /// Returns the generated symbol file.
+    pub fn symbol_file(&self) -> &[u8] {
+        &self.symfile
+    }
+}
+
+impl Drop for GdbJitEntry {
+    fn drop(&mut self) {
+        let _guard = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = self.entry;
+            if (*entry).prev_entry.is_null() {
+                (*descriptor).first_entry = (*entry).next_entry;
+            } else {
+                (*(*entry).prev_entry).next_entry = (*entry).next_entry;
+            }
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = (*entry).prev_entry;
+            }
+            (*descriptor).relevant_entry = entry;
+            (*descriptor).action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+
+            (*descriptor).relevant_entry = ptr::null_mut();
+            (*descriptor).action_flag = JIT_NOACTION;
+            drop(Box::from_raw(entry));
+        }
+    }
+}
+
+
+// Generate a relocatable ELF file with a `.text` section placed over the range covered by `symbols`,
+// and a function symbol for each of them.
+fn symbol_file(symbols: &[(&str, usize, usize)]) -> Vec<u8> {
+    let start = symbols.iter().map(|&(_, addr, _)| addr).min().unwrap_or(0);
+    let end = symbols.iter().map(|&(_, addr, size)| addr + size).max().unwrap_or(0);
+
+    let mut writer = ElfWriter::new(elf::ET_REL, elf::native_machine());
+
+    let mut text = Section::new(".text", elf::SHT_NOBITS, Vec::new());
+    text.flags = elf::SHF_ALLOC | elf::SHF_EXECINSTR;
+    text.addr = start as u64;
+    text.size = (end - start) as u64;
+    text.align = 16;
+    let text_index = writer.add_section(text);
+
+    let mut table = SymbolTable::new();
+    for &(name, addr, size) in symbols {
+        table.add(name, elf::STB_GLOBAL, elf::STT_FUNC, text_index, (addr - start) as u64, size as u64);
+    }
+
+    let (symtab, strtab) = table.into_sections(writer.next_index() + 1);
+    writer.add_section(symtab);
+    writer.add_section(strtab);
+    writer.finish()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::CodeHeap;
+    use crate::x64::X64Relocation;
+    use crate::DynasmLabelApi;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    // parse the function symbols out of a symbol file, as their name and address
+    fn parse_symbols(file: &[u8]) -> Vec<(String, u64)> {
+        assert_eq!(&file[..4], b"\x7FELF");
+        let shoff = LittleEndian::read_u64(&file[40..]) as usize;
+        let shnum = LittleEndian::read_u16(&file[60..]) as usize;
+        let section = |i: usize| &file[shoff + i * 64 .. shoff + (i + 1) * 64];
+
+        let symtab = (0 .. shnum).map(section).find(|s| LittleEndian::read_u32(&s[4..]) == elf::SHT_SYMTAB).unwrap();
+        let strtab = section(LittleEndian::read_u32(&symtab[40..]) as usize);
+        let strings = &file[LittleEndian::read_u64(&strtab[24..]) as usize ..];
+        let symbols = &file[LittleEndian::read_u64(&symtab[24..]) as usize ..][.. LittleEndian::read_u64(&symtab[32..]) as usize];
+
+        symbols.chunks(24).skip(1).map(|symbol| {
+            let name = &strings[LittleEndian::read_u32(symbol) as usize ..];
+            let name = String::from_utf8(name[.. name.iter().position(|&c| c == 0).unwrap()].to_vec()).unwrap();
+            let text = section(LittleEndian::read_u16(&symbol[6..]) as usize);
+            (name, LittleEndian::read_u64(&text[16..]) + LittleEndian::read_u64(&symbol[8..]))
+        }).collect()
+    }
+
+    // collect the symbols of all registered symbol files
+    fn registered_symbols() -> Vec<(String, u64)> {
+        let _guard = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        let mut symbols = Vec::new();
+        unsafe {
+            let mut entry = (*ptr::addr_of!(__jit_debug_descriptor)).first_entry;
+            while !entry.is_null() {
+                let file = std::slice::from_raw_parts((*entry).symfile_addr, (*entry).symfile_size as usize);
+                symbols.extend(parse_symbols(file));
+                entry = (*entry).next_entry;
+            }
+        }
+        symbols
+    }
+
+    #[test]
+    fn test_gdb_jit_entry() {
+        let first = GdbJitEntry::register(&[("gdb_test_a", 0x1000, 16), ("gdb_test_b", 0x1010, 8)]);
+        let second = GdbJitEntry::register(&[("gdb_test_c", 0x2000, 4)]);
+
+        let symbols = registered_symbols();
+        assert!(symbols.contains(&("gdb_test_a".to_string(), 0x1000)));
+        assert!(symbols.contains(&("gdb_test_b".to_string(), 0x1010)));
+        assert!(symbols.contains(&("gdb_test_c".to_string(), 0x2000)));
+
+        drop(first);
+        let symbols = registered_symbols();
+        assert!(!symbols.iter().any(|(name, _)| name == "gdb_test_a" || name == "gdb_test_b"));
+        assert!(symbols.contains(&("gdb_test_c".to_string(), 0x2000)));
+
+        drop(second);
+        assert!(!registered_symbols().iter().any(|(name, _)| name.starts_with("gdb_test_")));
+    }
+
+    #[test]
+    fn test_gdb_jit_heap() {
+        let mut heap = CodeHeap::<X64Relocation>::new(4096).unwrap();
+        heap.enable_gdb_jit();
+
+        let mut ops = heap.assembler();
+        ops.global_label("gdb_heap_function");
+        ops.extend(&[0xC3]);
+        let function = ops.finish().unwrap();
+
+        let address = heap.address(function) as u64;
+        assert!(registered_symbols().contains(&("gdb_heap_function".to_string(), address)));
+
+        heap.free(function);
+        assert!(!registered_symbols().iter().any(|(name, _)| name == "gdb_heap_function"));
+    }
+}
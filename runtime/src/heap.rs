@@ -0,0 +1,577 @@
+//! This module implements a code heap: a region of executable memory into which functions can be assembled
+//! individually, and from which they can be freed again so their memory can be reused for later functions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::io;
+use std::mem;
+
+use crate::{AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi, DynasmError, Executor, LabelKind, TargetKind};
+use crate::components::{LabelRegistry, RelocRegistry, VeneerRegistry, SymbolRegistry, SymbolResolver, PatchLoc};
+use crate::mmap::ExecutableBuffer;
+#[cfg(feature = "gdb-jit")]
+use crate::gdb::GdbJitEntry;
+use crate::relocations::{Relocation, RelocationKind};
+
+/// The alignment of the start of every function allocated in a `CodeHeap`.
+pub const FUNCTION_ALIGNMENT: usize = 16;
+
+
+/// A handle to a function that was assembled into a `CodeHeap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HeapFunction(usize);
+
+// bookkeeping for a function that lives in the heap
+#[derive(Debug)]
+struct Allocation {
+    offset: usize,
+    size: usize,
+    globals: Vec<&'static str>,
+    // the registration of this function with the GDB JIT interface, if enabled
+    #[cfg(feature = "gdb-jit")]
+    _debug: Option<GdbJitEntry>,
+}
+
+
+/// A fixed size region of executable memory that functions can be assembled into one at a time using a `HeapAssembler`.
+/// Every function gets its own allocation, which can be freed later so the memory can be reused. As functions never move,
+/// functions can refer to the global labels defined by other functions that are still alive.
+/// Code in the heap is executed through an `Executor`, the same way as code in an `Assembler`.
+#[derive(Debug)]
+pub struct CodeHeap<R: Relocation> {
+    execbuffer: Arc<RwLock<ExecutableBuffer>>,
+    // the address that the heap starts at
+    execbuffer_addr: usize,
+    // the total size of the heap
+    size: usize,
+
+    // free ranges in the heap, mapping offset to size. adjacent ranges are always merged.
+    free: BTreeMap<usize, usize>,
+    // all functions that are currently alive
+    allocations: HashMap<usize, Allocation>,
+    next_id: usize,
+
+    // global labels exported by functions that are currently alive, mapping to their offset in the heap
+    exports: HashMap<&'static str, usize>,
+    // resolves the symbols targeted by `extern "name"`
+    symbols: SymbolRegistry,
+
+    // if functions should be registered with the GDB JIT interface
+    #[cfg(feature = "gdb-jit")]
+    gdb_jit: bool,
+
+    phantom: std::marker::PhantomData<R>,
+}
+
+impl<R: Relocation> CodeHeap<R> {
+    /// Create a new code heap, reserving `size` bytes of executable memory (rounded up to `page_size`).
+    pub fn new(size: usize) -> io::Result<Self> {
+        let page_size = R::page_size();
+        let size = match size % page_size {
+            0 => size,
+            misalign => size + page_size - misalign
+        };
+
+        let execbuffer = ExecutableBuffer::new(size)?;
+        let execbuffer_addr = execbuffer.base_addr();
+
+        let mut free = BTreeMap::new();
+        if size != 0 {
+            free.insert(0, size);
+        }
+
+        Ok(CodeHeap {
+            execbuffer: Arc::new(RwLock::new(execbuffer)),
+            execbuffer_addr,
+            size,
+            free,
+            allocations: HashMap::new(),
+            next_id: 0,
+            exports: HashMap::new(),
+            symbols: SymbolRegistry::new(),
+            #[cfg(feature = "gdb-jit")]
+            gdb_jit: false,
+            phantom: std::marker::PhantomData,
+        })
+    }
+
+    /// Create a `HeapAssembler` that assembles a single new function into this heap.
+    pub fn assembler(&mut self) -> HeapAssembler<'_, R> {
+        HeapAssembler {
+            heap: self,
+            ops: Vec::new(),
+            labels: LabelRegistry::new(),
+            relocs: RelocRegistry::new(),
+            resolved: Vec::new(),
+            veneers: VeneerRegistry::new(),
+            error: None,
+        }
+    }
+
+    /// Use `resolver` to resolve the symbols targeted by `extern "name"` in functions assembled into this heap.
+    pub fn set_symbol_resolver<S: SymbolResolver + Send + 'static>(&mut self, resolver: S) {
+        self.symbols.set_resolver(resolver);
+    }
+
+    /// Register every function assembled into this heap from now on with the GDB JIT interface, so debuggers
+    /// can symbolize it. Functions are named after the global labels they define, and are unregistered when freed.
+    #[cfg(feature = "gdb-jit")]
+    pub fn enable_gdb_jit(&mut self) {
+        self.gdb_jit = true;
+    }
+
+    /// Returns the offset of the start of `function` in the executable buffer of this heap.
+    pub fn offset(&self, function: HeapFunction) -> AssemblyOffset {
+        AssemblyOffset(self.allocation(function).offset)
+    }
+
+    /// Returns the size of the allocation of `function`.
+    pub fn size_of(&self, function: HeapFunction) -> usize {
+        self.allocation(function).size
+    }
+
+    /// Returns the address at which `function` starts. As functions in a heap never move, this address is valid
+    /// for as long as the function is alive.
+    pub fn address(&self, function: HeapFunction) -> usize {
+        self.execbuffer_addr + self.allocation(function).offset
+    }
+
+    /// Returns the offset in the executable buffer of this heap of the global label `name`,
+    /// if it is defined by a function that is still alive.
+    pub fn resolve_global(&self, name: &'static str) -> Result<AssemblyOffset, DynasmError> {
+        self.exports.get(&name).map(|&o| AssemblyOffset(o)).ok_or(DynasmError::UnknownLabel(LabelKind::Global(name)))
+    }
+
+    /// Returns the total size of this heap.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the amount of bytes in this heap that are not allocated to any function.
+    pub fn free_space(&self) -> usize {
+        self.free.values().sum()
+    }
+
+    /// Free `function`, allowing its memory to be reused by functions assembled later.
+    /// The global labels it defined can no longer be referred to by new functions.
+    /// Any function that is still alive and refers to this function will now refer to invalid code,
+    /// so these should be freed as well before such code is executed again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `function` was already freed.
+    pub fn free(&mut self, function: HeapFunction) {
+        let allocation = self.allocations.remove(&function.0).expect("Function was already freed");
+
+        for name in allocation.globals {
+            self.exports.remove(&name);
+        }
+
+        self.release(allocation.offset, allocation.size);
+    }
+
+    /// Create an executor which can be used to execute code in this heap while more functions are assembled into it.
+    pub fn reader(&self) -> Executor {
+        Executor {
+            execbuffer: self.execbuffer.clone()
+        }
+    }
+
+    fn allocation(&self, function: HeapFunction) -> &Allocation {
+        self.allocations.get(&function.0).expect("Function was already freed")
+    }
+
+    // find the first free range that fits size bytes, and take it out of the free list
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let size = match size % FUNCTION_ALIGNMENT {
+            0 => size.max(FUNCTION_ALIGNMENT),
+            misalign => size + FUNCTION_ALIGNMENT - misalign
+        };
+
+        let (&offset, &free_size) = self.free.iter().find(|&(_, &free_size)| free_size >= size)?;
+        self.free.remove(&offset);
+        if free_size > size {
+            self.free.insert(offset + size, free_size - size);
+        }
+        Some(offset)
+    }
+
+    // return a range to the free list, merging it with its neighbours
+    fn release(&mut self, mut offset: usize, mut size: usize) {
+        size = match size % FUNCTION_ALIGNMENT {
+            0 => size.max(FUNCTION_ALIGNMENT),
+            misalign => size + FUNCTION_ALIGNMENT - misalign
+        };
+
+        if let Some(next_size) = self.free.remove(&(offset + size)) {
+            size += next_size;
+        }
+
+        let previous = self.free.range(.. offset).next_back().map(|(&o, &s)| (o, s));
+        if let Some((prev_offset, prev_size)) = previous {
+            if prev_offset + prev_size == offset {
+                self.free.remove(&prev_offset);
+                offset = prev_offset;
+                size += prev_size;
+            }
+        }
+
+        self.free.insert(offset, size);
+    }
+}
+
+
+/// An assembler that assembles a single function into a `CodeHeap`. As the location of the function is only known
+/// once it has been fully assembled, all relocations are resolved when it is finished with `HeapAssembler::finish`.
+/// Global labels that are not defined by this function are resolved to the global labels exported by functions
+/// that are alive in the heap.
+#[derive(Debug)]
+pub struct HeapAssembler<'a, R: Relocation> {
+    heap: &'a mut CodeHeap<R>,
+    ops: Vec<u8>,
+    labels: LabelRegistry,
+    relocs: RelocRegistry<R>,
+    // relocations whose target is known, but that still have to be written once the function is placed.
+    // the target is either an offset in this function, or for extern relocations an absolute address.
+    resolved: Vec<(PatchLoc<R>, usize, TargetKind)>,
+    // branches to absolute addresses
+    veneers: VeneerRegistry<R>,
+    error: Option<DynasmError>,
+}
+
+impl<'a, R: Relocation> HeapAssembler<'a, R> {
+    /// Create a new dynamic label ID. Dynamic labels are local to the function being assembled.
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        self.labels.new_dynamic_label()
+    }
+
+    /// Provides access to the labels defined in the function being assembled.
+    pub fn labels(&self) -> &LabelRegistry {
+        &self.labels
+    }
+
+    /// Finish assembling this function, allocating memory for it in the heap, resolving all its relocations and
+    /// making it available for execution. Any global labels defined in it are exported to the heap.
+    pub fn finish(mut self) -> Result<HeapFunction, DynasmError> {
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // Resolve globals, first to this function, then to the rest of the heap.
+        // Targets outside of this function are kept as offsets relative to the start of the heap for now.
+        let mut external = Vec::new();
+        for (loc, name) in self.relocs.take_globals() {
+            match self.labels.resolve_global(name) {
+                Ok(target) => self.resolved.push((loc, target.0, TargetKind::Global(name))),
+                Err(_) => external.push((loc, self.heap.resolve_global(name)?.0, TargetKind::Global(name)))
+            }
+        }
+
+        // Resolve dynamics
+        for (loc, id) in self.relocs.take_dynamics() {
+            let target = self.labels.resolve_dynamic(id)?;
+            self.resolved.push((loc, target.0, TargetKind::Dynamic(id)));
+        }
+
+        // Check that there are no unknown local labels
+        if let Some((_, name)) = self.relocs.take_locals().next() {
+            return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
+        }
+
+        // Resolve symbols
+        for (loc, name) in self.relocs.take_symbols() {
+            let target = self.heap.symbols.resolve(name)?;
+            if loc.relocation.kind() == RelocationKind::Relative {
+                self.veneers.add(loc, target);
+            } else {
+                self.resolved.push((loc, target, TargetKind::Extern(target)));
+            }
+        }
+
+        // Check that the global labels defined in this function are not yet defined elsewhere
+        let globals: Vec<_> = self.labels.globals().map(|(name, _)| name).collect();
+        if let Some(&name) = globals.iter().find(|name| self.heap.exports.contains_key(*name)) {
+            return Err(DynasmError::DuplicateLabel(LabelKind::Global(name)));
+        }
+
+        // add veneers for branches to absolute addresses, which are used if their targets end up out of range
+        self.veneers.emit(0, None, &mut self.ops);
+
+        // place the function
+        let offset = match self.heap.allocate(self.ops.len()) {
+            Some(offset) => offset,
+            None => return Err(DynasmError::OutOfMemory)
+        };
+        let base_addr = self.heap.execbuffer_addr + offset;
+
+        // express targets in the rest of the heap relative to the start of this function
+        let heap_addr = self.heap.execbuffer_addr;
+        let mut resolved = mem::take(&mut self.resolved);
+        resolved.extend(external.into_iter().map(|(loc, target, kind)| {
+            let target = match loc.relocation.kind() {
+                RelocationKind::RelToAbs => target + heap_addr,
+                RelocationKind::Relative
+                | RelocationKind::AbsToRel => target.wrapping_sub(offset)
+            };
+            (loc, target, kind)
+        }));
+
+        // and encode all relocations
+        for (loc, target, kind) in resolved {
+            if loc.patch(0, base_addr, &mut self.ops, target).is_err() {
+                self.heap.release(offset, self.ops.len());
+                return Err(DynasmError::ImpossibleRelocation(kind));
+            }
+        }
+        if let Err(e) = self.veneers.patch(0, Some(base_addr), &mut self.ops) {
+            self.heap.release(offset, self.ops.len());
+            return Err(e);
+        }
+
+        // copy the function into the heap
+        self.heap.execbuffer.write().unwrap().write_at(offset, &self.ops).expect("Could not swap buffer protection modes");
+
+        // and register it
+        for (name, label_offset) in self.labels.globals() {
+            self.heap.exports.insert(name, offset + label_offset.0);
+        }
+
+        let id = self.heap.next_id;
+        self.heap.next_id += 1;
+
+        #[cfg(feature = "gdb-jit")]
+        let debug = if self.heap.gdb_jit {
+            Some(self.register_symbols(id, base_addr))
+        } else {
+            None
+        };
+
+        self.heap.allocations.insert(id, Allocation {
+            offset,
+            size: self.ops.len(),
+            globals,
+            #[cfg(feature = "gdb-jit")]
+            _debug: debug
+        });
+
+        Ok(HeapFunction(id))
+    }
+
+    // register the function with the GDB JIT interface, with a symbol for every global label it defines,
+    // extending up to the next one.
+    #[cfg(feature = "gdb-jit")]
+    fn register_symbols(&self, id: usize, base_addr: usize) -> GdbJitEntry {
+        let mut starts: Vec<_> = self.labels.globals().map(|(name, offset)| (name.to_string(), offset.0)).collect();
+        starts.sort_by_key(|&(_, offset)| offset);
+        match starts.first() {
+            Some(&(_, 0)) => (),
+            _ => starts.insert(0, (format!("heap_function_{}", id), 0))
+        }
+
+        let symbols: Vec<_> = starts.iter().enumerate().map(|(i, (name, start))| {
+            let end = starts[i + 1 ..].iter().map(|&(_, s)| s).find(|&s| s > *start).unwrap_or(self.ops.len());
+            (name.as_str(), base_addr + start, end - start)
+        }).collect();
+        GdbJitEntry::register(&symbols)
+    }
+}
+
+impl<'a, R: Relocation> Extend<u8> for HeapAssembler<'a, R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        self.ops.extend(iter)
+    }
+}
+
+impl<'a, 'b, R: Relocation> Extend<&'b u8> for HeapAssembler<'a, R> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'b u8> {
+        self.ops.extend(iter)
+    }
+}
+
+impl<'a, R: Relocation> DynasmApi for HeapAssembler<'a, R> {
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.ops.len())
+    }
+    fn push(&mut self, byte: u8) {
+        self.ops.push(byte);
+    }
+    fn align(&mut self, alignment: usize, with: u8) {
+        let offset = self.offset().0 % alignment;
+        if offset != 0 {
+            for _ in offset .. alignment {
+                self.push(with);
+            }
+        }
+    }
+}
+
+impl<'a, R: Relocation> DynasmLabelApi for HeapAssembler<'a, R> {
+    type Relocation = R;
+
+    fn local_label(&mut self, name: &'static str) {
+        let offset = self.offset();
+        for loc in self.relocs.take_locals_named(name) {
+            self.resolved.push((loc, offset.0, TargetKind::Forward(name)));
+        }
+        self.labels.define_local(name, offset);
+    }
+    fn global_label( &mut self, name: &'static str) {
+        let offset = self.offset();
+        if let Err(e) = self.labels.define_global(name, offset) {
+            self.error = Some(e)
+        }
+    }
+    fn dynamic_label(&mut self, id: DynamicLabel) {
+        let offset = self.offset();
+        if let Err(e) = self.labels.define_dynamic(id, offset) {
+            self.error = Some(e)
+        }
+    }
+    fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+    }
+    fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+    }
+    fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let location = self.offset();
+        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+    }
+    fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
+        let target = match self.labels.resolve_local(name) {
+            Ok(target) => target.0,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            }
+        };
+        let location = self.offset();
+        self.resolved.push((PatchLoc::new(location, offset, kind), target, TargetKind::Backward(name)));
+    }
+    fn bare_relocation(&mut self, target: usize, kind: R) {
+        let location = self.offset();
+        let loc = PatchLoc::new(location, 0, kind);
+        if loc.relocation.kind() == RelocationKind::Relative {
+            self.veneers.add(loc, target);
+        } else {
+            self.resolved.push((loc, target, TargetKind::Extern(target)));
+        }
+    }
+    fn symbol_relocation(&mut self, name: &'static str, kind: R) {
+        let location = self.offset();
+        self.relocs.add_symbol(name, PatchLoc::new(location, 0, kind));
+    }
+    fn push_label_scope(&mut self) {
+        self.labels.push_label_scope(&mut self.relocs);
+    }
+    fn pop_label_scope(&mut self) {
+        if let Err(e) = self.labels.pop_label_scope(&mut self.relocs) {
+            self.error = Some(e)
+        }
+    }
+    fn record_error(&mut self, error: DynasmError) {
+        self.error = Some(error)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x64::X64Relocation;
+
+    // mov eax, 42; ret
+    const ANSWER: [u8; 6] = [0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3];
+
+    #[test]
+    fn test_heap_reuse() {
+        let mut heap = CodeHeap::<X64Relocation>::new(4096).unwrap();
+
+        let mut ops = heap.assembler();
+        ops.extend(&ANSWER);
+        let first = ops.finish().unwrap();
+
+        let mut ops = heap.assembler();
+        ops.extend(&[0xCC; 100]);
+        let second = ops.finish().unwrap();
+        assert_eq!(heap.offset(first), AssemblyOffset(0));
+        assert_eq!(heap.offset(second), AssemblyOffset(16));
+        assert_eq!(heap.free_space(), 4096 - 16 - 112);
+
+        heap.free(first);
+        let mut ops = heap.assembler();
+        ops.extend(&ANSWER);
+        let third = ops.finish().unwrap();
+        assert_eq!(heap.offset(third), AssemblyOffset(0));
+
+        heap.free(second);
+        heap.free(third);
+        assert_eq!(heap.free_space(), 4096);
+
+        let mut ops = heap.assembler();
+        ops.extend(vec![0xCC; 4097]);
+        assert_eq!(ops.finish(), Err(DynasmError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_heap_linking() {
+        let mut heap = CodeHeap::<X64Relocation>::new(4096).unwrap();
+        let reader = heap.reader();
+
+        let mut ops = heap.assembler();
+        ops.global_label("answer");
+        ops.extend(&ANSWER);
+        let answer = ops.finish().unwrap();
+
+        // call ->answer; add eax, 1; ret
+        let mut ops = heap.assembler();
+        ops.extend(&[0xE8, 0x00, 0x00, 0x00, 0x00]);
+        ops.global_reloc("answer", 0, (0, 4));
+        ops.extend(&[0x83, 0xC0, 0x01, 0xC3]);
+        let caller = ops.finish().unwrap();
+
+        let buf = reader.lock();
+        let call = &buf[heap.offset(caller).0 .. heap.offset(caller).0 + 5];
+        assert_eq!(call, &[0xE8, 0xEB, 0xFF, 0xFF, 0xFF]);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(heap.address(caller)) };
+            assert_eq!(f(), 43);
+        }
+        drop(buf);
+
+        // once freed, the label can no longer be linked against
+        heap.free(answer);
+        let mut ops = heap.assembler();
+        ops.extend(&[0xE8, 0x00, 0x00, 0x00, 0x00]);
+        ops.global_reloc("answer", 0, (0, 4));
+        assert_eq!(ops.finish(), Err(DynasmError::UnknownLabel(LabelKind::Global("answer"))));
+    }
+
+    #[test]
+    fn test_heap_sections() {
+        let mut heap = CodeHeap::<X64Relocation>::new(4096).unwrap();
+
+        // without support for sections, cold code is placed in order
+        let mut ops = heap.assembler();
+        ops.push(0x90);
+        ops.switch_section(crate::Section::Cold);
+        ops.extend(&ANSWER);
+        ops.switch_section(crate::Section::Hot);
+        ops.push(0xCC);
+        let function = ops.finish().unwrap();
+        assert_eq!(heap.size_of(function), 8);
+        let start = heap.offset(function).0;
+        assert_eq!(&heap.reader().lock()[start + 1 .. start + 7], &ANSWER);
+
+        // data cannot be placed between code, which is reported when the function is finished
+        let mut ops = heap.assembler();
+        ops.switch_section(crate::Section::Data);
+        ops.push(0);
+        assert_eq!(ops.finish(), Err(DynasmError::UnsupportedDirective(".section")));
+    }
+}
@@ -6,6 +6,7 @@
 
 extern crate memmap;
 extern crate byteorder;
+extern crate libc;
 
 pub mod mmap;
 pub mod components;
@@ -13,17 +14,27 @@ pub mod relocations;
 pub mod x64;
 pub mod x86;
 pub mod aarch64;
-
-pub use crate::mmap::ExecutableBuffer;
-use crate::components::{MemoryManager, LabelRegistry, RelocRegistry, ManagedRelocs, PatchLoc};
-use crate::relocations::Relocation;
+pub mod heap;
+pub mod perf;
+#[cfg(feature = "gdb-jit")]
+pub mod gdb;
+pub mod unwind;
+pub mod module;
+mod elf;
+
+pub use crate::mmap::{ExecutableBuffer, PlacementHint};
+use crate::components::{MemoryManager, MappingStrategy, AtomicBuffer, LabelRegistry, LabelCheckpoint, RelocRegistry, ManagedRelocs, RelaxRegistry, VeneerRegistry, SymbolRegistry, SymbolResolver, LabelExports, ImportRegistry, RetargetRegistry, PatchpointRegistry, CodeLayout, PatchLoc, CopyPatchLoc, SectionBuffer, SectionCheckpoint, intern};
+use crate::relocations::{Relocation, RelocationKind, pad};
+use crate::perf::PerfRecorder;
+use crate::unwind::{CfiBuilder, UnwindTable};
+use crate::module::{CodeModule, LoadedModule};
 
 use std::iter::Extend;
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::io;
 use std::error;
 use std::fmt;
-use std::mem;
 
 /// This macro takes a *const pointer from the source operand, and then casts it to the desired return type.
 /// this allows it to be used as an easy shorthand for passing pointers as dynasm immediate arguments.
@@ -44,9 +55,10 @@ macro_rules! MutPointer {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AssemblyOffset(pub usize);
 
-/// A dynamic label
+/// A dynamic label. Besides its ID, it contains a version that is bumped every time the ID is freed, which is
+/// used to detect uses of freed dynamic labels in debug builds.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct DynamicLabel(usize);
+pub struct DynamicLabel(usize, u32);
 
 
 impl DynamicLabel {
@@ -57,6 +69,59 @@ impl DynamicLabel {
 }
 
 
+/// A handle to a patchpoint, created by `Assembler::patchpoint`, that can be used to retarget it. Handles to
+/// patchpoints that were discarded by a rollback are never valid again, as every id is paired with a version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Patchpoint(usize, u32);
+
+
+/// A section of an assembler that code can be assembled into, selected with `DynasmLabelApi::switch_section`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    /// The section code is assembled into by default.
+    Hot,
+    /// A section for code that is rarely executed, like slow paths. When code is committed, the uncommitted code
+    /// of this section is placed after the uncommitted code of the hot section. Until then, its code has provisional
+    /// offsets of `1 << 62` and up, which is what `offset` returns and labels defined in it resolve to. These can be
+    /// used to refer to the code within the assembler, but only locate it once it has been committed.
+    Cold,
+    /// A section for constants. An `Assembler` created with `Assembler::with_data_sections` commits it to read-only
+    /// memory, while a `VecAssembler` places it after the cold section.
+    Rodata,
+    /// A section for data that code can modify. An `Assembler` created with `Assembler::with_data_sections` commits it
+    /// to writable memory, while a `VecAssembler` places it after the read-only data section.
+    Data,
+}
+
+
+/// The state of an assembler at some point in its uncommitted code, created by `checkpoint`. Rolling the assembler
+/// back to it with `rollback` discards everything assembled since, as long as no code was committed in between.
+#[derive(Debug)]
+pub struct Checkpoint<R: Relocation> {
+    // the end of the hot code, and the state of the cold, read-only data and data sections
+    offset: AssemblyOffset,
+    sections: [SectionCheckpoint; 3],
+    section: Section,
+    committed: usize,
+    labels: LabelCheckpoint,
+    relocs: RelocRegistry<R>,
+    relax: Option<RelaxRegistry<R>>,
+    error: Option<DynasmError>,
+    // the amount of resolved relocations of a VecAssembler
+    resolved: usize,
+}
+
+impl<R: Relocation> Checkpoint<R> {
+    // returns if code at `offset` was assembled after this checkpoint
+    fn discards(&self, offset: usize) -> bool {
+        match self.sections.iter().find(|section| section.contains(offset)) {
+            Some(section) => offset >= section.end(),
+            None => offset >= self.offset.0
+        }
+    }
+}
+
+
 /// A read-only shared reference to the executable buffer inside an Assembler. By
 /// locking it the internal `ExecutableBuffer` can be accessed and executed.
 #[derive(Debug, Clone)]
@@ -70,7 +135,8 @@ impl Executor {
     /// Gain read-access to the internal `ExecutableBuffer`. While the returned guard
     /// is alive, it can be used to read and execute from the `ExecutableBuffer`.
     /// Any pointers created to the `Executablebuffer` should no longer be used when
-    /// the guard is dropped.
+    /// the guard is dropped, unless the assembler was created with `Assembler::with_reservation`,
+    /// in which case they stay valid as long as the buffer lives.
     #[inline]
     pub fn lock(&self) -> RwLockReadGuard<ExecutableBuffer> {
         self.execbuffer.read().unwrap()
@@ -78,6 +144,26 @@ impl Executor {
 }
 
 
+/// A read-only shared reference to the executable buffer inside an Assembler that uses
+/// `MappingStrategy::LockFree`. The internal `ExecutableBuffer` can be accessed without locking.
+#[derive(Debug, Clone)]
+pub struct LockFreeExecutor {
+    execbuffer: Arc<AtomicBuffer>
+}
+
+impl LockFreeExecutor {
+    /// Obtain a reference to the current `ExecutableBuffer`. While the returned reference
+    /// is alive, it can be used to read and execute from the `ExecutableBuffer`.
+    /// Code committed after this call is not guaranteed to be visible through the returned reference,
+    /// and code altered after this call is never visible through it. Any pointers created to the
+    /// `ExecutableBuffer` should no longer be used when the reference is dropped.
+    #[inline]
+    pub fn load(&self) -> Arc<ExecutableBuffer> {
+        self.execbuffer.load()
+    }
+}
+
+
 /// A description of a label. Used for error reporting.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum LabelKind {
@@ -140,8 +226,18 @@ pub enum DynasmError {
     DuplicateLabel(LabelKind),
     /// An unknown label
     UnknownLabel(LabelKind),
+    /// A symbol was referenced that the symbol resolver of the assembler could not resolve
+    UnknownSymbol(&'static str),
     /// The user tried to declare a relocation too far away from the label it targets
     ImpossibleRelocation(TargetKind),
+    /// There was not enough free memory to place the assembled code
+    OutOfMemory,
+    /// A label scope was left that was never entered
+    UnbalancedScope,
+    /// The assembler was rolled back to a checkpoint in code that has been committed since
+    RollbackPastCommit,
+    /// A directive was used that the assembler does not support
+    UnsupportedDirective(&'static str),
 }
 
 impl fmt::Display for DynasmError {
@@ -150,7 +246,12 @@ impl fmt::Display for DynasmError {
             DynasmError::CheckFailed => write!(f, "An assembly modification check failed"),
             DynasmError::DuplicateLabel(l) => write!(f, "Duplicate label defined: '{}'", l),
             DynasmError::UnknownLabel(l) => write!(f, "Unknown label: '{}'", l),
+            DynasmError::UnknownSymbol(s) => write!(f, "Unknown symbol: '{}'", s),
             DynasmError::ImpossibleRelocation(s) => write!(f, "Impossible relocation: '{}'", s),
+            DynasmError::OutOfMemory => write!(f, "Out of memory to place assembled code in"),
+            DynasmError::UnbalancedScope => write!(f, "A label scope was left that was never entered"),
+            DynasmError::RollbackPastCommit => write!(f, "Cannot roll back to a checkpoint before committed code"),
+            DynasmError::UnsupportedDirective(d) => write!(f, "Directive not supported by this assembler: '{}'", d),
         }
     }
 }
@@ -161,7 +262,12 @@ impl error::Error for DynasmError {
             DynasmError::CheckFailed => "An assembly modification offset check failed",
             DynasmError::DuplicateLabel(_) => "Duplicate label defined",
             DynasmError::UnknownLabel(_) => "Unknown label",
+            DynasmError::UnknownSymbol(_) => "Unknown symbol",
             DynasmError::ImpossibleRelocation(_) => "Impossible relocation",
+            DynasmError::OutOfMemory => "Out of memory",
+            DynasmError::UnbalancedScope => "Unbalanced label scope",
+            DynasmError::RollbackPastCommit => "Rollback past a commit",
+            DynasmError::UnsupportedDirective(_) => "Unsupported directive",
         }
     }
 }
@@ -230,6 +336,12 @@ pub trait DynasmLabelApi : DynasmApi {
     fn global_label( &mut self, name: &'static str);
     /// Record the definition of a dynamic label
     fn dynamic_label(&mut self, id: DynamicLabel);
+    /// Record the definition of a global label whose name is only known at runtime. The name is interned with
+    /// `components::intern`, so it is never freed, after which the label behaves exactly like a global label named
+    /// by a string literal.
+    fn named_global_label(&mut self, name: &str) {
+        self.global_label(intern(name))
+    }
 
     /// Record a relocation spot for a forward reference to a local label
     fn forward_reloc( &mut self, name: &'static str, offset: isize, kind: <Self::Relocation as Relocation>::Encoding) {
@@ -243,14 +355,27 @@ pub trait DynasmLabelApi : DynasmApi {
     fn global_reloc(  &mut self, name: &'static str, offset: isize, kind: <Self::Relocation as Relocation>::Encoding) {
         self.global_relocation(name, offset, Self::Relocation::from_encoding(kind))
     }
+    /// Record a relocation spot for a reference to a global label whose name is only known at runtime.
+    /// Like with `named_global_label`, the name is interned and never freed.
+    fn named_global_reloc(&mut self, name: &str, offset: isize, kind: <Self::Relocation as Relocation>::Encoding) {
+        self.named_global_relocation(name, offset, Self::Relocation::from_encoding(kind))
+    }
     /// Record a relocation spot for a reference to a dynamic label
     fn dynamic_reloc( &mut self, id: DynamicLabel,   offset: isize, kind: <Self::Relocation as Relocation>::Encoding) {
         self.dynamic_relocation(id, offset, Self::Relocation::from_encoding(kind))
     }
-    /// Record a relocation spot to an arbitrary target.
+    /// Record a relocation spot to an arbitrary target. The target is always an absolute address: relative relocations
+    /// (`RelocationKind::Relative`) are encoded as the distance from the relocation to `target`, rather than as
+    /// `target` itself. Assemblers route branches that cannot reach such a target through a veneer, if the relocation
+    /// supports it.
     fn bare_reloc(&mut self, target: usize, kind: <Self::Relocation as Relocation>::Encoding) {
         self.bare_relocation(target, Self::Relocation::from_encoding(kind))
     }
+    /// Record a relocation spot to the address of the symbol `name`, which is resolved by the assembler's
+    /// `SymbolResolver` when the code is committed. Otherwise this behaves like `bare_reloc`.
+    fn symbol_reloc(&mut self, name: &'static str, kind: <Self::Relocation as Relocation>::Encoding) {
+        self.symbol_relocation(name, Self::Relocation::from_encoding(kind))
+    }
 
     /// Equivalent of forward_reloc, but takes a non-encoded relocation
     fn forward_relocation( &mut self, name: &'static str, offset: isize, kind: Self::Relocation);
@@ -258,10 +383,49 @@ pub trait DynasmLabelApi : DynasmApi {
     fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: Self::Relocation);
     /// Equivalent of global_reloc, but takes a non-encoded relocation
     fn global_relocation(  &mut self, name: &'static str, offset: isize, kind: Self::Relocation);
+    /// Equivalent of named_global_reloc, but takes a non-encoded relocation
+    fn named_global_relocation(&mut self, name: &str, offset: isize, kind: Self::Relocation) {
+        self.global_relocation(intern(name), offset, kind)
+    }
     /// Equivalent of dynamic_reloc, but takes a non-encoded relocation
     fn dynamic_relocation( &mut self, id: DynamicLabel,   offset: isize, kind: Self::Relocation);
     /// Equivalent of bare_reloc, but takes a non-encoded relocation
     fn bare_relocation(&mut self, target: usize, kind: Self::Relocation);
+    /// Equivalent of symbol_reloc, but takes a non-encoded relocation. Assemblers without a `SymbolResolver` know no
+    /// symbols, so by default this records `DynasmError::UnknownSymbol`.
+    fn symbol_relocation(&mut self, name: &'static str, _kind: Self::Relocation) {
+        self.record_error(DynasmError::UnknownSymbol(name))
+    }
+
+    /// Enter a new scope for local labels. Until the scope is left with `pop_label_scope`, local labels can only be
+    /// referenced if they are defined in this scope, and local labels defined in it cannot be referenced after it.
+    /// By default, this records `DynasmError::UnsupportedDirective`.
+    fn push_label_scope(&mut self) {
+        self.record_error(DynasmError::UnsupportedDirective(".scope"))
+    }
+    /// Leave the scope for local labels that was entered last. Forward references to local labels that were not
+    /// defined in the scope are reported as an error. By default, this records `DynasmError::UnsupportedDirective`.
+    fn pop_label_scope(&mut self) {
+        self.record_error(DynasmError::UnsupportedDirective(".endscope"))
+    }
+
+    /// Mark the relocation that was recorded last as belonging to a jump that may be replaced by a shorter encoding
+    /// if its target is close enough. Assemblers that do not implement branch relaxation can ignore this.
+    fn relaxable_reloc(&mut self) {}
+
+    /// Record an error in the code assembled so far, to be reported when it is committed or finalized. This is used
+    /// by directives the assembler does not support, which is what the default implementations of the other methods
+    /// of this trait do.
+    fn record_error(&mut self, error: DynasmError);
+
+    /// Continue assembling code into `section`. Assemblers that do not support multiple sections can place all code
+    /// in order, which is what this does by default. As data cannot be placed between code, switching to
+    /// `Section::Rodata` or `Section::Data` records `DynasmError::UnsupportedDirective`.
+    fn switch_section(&mut self, section: Section) {
+        if !matches!(section, Section::Hot | Section::Cold) {
+            self.record_error(DynasmError::UnsupportedDirective(".section"));
+        }
+    }
 }
 
 
@@ -330,7 +494,21 @@ pub struct VecAssembler<R: Relocation> {
     baseaddr: usize,
     labels: LabelRegistry,
     relocs: RelocRegistry<R>,
+    // resolved relocations that depend on the base address, and their targets
+    resolved: Vec<(PatchLoc<R>, usize)>,
+    // relocations waiting for branch relaxation, if enabled
+    relax: Option<RelaxRegistry<R>>,
+    // branches to absolute addresses
+    veneers: VeneerRegistry<R>,
+    symbols: SymbolRegistry,
     error: Option<DynasmError>,
+    // the length of the code when it was last committed
+    committed: usize,
+    // the section code is assembled into, and the uncommitted contents of the other sections
+    section: Section,
+    cold: SectionBuffer,
+    rodata: SectionBuffer,
+    data: SectionBuffer,
 }
 
 impl<R: Relocation> VecAssembler<R> {
@@ -341,13 +519,142 @@ impl<R: Relocation> VecAssembler<R> {
             baseaddr,
             labels: LabelRegistry::new(),
             relocs: RelocRegistry::new(),
-            error: None
+            resolved: Vec::new(),
+            relax: None,
+            veneers: VeneerRegistry::new(),
+            symbols: SymbolRegistry::new(),
+            error: None,
+            committed: 0,
+            section: Section::Hot,
+            cold: SectionBuffer::provisional(Section::Cold),
+            rodata: SectionBuffer::provisional(Section::Rodata),
+            data: SectionBuffer::provisional(Section::Data)
         }
     }
 
+    /// Use `resolver` to resolve the symbols targeted by `extern "name"` when the code is committed.
+    pub fn set_symbol_resolver<S: SymbolResolver + Send + 'static>(&mut self, resolver: S) {
+        self.symbols.set_resolver(resolver);
+    }
+
+    /// Enable branch relaxation for the code assembled from now on. The jumps that the `dynasm!` macro marks as
+    /// relaxable are assembled with their longest encoding, and their relocations are only encoded when the code is
+    /// committed. At that point, each of these jumps is replaced by the shortest encoding that reaches its target,
+    /// which moves the code after it backwards. Labels, alignment padding and the relocations kept for
+    /// `finalize_module` move along with the code, but offsets obtained earlier through `offset()` do not, so code
+    /// after a relaxable jump should be referred to with labels.
+    pub fn enable_relaxation(&mut self) {
+        self.relax.get_or_insert_with(RelaxRegistry::new).enable();
+    }
+
+    /// Create a new dynamic label ID
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        self.labels.new_dynamic_label()
+    }
+
     /// Resolves any relocations emitted to the assembler before this point.
     /// If an impossible relocation was specified before this point, returns them here.
     pub fn commit(&mut self) -> Result<(), DynasmError> {
+        let result = self.resolve().map(|_| ());
+        self.labels.forget_history();
+        self.committed = self.ops.len();
+        result
+    }
+
+    /// Create a checkpoint of the current state of this assembler, which it can be rolled back to with `rollback`.
+    pub fn checkpoint(&self) -> Checkpoint<R> where R: Clone {
+        Checkpoint {
+            offset: AssemblyOffset(self.ops.len()),
+            sections: [self.cold.checkpoint(), self.rodata.checkpoint(), self.data.checkpoint()],
+            section: self.section,
+            committed: self.committed,
+            labels: self.labels.checkpoint(),
+            relocs: self.relocs.clone(),
+            relax: self.relax.clone(),
+            error: self.error.clone(),
+            resolved: self.resolved.len(),
+        }
+    }
+
+    /// Roll this assembler back to `checkpoint`. The code assembled since is discarded, together with the labels
+    /// defined, the relocations emitted and any errors that happened in it. Dynamic label IDs created since stay valid,
+    /// but are undefined. Fails if code was committed since the checkpoint was created.
+    pub fn rollback(&mut self, checkpoint: &Checkpoint<R>) -> Result<(), DynasmError> where R: Clone {
+        if self.committed != checkpoint.committed {
+            return Err(DynasmError::RollbackPastCommit);
+        }
+
+        self.ops.truncate(checkpoint.offset.0);
+        self.cold.rollback(&checkpoint.sections[0]);
+        self.rodata.rollback(&checkpoint.sections[1]);
+        self.data.rollback(&checkpoint.sections[2]);
+        self.section = checkpoint.section;
+        self.labels.rollback(&checkpoint.labels);
+        self.relocs = checkpoint.relocs.clone();
+        self.relax = checkpoint.relax.clone();
+        self.error = checkpoint.error.clone();
+        self.resolved.truncate(checkpoint.resolved);
+        self.veneers.discard(|offset| checkpoint.discards(offset));
+        Ok(())
+    }
+
+    // the buffer of `section`, unless it is the hot section
+    fn section_buffer(&mut self, section: Section) -> Option<&mut SectionBuffer> {
+        match section {
+            Section::Hot => None,
+            Section::Cold => Some(&mut self.cold),
+            Section::Rodata => Some(&mut self.rodata),
+            Section::Data => Some(&mut self.data)
+        }
+    }
+
+    // place the uncommitted contents of the cold, read-only data and data sections after the hot code in that order,
+    // and move everything referring to them along
+    fn place_sections(&mut self) {
+        self.place_section(Section::Cold, None);
+        self.place_section(Section::Rodata, Some(0));
+        self.place_section(Section::Data, Some(0));
+    }
+
+    // place the uncommitted contents of `section` after the code, padded with `with` (or no-op instructions) so they
+    // keep their alignment
+    fn place_section(&mut self, section: Section, with: Option<u8>) {
+        let start = self.ops.len();
+        let current = self.section == section;
+        let buffer = self.section_buffer(section).unwrap();
+        if !buffer.used {
+            return;
+        }
+
+        let (mut contents, alignment) = buffer.take();
+        let padding = (alignment - start % alignment) % alignment;
+        let f = buffer.placement(start + padding);
+        buffer.used = current;
+        pad::<R>(&mut self.ops, padding, with);
+        self.ops.append(&mut contents);
+
+        self.labels.relocate(f);
+        self.relocs.relocate(f);
+        self.veneers.relocate(f);
+        if let Some(relax) = &mut self.relax {
+            relax.relocate(f);
+            relax.add_align(AssemblyOffset(start), padding, alignment, with);
+        }
+    }
+
+    // resolve all relocations, returning how the code was moved if branch relaxation changed anything
+    fn resolve(&mut self) -> Result<Option<CodeLayout>, DynasmError> {
+        self.place_sections();
+
+        // Resolve symbols, after which they are handled like any other relocation to an address. If any symbol is
+        // unknown, they are all kept so the code can be committed again once it is known.
+        let symbols = &self.symbols;
+        let targets = self.relocs.symbols().map(|name| symbols.resolve(name)).collect::<Result<Vec<_>, _>>()?;
+        let resolved: Vec<_> = self.relocs.take_symbols().zip(targets).collect();
+        for ((loc, _), target) in resolved {
+            self.extern_relocation(loc, target);
+        }
+
         // If we accrued any errors while assembling before, emit them now.
         if let Some(e) = self.error.take() {
             return Err(e);
@@ -356,31 +663,95 @@ impl<R: Relocation> VecAssembler<R> {
         // Resolve globals
         for (loc, name) in self.relocs.take_globals() {
             let target = self.labels.resolve_global(name)?;
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, target.0, TargetKind::Global(name));
+                continue;
+            }
             if loc.patch(0, self.baseaddr, &mut self.ops, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
             }
+            if loc.needs_adjustment() {
+                self.resolved.push((loc, target.0));
+            }
         }
 
         // Resolve dynamics
         for (loc, id) in self.relocs.take_dynamics() {
             let target = self.labels.resolve_dynamic(id)?;
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, target.0, TargetKind::Dynamic(id));
+                continue;
+            }
             if loc.patch(0, self.baseaddr, &mut self.ops, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
             }
+            if loc.needs_adjustment() {
+                self.resolved.push((loc, target.0));
+            }
         }
 
         // Check that there are no unknown local labels
-        for (_, name) in self.relocs.take_locals() {
+        if let Some((_, name)) = self.relocs.take_locals().next() {
             return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
         }
 
-        Ok(())
+        // Perform branch relaxation, and encode the relocations that were waiting for it
+        let mut layout = None;
+        if let Some(relax) = &mut self.relax {
+            layout = relax.relax(0, &mut self.ops);
+            if let Some(layout) = &layout {
+                self.labels.relocate(|offset| layout.map(offset));
+                self.veneers.relocate(|offset| layout.map(offset));
+                for (loc, target) in &mut self.resolved {
+                    *target = layout.map_target(loc, *target);
+                    loc.location = layout.map(loc.location);
+                }
+            }
+
+            for (loc, target, kind) in relax.take() {
+                if loc.patch(0, self.baseaddr, &mut self.ops, target).is_err() {
+                    return Err(DynasmError::ImpossibleRelocation(kind));
+                }
+                if loc.needs_adjustment() {
+                    self.resolved.push((loc, target));
+                }
+            }
+        }
+
+        // stop deferring relocations if that was only done for the other sections, which have been placed now
+        if self.section == Section::Hot && self.relax.as_ref().is_some_and(|relax| !relax.is_relaxing()) {
+            self.relax = None;
+        }
+
+        // Add veneers for new targets of branches to absolute addresses, and encode these branches
+        self.veneers.emit(0, None, &mut self.ops);
+        self.veneers.patch(0, Some(self.baseaddr), &mut self.ops)?;
+
+        Ok(layout)
+    }
+
+    // record a relocation to the absolute address `target`
+    fn extern_relocation(&mut self, loc: PatchLoc<R>, target: usize) {
+        if loc.relocation.kind() == RelocationKind::Relative {
+            self.veneers.add(loc, target);
+        } else if let Some(relax) = &mut self.relax {
+            relax.add(loc, target, TargetKind::Extern(target));
+        } else if loc.patch(0, self.baseaddr, &mut self.ops, target).is_err() {
+            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)))
+        } else if loc.needs_adjustment() {
+            self.resolved.push((loc, target))
+        }
     }
 
     /// Use an `UncommittedModifier` to alter uncommitted code.
     /// This does not allow the user to change labels/relocations.
     pub fn alter(&mut self) -> UncommittedModifier {
-        UncommittedModifier::new(&mut self.ops, AssemblyOffset(0))
+        match self.section {
+            Section::Hot => UncommittedModifier::new(&mut self.ops, AssemblyOffset(0)),
+            Section::Cold => self.cold.modifier(),
+            Section::Rodata => self.rodata.modifier(),
+            Section::Data => self.data.modifier()
+        }
     }
 
     /// Finalizes the `VecAssembler`, returning the resulting `Vec<u8>` containing all assembled data.
@@ -389,34 +760,222 @@ impl<R: Relocation> VecAssembler<R> {
         self.commit()?;
         Ok(self.ops)
     }
+
+    /// Finalizes the `VecAssembler` into a relocatable ELF64 object file, which can be linked with the system linker.
+    /// All assembled data is placed in a `.text` section. Every global label defined in this assembler becomes a
+    /// global symbol, and references to global labels that were not defined become references to undefined
+    /// symbols, which have to be provided by another object when linking. Absolute references to the assembled code
+    /// become relocations against the `.text` section, and all other relocations are resolved immediately.
+    /// A reference to an undefined symbol or a relocation depending on the address of the code that cannot be
+    /// expressed as an ELF relocation results in an error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the target architecture does not support producing ELF64 objects.
+    pub fn finalize_elf(mut self) -> Result<Vec<u8>, DynasmError> {
+        let machine = R::elf_machine().expect("Relocatable objects are not supported for this architecture");
+        self.place_sections();
+
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // Resolve globals, keeping references to undefined labels around as relocations
+        let mut external = Vec::new();
+        for (loc, name) in self.relocs.take_globals() {
+            match self.labels.resolve_global(name) {
+                Ok(target) => if let Some(relax) = &mut self.relax {
+                    relax.add(loc, target.0, TargetKind::Global(name));
+                } else if loc.patch(0, self.baseaddr, &mut self.ops, target.0).is_err() {
+                    return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
+                } else if loc.needs_adjustment() {
+                    self.resolved.push((loc, target.0));
+                },
+                Err(_) => match (loc.relocation.kind(), loc.relocation.elf_relocation_type()) {
+                    (RelocationKind::Relative, Some(kind))
+                    | (RelocationKind::AbsToRel, Some(kind)) => external.push((loc, name, kind)),
+                    _ => return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)))
+                }
+            }
+        }
+
+        if let Some(layout) = self.resolve()? {
+            for (loc, _, _) in &mut external {
+                loc.location = layout.map(loc.location);
+            }
+        }
+
+        // the object can be linked at any address, so branches to absolute addresses have to use their veneers
+        self.veneers.patch(0, None, &mut self.ops)?;
+
+        // and relocations that depend on the address of the code have to be applied by the linker
+        let mut local = Vec::new();
+        for (loc, target) in self.resolved.drain(..) {
+            match (loc.relocation.kind(), loc.relocation.elf_relocation_type()) {
+                (RelocationKind::AbsToRel, Some(kind)) => local.push((loc, target, kind)),
+                _ => return Err(DynasmError::ImpossibleRelocation(TargetKind::Managed))
+            }
+        }
+
+        let mut writer = elf::ElfWriter::new(elf::ET_REL, machine);
+
+        let mut text = elf::Section::new(".text", elf::SHT_PROGBITS, self.ops);
+        text.flags = elf::SHF_ALLOC | elf::SHF_EXECINSTR;
+        text.align = 16;
+        let text_index = writer.add_section(text);
+
+        // the symbol table
+        let mut symbols = elf::SymbolTable::new();
+        let text_symbol = symbols.add("", elf::STB_LOCAL, elf::STT_SECTION, text_index, 0, 0);
+        let mut globals: Vec<_> = self.labels.globals().collect();
+        globals.sort_by_key(|&(name, offset)| (offset, name));
+        for (name, offset) in globals {
+            symbols.add(name, elf::STB_GLOBAL, elf::STT_FUNC, text_index, offset.0 as u64, 0);
+        }
+
+        let mut undefined = HashMap::new();
+        let mut rela = Vec::new();
+        for (loc, name, kind) in external {
+            let symbol = *undefined.entry(name).or_insert_with(|| {
+                symbols.add(name, elf::STB_GLOBAL, elf::STT_NOTYPE, elf::SHN_UNDEF, 0, 0)
+            });
+
+            // relative relocations are computed as S + A - P, where P is the start of the relocated field,
+            // and absolute ones as S + A
+            let field = loc.location.0 - loc.relocation.field_offset();
+            let addend = match loc.relocation.kind() {
+                RelocationKind::Relative => loc.relocation.start_offset() as i64 - loc.relocation.field_offset() as i64,
+                _ => 0
+            } + loc.offset as i64;
+            elf::write_rela(&mut rela, field as u64, symbol, kind, addend);
+        }
+        for (loc, target, kind) in local {
+            let field = loc.location.0 - loc.relocation.field_offset();
+            elf::write_rela(&mut rela, field as u64, text_symbol, kind, target as i64 + loc.offset as i64);
+        }
+
+        let symtab_index = writer.next_index() + 2;
+        let mut rela = elf::Section::new(".rela.text", elf::SHT_RELA, rela);
+        rela.flags = elf::SHF_INFO_LINK;
+        rela.link = u32::from(symtab_index);
+        rela.info = u32::from(text_index);
+        rela.align = 8;
+        rela.entsize = elf::RELA_SIZE as u64;
+        writer.add_section(rela);
+
+        // mark the object as not requiring an executable stack
+        writer.add_section(elf::Section::new(".note.GNU-stack", elf::SHT_PROGBITS, Vec::new()));
+
+        let (symtab, strtab) = symbols.into_sections(symtab_index + 1);
+        writer.add_section(symtab);
+        writer.add_section(strtab);
+
+        Ok(writer.finish())
+    }
+
+    /// Finalizes the `VecAssembler` into a `CodeModule`, which can be serialized and later be placed into an
+    /// `Assembler` at any address using `Assembler::load_module`. References to global labels that were not defined
+    /// in this assembler are kept in the module, and are resolved against the labels of the `Assembler` it is loaded into.
+    /// All other relocations are resolved immediately.
+    pub fn finalize_module(mut self) -> Result<CodeModule<R>, DynasmError> {
+        self.place_sections();
+
+        // If we accrued any errors while assembling before, emit them now.
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        // Resolve globals, keeping references to undefined labels around
+        let mut externs = Vec::new();
+        for (loc, name) in self.relocs.take_globals() {
+            match self.labels.resolve_global(name) {
+                Ok(target) => {
+                    if let Some(relax) = &mut self.relax {
+                        relax.add(loc, target.0, TargetKind::Global(name));
+                        continue;
+                    }
+                    if loc.patch(0, self.baseaddr, &mut self.ops, target.0).is_err() {
+                        return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
+                    }
+                    if loc.needs_adjustment() {
+                        self.resolved.push((loc, target.0));
+                    }
+                },
+                Err(_) => externs.push((loc, name.to_string()))
+            }
+        }
+
+        if let Some(layout) = self.resolve()? {
+            for (loc, _) in &mut externs {
+                loc.location = layout.map(loc.location);
+            }
+        }
+
+        // the module can be loaded at any address, so branches to absolute addresses have to use their veneers
+        self.veneers.patch(0, None, &mut self.ops)?;
+
+        let mut globals: Vec<_> = self.labels.globals().map(|(name, offset)| (name.to_string(), offset.0)).collect();
+        globals.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+        Ok(CodeModule {
+            code: self.ops,
+            globals,
+            dynamics: self.labels.dynamics().map(|(_, offset)| offset.map(|o| o.0)).collect(),
+            relocations: self.resolved,
+            externs
+        })
+    }
 }
 
 impl<R: Relocation> Extend<u8> for VecAssembler<R> {
     fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
-        self.ops.extend(iter)
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.extend(iter),
+            None => self.ops.extend(iter)
+        }
     }
 }
 
 impl<'a, R: Relocation> Extend<&'a u8> for VecAssembler<R> {
     fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
-        self.ops.extend(iter)
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.extend(iter),
+            None => self.ops.extend(iter)
+        }
     }
 }
 
 impl<R: Relocation> DynasmApi for VecAssembler<R> {
     fn offset(&self) -> AssemblyOffset {
-        AssemblyOffset(self.ops.len())
+        match self.section {
+            Section::Hot => AssemblyOffset(self.ops.len()),
+            Section::Cold => self.cold.offset(),
+            Section::Rodata => self.rodata.offset(),
+            Section::Data => self.data.offset()
+        }
     }
     fn push(&mut self, byte: u8) {
-        self.ops.push(byte);
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.push(byte),
+            None => self.ops.push(byte)
+        }
     }
     fn align(&mut self, alignment: usize, with: u8) {
-        let offset = self.offset().0 % alignment;
+        let start = self.offset();
+        let offset = start.0 % alignment;
         if offset != 0 {
             for _ in offset .. alignment {
                 self.push(with);
             }
         }
+        if let Some(buffer) = self.section_buffer(self.section) {
+            buffer.align = buffer.align.max(alignment);
+        }
+        let padding = self.offset().0 - start.0;
+        if let Some(relax) = &mut self.relax {
+            relax.add_align(start, padding, alignment, Some(with));
+        }
     }
 }
 
@@ -426,8 +985,12 @@ impl<R: Relocation> DynasmLabelApi for VecAssembler<R> {
     fn local_label(&mut self, name: &'static str) {
         let offset = self.offset();
         for loc in self.relocs.take_locals_named(name) {
-            if loc.patch(0, self.baseaddr, &mut self.ops, offset.0).is_err() {
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, offset.0, TargetKind::Forward(name));
+            } else if loc.patch(0, self.baseaddr, &mut self.ops, offset.0).is_err() {
                 self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)))
+            } else if loc.needs_adjustment() {
+                self.resolved.push((loc, offset.0))
             }
         }
         self.labels.define_local(name, offset);
@@ -466,17 +1029,50 @@ impl<R: Relocation> DynasmLabelApi for VecAssembler<R> {
         };
         let location = self.offset();
         let loc = PatchLoc::new(location, offset, kind);
-        if loc.patch(0, self.baseaddr, &mut self.ops, target).is_err() {
+        if let Some(relax) = &mut self.relax {
+            relax.add(loc, target, TargetKind::Backward(name));
+        } else if loc.patch(0, self.baseaddr, &mut self.ops, target).is_err() {
             self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)))
+        } else if loc.needs_adjustment() {
+            self.resolved.push((loc, target))
         }
     }
     fn bare_relocation(&mut self, target: usize, kind: R) {
         let location = self.offset();
-        let loc = PatchLoc::new(location, 0, kind);
-        if loc.patch(0, self.baseaddr, &mut self.ops, target).is_err() {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)))
+        self.extern_relocation(PatchLoc::new(location, 0, kind), target);
+    }
+    fn symbol_relocation(&mut self, name: &'static str, kind: R) {
+        let location = self.offset();
+        self.relocs.add_symbol(name, PatchLoc::new(location, 0, kind));
+    }
+    fn push_label_scope(&mut self) {
+        self.labels.push_label_scope(&mut self.relocs);
+    }
+    fn pop_label_scope(&mut self) {
+        if let Err(e) = self.labels.pop_label_scope(&mut self.relocs) {
+            self.error = Some(e)
+        }
+    }
+    fn relaxable_reloc(&mut self) {
+        let location = self.offset();
+        if let Some(relax) = &mut self.relax {
+            relax.mark_relaxable(location);
         }
     }
+    fn record_error(&mut self, error: DynasmError) {
+        self.error = Some(error)
+    }
+    fn switch_section(&mut self, section: Section) {
+        // the contents of other sections only get their final offsets when they are committed, so relocations have
+        // to wait for that
+        if let Some(buffer) = self.section_buffer(section) {
+            buffer.used = true;
+            if self.relax.is_none() {
+                self.relax = Some(RelaxRegistry::deferring());
+            }
+        }
+        self.section = section;
+    }
 }
 
 
@@ -491,19 +1087,120 @@ pub struct Assembler<R: Relocation> {
     relocs: RelocRegistry<R>,
     managed: ManagedRelocs<R>,
     error: Option<DynasmError>,
+    perf: Option<PerfRecorder>,
+    unwind: UnwindTable,
+    relax: Option<RelaxRegistry<R>>,
+    veneers: VeneerRegistry<R>,
+    symbols: SymbolRegistry,
+    imports: ImportRegistry<R>,
+    exports: Option<LabelExports>,
+    retargets: RetargetRegistry<R>,
+    patchpoints: PatchpointRegistry<R>,
+    // the relocations emitted in the patchpoint that is being assembled if any, and how to copy them
+    capture: Option<(Vec<PatchLoc<R>>, CopyPatchLoc<R>)>,
+    // the section code is assembled into, and the uncommitted contents of the other sections
+    section: Section,
+    cold: SectionBuffer,
+    rodata: SectionBuffer,
+    data: SectionBuffer,
 }
 
 impl<R: Relocation> Assembler<R> {
     /// Create a new, empty assembler, with initial allocation size `page_size`.
     pub fn new() -> io::Result<Self> {
-        Ok(Self {
+        Self::with_strategy(MappingStrategy::ProtectionSwapping)
+    }
+
+    /// Create a new, empty assembler like `new`, except that it uses `strategy` to make committed code executable
+    /// instead of swapping the protection of its executable memory. See `MappingStrategy` for the trade-offs.
+    pub fn with_strategy(strategy: MappingStrategy) -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::with_strategy(R::page_size(), strategy)?))
+    }
+
+    /// Create a new, empty assembler that reserves `reserved_size` bytes of executable memory up front
+    /// (rounded up to `page_size`), and uses `strategy` to write into it. Committed code is never moved by this
+    /// assembler, so pointers into its executable buffer stay valid for as long as the buffer lives, even
+    /// when no `Executor` lock is held. Committing more code than fits in the reservation fails with
+    /// `DynasmError::OutOfMemory`.
+    pub fn with_reservation(reserved_size: usize, strategy: MappingStrategy) -> io::Result<Self> {
+        let page_size = R::page_size();
+        let reserved_size = match reserved_size % page_size {
+            0 => reserved_size,
+            misalign => reserved_size + page_size - misalign
+        };
+
+        Ok(Self::with_memory(MemoryManager::with_reservation(reserved_size, strategy)?))
+    }
+
+    /// Create a new, empty assembler like `with_reservation` that reserves `code_size` bytes of executable memory,
+    /// followed by a read-only data section of `rodata_size` bytes and a writable data section of `data_size` bytes,
+    /// all rounded up to `page_size`. These are filled by assembling into `Section::Rodata` and `Section::Data`, as after
+    /// the `.section rodata` and `.section data` directives. Neither data section is executable, and as both stay at
+    /// a fixed distance from the code, code can refer to their contents with relative addressing. Committing more
+    /// data than fits in a data section fails with `DynasmError::OutOfMemory`. `MappingStrategy::LockFree` is not
+    /// supported.
+    pub fn with_data_sections(code_size: usize, rodata_size: usize, data_size: usize, strategy: MappingStrategy) -> io::Result<Self> {
+        let page_size = R::page_size();
+        let round = |size: usize| match size % page_size {
+            0 => size,
+            misalign => size + page_size - misalign
+        };
+        let (code_size, rodata_size, data_size) = (round(code_size), round(rodata_size), round(data_size));
+
+        let mut assembler = Self::with_memory(MemoryManager::with_data_sections(code_size, rodata_size, data_size, strategy)?);
+        assembler.rodata = SectionBuffer::new(code_size, code_size + rodata_size);
+        assembler.data = SectionBuffer::new(code_size + rodata_size, code_size + rodata_size + data_size);
+        Ok(assembler)
+    }
+
+    /// Create a new, empty assembler that uses `strategy` to write into its executable memory, which is placed
+    /// according to `hint` if possible. This allows committed code to reach the hinted address with relative
+    /// branches, like `call extern` to a function in this program without going through a veneer. Use
+    /// `is_placed` to check if this succeeded.
+    pub fn with_placement(hint: PlacementHint, strategy: MappingStrategy) -> io::Result<Self> {
+        Ok(Self::with_memory(MemoryManager::with_placement(R::page_size(), strategy, hint)?))
+    }
+
+    fn with_memory(memory: MemoryManager) -> Self {
+        // relocations never have to be adjusted in code that is never moved
+        let managed = if memory.is_fixed() {
+            ManagedRelocs::disabled()
+        } else {
+            ManagedRelocs::new()
+        };
+
+        Self {
             ops: Vec::new(),
-            memory: MemoryManager::new(R::page_size())?,
+            memory,
             labels: LabelRegistry::new(),
             relocs: RelocRegistry::new(),
-            managed: ManagedRelocs::new(),
-            error: None
-        })
+            managed,
+            error: None,
+            perf: None,
+            unwind: UnwindTable::default(),
+            relax: None,
+            veneers: VeneerRegistry::new(),
+            symbols: SymbolRegistry::new(),
+            imports: ImportRegistry::new(),
+            exports: None,
+            retargets: RetargetRegistry::new(),
+            patchpoints: PatchpointRegistry::new(),
+            capture: None,
+            section: Section::Hot,
+            cold: SectionBuffer::provisional(Section::Cold),
+            rodata: SectionBuffer::new(0, 0),
+            data: SectionBuffer::new(0, 0)
+        }
+    }
+
+    /// Enable branch relaxation for the code assembled from now on. The jumps that the `dynasm!` macro marks as
+    /// relaxable are assembled with their longest encoding, and their relocations are only encoded when the code is
+    /// committed. At that point, each of these jumps is replaced by the shortest encoding that reaches its target,
+    /// which moves the uncommitted code after it backwards. Labels, alignment padding, patchpoints, perf symbols and
+    /// the unwind information added with `add_unwind_info` move along with the code, but offsets obtained earlier
+    /// through `offset()` do not, so code after a relaxable jump should be referred to with labels.
+    pub fn enable_relaxation(&mut self) {
+        self.relax.get_or_insert_with(RelaxRegistry::new).enable();
     }
 
     /// Create a new dynamic label ID
@@ -511,70 +1208,359 @@ impl<R: Relocation> Assembler<R> {
         self.labels.new_dynamic_label()
     }
 
+    /// Create a new retargetable dynamic label ID. Unlike other labels, a retargetable label can be defined again
+    /// within `alter`, after which all committed references to it are patched to point to its new definition.
+    /// References from other assemblers that imported the label are not updated.
+    pub fn new_retargetable_label(&mut self) -> DynamicLabel where R: Clone {
+        let id = self.labels.new_dynamic_label();
+        self.retargets.add_label(id);
+        id
+    }
+
+    /// Assemble a patchpoint: the code emitted by `f`, which has to contain a single relocation like `call =>label`,
+    /// placed in a block of `size` bytes that can be overwritten with a single atomic store. The block is aligned to
+    /// `size` rounded up to a power of two, and padded to that size with no-op instructions. Once committed, the
+    /// patchpoint can be retargeted with `retarget_patchpoint` while other threads execute it. Code emitted by `f` is
+    /// never relaxed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is larger than 8, if `f` emits more than `size` bytes, or if `f` does not emit exactly one
+    /// relocation.
+    pub fn patchpoint<F>(&mut self, size: usize, f: F) -> Patchpoint where F: FnOnce(&mut Self), R: Clone {
+        assert!(size <= 8, "Patchpoints can be at most 8 bytes large");
+        let size = size.next_power_of_two();
+        self.pad_to(size, None);
+
+        let start = self.offset();
+        self.capture = Some((Vec::new(), PatchLoc::clone));
+        f(self);
+        let (mut relocations, _) = self.capture.take().unwrap();
+
+        assert!(self.offset().0 - start.0 <= size, "Patchpoint code is larger than {} bytes", size);
+        assert!(relocations.len() == 1, "Patchpoint code has to contain a single relocation, not {}", relocations.len());
+        self.pad_to(size, None);
+
+        self.patchpoints.add(relocations.pop().unwrap(), start, size)
+    }
+
+    /// Retarget `patchpoint` to the dynamic label `id` with a single atomic store, committing any uncommitted code
+    /// first. Unlike `alter`, this does not block executors if the `DualMapped` or `LockFree` strategy is used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `patchpoint` was discarded by a rollback.
+    pub fn retarget_patchpoint(&mut self, patchpoint: Patchpoint, id: DynamicLabel) -> Result<(), DynasmError> where R: Clone {
+        self.commit()?;
+        let target = self.labels.resolve_dynamic(id)?;
+        self.write_patchpoint(patchpoint, target.0, TargetKind::Dynamic(id))
+    }
+
+    /// Retarget `patchpoint` to the absolute address `target` with a single atomic store, like `retarget_patchpoint`.
+    /// No veneer can be created for this, so the patchpoint has to be able to reach `target` directly.
+    pub fn retarget_patchpoint_extern(&mut self, patchpoint: Patchpoint, target: usize) -> Result<(), DynasmError> where R: Clone {
+        self.commit()?;
+        let buf_addr = self.memory.execbuffer_addr();
+        self.write_patchpoint(patchpoint, target.wrapping_sub(buf_addr), TargetKind::Extern(target))?;
+
+        // keep the patchpoint pointing at target when the buffer moves
+        let (loc, _, _) = self.patchpoints.get(patchpoint);
+        self.veneers.add_resolved(loc.clone(), target);
+        Ok(())
+    }
+
+    // encode the relocation of a committed patchpoint to point to the offset `target`, and store its block atomically
+    fn write_patchpoint(&mut self, patchpoint: Patchpoint, target: usize, kind: TargetKind) -> Result<(), DynasmError> where R: Clone {
+        let (loc, start, size) = self.patchpoints.get(patchpoint);
+        let buf_addr = self.memory.execbuffer_addr();
+        let mut block = self.memory.read(|buffer| buffer[start .. start + size].to_vec());
+        if loc.patch(start, buf_addr, &mut block, target).is_err() {
+            return Err(DynasmError::ImpossibleRelocation(kind));
+        }
+
+        // forget the previous target of the patchpoint
+        let field = loc.location.0 - loc.relocation.field_offset();
+        self.veneers.remove_between(field, field + 1);
+        self.imports.remove_between(field, field + 1);
+        self.retargets.remove_between(field, field + 1);
+        if loc.needs_adjustment() {
+            self.managed.add(loc.clone());
+        }
+
+        self.memory.write_atomic(start, &block);
+        Ok(())
+    }
+
+    /// Create a checkpoint of the current state of this assembler, which it can be rolled back to with `rollback`.
+    pub fn checkpoint(&self) -> Checkpoint<R> where R: Clone {
+        Checkpoint {
+            offset: AssemblyOffset(self.memory.committed() + self.ops.len()),
+            sections: [self.cold.checkpoint(), self.rodata.checkpoint(), self.data.checkpoint()],
+            section: self.section,
+            committed: self.memory.committed(),
+            labels: self.labels.checkpoint(),
+            relocs: self.relocs.clone(),
+            relax: self.relax.clone(),
+            error: self.error.clone(),
+            resolved: 0,
+        }
+    }
+
+    /// Roll this assembler back to `checkpoint`. The uncommitted code assembled since is discarded, together with
+    /// the labels defined, the relocations emitted, the patchpoints and debug information added and any errors that
+    /// happened in it. Dynamic label IDs created since stay valid, but are undefined. Fails if code or data was
+    /// committed since the checkpoint was created.
+    pub fn rollback(&mut self, checkpoint: &Checkpoint<R>) -> Result<(), DynasmError> where R: Clone {
+        if self.memory.committed() != checkpoint.committed
+            || self.rodata.committed_since(&checkpoint.sections[1])
+            || self.data.committed_since(&checkpoint.sections[2]) {
+            return Err(DynasmError::RollbackPastCommit);
+        }
+
+        self.ops.truncate(checkpoint.offset.0 - checkpoint.committed);
+        self.cold.rollback(&checkpoint.sections[0]);
+        self.rodata.rollback(&checkpoint.sections[1]);
+        self.data.rollback(&checkpoint.sections[2]);
+        self.section = checkpoint.section;
+        self.labels.rollback(&checkpoint.labels);
+        self.relocs = checkpoint.relocs.clone();
+        self.relax = checkpoint.relax.clone();
+        self.error = checkpoint.error.clone();
+
+        let discards = |offset| checkpoint.discards(offset);
+        self.managed.discard(discards);
+        self.veneers.discard(discards);
+        self.imports.discard(discards);
+        self.patchpoints.discard(discards);
+        self.unwind.discard(discards);
+        if let Some(perf) = &mut self.perf {
+            perf.discard(discards);
+        }
+        Ok(())
+    }
+
+    /// Free the dynamic label `id`, so its ID can be reused by `new_dynamic_label`. Uncommitted code is committed
+    /// first, so no relocations to the label are pending anymore. Code that was already committed is not affected.
+    /// `id` must not be used afterwards, which is checked in debug builds.
+    pub fn free_dynamic_label(&mut self, id: DynamicLabel) -> Result<(), DynasmError> {
+        self.commit()?;
+        self.labels.free_dynamic(id);
+        self.imports.remove_freed_aliases(&self.labels);
+        self.retargets.remove_freed(&self.labels);
+        self.publish_exports();
+        Ok(())
+    }
+
+    /// Start a new generation of dynamic labels, and return it. Dynamic labels created from now on belong to this
+    /// generation until the next one is started, and can be freed together with `free_label_generation`.
+    pub fn new_label_generation(&mut self) -> usize {
+        self.labels.new_generation()
+    }
+
+    /// Free all dynamic labels created in `generation`, like `free_dynamic_label` does.
+    pub fn free_label_generation(&mut self, generation: usize) -> Result<(), DynasmError> {
+        self.commit()?;
+        self.labels.free_generation(generation);
+        self.imports.remove_freed_aliases(&self.labels);
+        self.retargets.remove_freed(&self.labels);
+        self.publish_exports();
+        Ok(())
+    }
+
+    /// Use `resolver` to resolve the symbols targeted by `extern "name"` when the code is committed.
+    /// Symbols referenced in `alter` are resolved immediately.
+    pub fn set_symbol_resolver<S: SymbolResolver + Send + 'static>(&mut self, resolver: S) {
+        self.symbols.set_resolver(resolver);
+    }
+
+    /// Publish the global and dynamic labels of this assembler, so other assemblers can link against them with
+    /// `import`. Labels are published once the code they are defined in has been committed. The returned table is
+    /// updated whenever code is committed or the executable buffer of this assembler moves. Executable buffers this
+    /// assembler moved out of are kept until every importer has relinked against the new one.
+    pub fn exports(&mut self) -> LabelExports {
+        if self.exports.is_none() {
+            self.exports = Some(LabelExports::new());
+            self.labels.track_changes();
+            self.memory.keep_replaced();
+        }
+        let exports = self.exports.clone().unwrap();
+        self.publish_exports();
+        exports
+    }
+
+    /// Resolve references to global labels that are not defined by this assembler against the labels published in
+    /// `exports` by another assembler. References are resolved when code is committed, and are patched again
+    /// whenever either assembler moves its executable buffer. Branches to imported labels have to be able to
+    /// reach them, so both assemblers should place their memory near each other, for instance with `with_placement`.
+    pub fn import(&mut self, exports: &LabelExports) {
+        self.imports.import(exports);
+    }
+
+    /// Create a new dynamic label ID that refers to the dynamic label `id` published in `exports` by another assembler.
+    pub fn import_dynamic_label(&mut self, exports: &LabelExports, id: DynamicLabel) -> DynamicLabel {
+        let alias = self.labels.new_dynamic_label();
+        self.imports.alias_dynamic(alias, exports, id);
+        alias
+    }
+
+    /// Patch committed code that refers to imported labels again, if any of the assemblers exporting them moved its
+    /// executable buffer since this code was last patched. This happens automatically whenever code is committed.
+    /// Until then, committed code referring to a moved assembler keeps executing the code of that assembler as it was
+    /// before it moved, as the old buffer is kept for this.
+    pub fn relink(&mut self) -> Result<(), DynasmError> {
+        if !self.imports.moved() {
+            return Ok(());
+        }
+
+        let managed = &self.managed;
+        let veneers = &mut self.veneers;
+        let imports = &mut self.imports;
+        let mut moved = false;
+
+        let output = self.memory.alter(|buffer, old_addr, buffer_addr| {
+            // the buffer was moved, fix any relocations relying on its address
+            if buffer_addr != old_addr {
+                moved = true;
+                let change = buffer_addr.wrapping_sub(old_addr) as isize;
+                for reloc in managed.iter() {
+                    if reloc.adjust(0, buffer, change).is_err() {
+                        return Err(DynasmError::ImpossibleRelocation(TargetKind::Managed));
+                    }
+                }
+                veneers.patch(0, Some(buffer_addr), buffer)?;
+            }
+            imports.patch(0, buffer_addr, buffer)
+        });
+
+        if moved {
+            self.record_debug_info(true);
+            self.publish_exports();
+        }
+        output
+    }
+
     /// Use an `UncommittedModifier` to alter uncommitted code.
     /// This does not allow the user to change labels/relocations.
     pub fn alter_uncommitted(&mut self) -> UncommittedModifier {
-        let offset = self.memory.committed();
-        UncommittedModifier::new(&mut self.ops, AssemblyOffset(offset))
+        match self.section {
+            Section::Hot => UncommittedModifier::new(&mut self.ops, AssemblyOffset(self.memory.committed())),
+            Section::Cold => self.cold.modifier(),
+            Section::Rodata => self.rodata.modifier(),
+            Section::Data => self.data.modifier()
+        }
     }
 
     /// Use a `Modifier` to alter committed code directly. While this is happening
-    /// no code can be executed as the relevant pages are remapped as writable.
+    /// no code can be executed as the relevant pages are remapped as writable, or
+    /// in the case of a dual mapped buffer, as the executable buffer is locked.
+    /// In the case of a lock-free buffer, the altered code is instead published as a copy
+    /// of the buffer, while `LockFreeExecutor`s can keep executing the old code.
     /// This API supports defining new labels/relocations, and overwriting previously defined relocations.
     pub fn alter<F, O>(&mut self, f: F) -> Result<O, DynasmError>
     where F: FnOnce(&mut Modifier<R>) -> O {
         self.commit()?;
 
-        // swap out a buffer from base
-        let mut lock = self.memory.write();
-        let buffer = mem::replace(&mut *lock, ExecutableBuffer::default());
-        let mut buffer = buffer.make_mut().expect("Could not swap buffer protection modes");
-
-        // construct the modifier
-        let mut modifier = Modifier {
-            asmoffset: 0,
-            previous_asmoffset: 0,
-            buffer: &mut *buffer,
-
-            labels: &mut self.labels,
-            relocs: &mut self.relocs,
-            old_managed: &mut self.managed,
-            new_managed: ManagedRelocs::new(),
-
-            error: None
-        };
-
-        // execute the user code
-        let output = f(&mut modifier);
-
-        // flush any changes made by the user code to the buffer
-        modifier.encode_relocs()?;
+        let labels = &mut self.labels;
+        let relocs = &mut self.relocs;
+        let managed = &mut self.managed;
+        let veneers = &mut self.veneers;
+        let symbols = &self.symbols;
+        let imports = &mut self.imports;
+        let retargets = &mut self.retargets;
+        let mut moved = false;
+
+        let output = self.memory.alter(|buffer, old_addr, buffer_addr| {
+            // the buffer was moved, fix any relocations relying on its address
+            if buffer_addr != old_addr {
+                moved = true;
+                let change = buffer_addr.wrapping_sub(old_addr) as isize;
+                for reloc in managed.iter() {
+                    if reloc.adjust(0, buffer, change).is_err() {
+                        return Err(DynasmError::ImpossibleRelocation(TargetKind::Managed));
+                    }
+                }
+                veneers.patch(0, Some(buffer_addr), buffer)?;
+                imports.patch(0, buffer_addr, buffer)?;
+            }
 
-        // repack the buffer
-        let buffer = buffer.make_exec().expect("Could not swap buffer protection modes");
-        mem::replace(&mut *lock, buffer);
+            // construct the modifier
+            let mut modifier = Modifier {
+                asmoffset: 0,
+                previous_asmoffset: 0,
+                buffer,
+                buffer_addr,
+
+                labels,
+                relocs,
+                old_managed: managed,
+                new_managed: ManagedRelocs::new(),
+                veneers,
+                symbols,
+                imports,
+                retargets,
+
+                error: None
+            };
+
+            // execute the user code
+            let output = f(&mut modifier);
+
+            // flush any changes made by the user code to the buffer
+            modifier.encode_relocs()?;
+
+            // call it a day
+            Ok(output)
+        });
 
-        // call it a day
-        Ok(output)
+        if moved {
+            self.record_debug_info(true);
+        }
+        self.labels.forget_history();
+        self.publish_exports();
+        output
     }
 
     /// Commit code, flushing the temporary internal assembling buffer to the mapped executable memory.
     /// This makes assembled code available for execution.
     pub fn commit(&mut self) -> Result<(), DynasmError> {
+        if !self.fits() {
+            return Err(DynasmError::OutOfMemory);
+        }
+
+        self.relink()?;
         self.encode_relocs()?;
+        self.labels.forget_history();
+
+        // write the data before any code that refers to it can be executed
+        self.rodata.commit(&mut self.memory);
+        self.data.commit(&mut self.memory);
 
         let managed = &self.managed;
+        let veneers = &mut self.veneers;
+        let imports = &mut self.imports;
         let error = &mut self.error;
+        let mut moved = false;
 
-        self.memory.commit(&mut self.ops, |buffer, old_addr, new_addr| {
+        let committed = self.memory.commit(&mut self.ops, |buffer, old_addr, new_addr| {
             let change = new_addr.wrapping_sub(old_addr) as isize;
+            moved = true;
 
             for reloc in managed.iter() {
                 if reloc.adjust(0, buffer, change).is_err() {
                     *error = Some(DynasmError::ImpossibleRelocation(TargetKind::Managed))
                 }
             }
+            if let Err(e) = veneers.patch(0, Some(new_addr), buffer) {
+                *error = Some(e)
+            }
+            if let Err(e) = imports.patch(0, new_addr, buffer) {
+                *error = Some(e)
+            }
         });
+        committed?;
+
+        self.record_debug_info(moved);
+        self.publish_exports();
 
         if let Some(e) = self.error.take() {
             return Err(e);
@@ -582,6 +1568,135 @@ impl<R: Relocation> Assembler<R> {
         Ok(())
     }
 
+    // returns if the uncommitted code and data fit in the memory of this assembler, counting the most the code can grow
+    // by while it is committed, so nothing has to be undone once committing it has started
+    fn fits(&self) -> bool {
+        let cold = if self.cold.used { self.cold.ops.len() + self.cold.align - 1 } else { 0 };
+        let veneers = self.veneers.island_size(self.relocs.symbol_relocations());
+        self.rodata.fits() && self.data.fits() && self.memory.fits(self.ops.len() + cold + veneers)
+    }
+
+    // publish the labels of this assembler to the assemblers importing them, together with the buffers it moved out of
+    fn publish_exports(&mut self) {
+        if let Some(exports) = &self.exports {
+            let replaced = self.memory.take_replaced();
+            exports.publish(self.memory.execbuffer_addr(), &mut self.labels, self.memory.committed(), replaced);
+        }
+    }
+
+    // record any newly committed symbols to the perf recorder, if one is attached, and register new unwind information
+    fn record_debug_info(&mut self, moved: bool) {
+        let addr = self.memory.execbuffer_addr();
+        if let Some(perf) = &mut self.perf {
+            self.memory.read(|buffer| perf.commit(buffer, addr, moved));
+        }
+        self.unwind.commit(addr, self.memory.committed(), moved);
+    }
+
+    /// Add unwind information for a function that ends at `end`. Once the function has been committed, it is
+    /// registered with the unwinder for as long as this assembler lives, and registered again whenever the executable
+    /// buffer of this assembler is moved.
+    pub fn add_unwind_info(&mut self, cfi: CfiBuilder, end: AssemblyOffset) {
+        self.unwind.add(cfi, end);
+    }
+
+    /// Attach a `PerfRecorder` to this assembler. From now on, every global and dynamic label defined will be recorded
+    /// as a symbol extending up to the next label or the end of the code committed with it. Symbols are recorded again
+    /// whenever the executable buffer of this assembler is moved.
+    pub fn set_perf_recorder(&mut self, recorder: PerfRecorder) {
+        self.perf = Some(recorder);
+    }
+
+    /// Provides mutable access to the attached `PerfRecorder`, if any. Errors encountered while recording symbols
+    /// do not cause committing code to fail, so they can be checked for with `PerfRecorder::take_error`.
+    pub fn perf_recorder_mut(&mut self) -> Option<&mut PerfRecorder> {
+        self.perf.as_mut()
+    }
+
+    /// Record the code from `start` up to `end` as a symbol named `name` once it has been committed.
+    /// This does nothing if no `PerfRecorder` has been attached.
+    pub fn define_symbol<S: Into<String>>(&mut self, name: S, start: AssemblyOffset, end: AssemblyOffset) {
+        if let Some(perf) = &mut self.perf {
+            perf.range(name.into(), start.0, end.0);
+        }
+    }
+
+    /// Place the code in `module` at the current offset of this assembler. The global labels of the module
+    /// are defined in this assembler, and its dynamic labels are recreated as new dynamic labels of this assembler.
+    /// The relocations of the module that depend on its address are applied again, while references to global
+    /// labels the module did not define are resolved against the labels of this assembler when it is committed.
+    /// Modules can only be loaded into the hot section; loading one while another section is selected fails with
+    /// `DynasmError::UnsupportedDirective`. The names of the global labels of the module, and of those it refers to,
+    /// are interned with `components::intern`, so they are never freed.
+    pub fn load_module(&mut self, module: CodeModule<R>) -> Result<LoadedModule, DynasmError> {
+        if self.section != Section::Hot {
+            return Err(DynasmError::UnsupportedDirective(".section"));
+        }
+        let base = self.offset().0;
+
+        // check that the globals of the module are not defined yet, so no labels are defined on failure
+        let globals: Vec<_> = module.globals.iter().map(|(name, offset)| (crate::components::intern(name), base + offset)).collect();
+        if let Some(&(name, _)) = globals.iter().find(|(name, _)| self.labels.resolve_global(name).is_ok()) {
+            return Err(DynasmError::DuplicateLabel(LabelKind::Global(name)));
+        }
+
+        let buf_offset = self.memory.committed();
+        let buf_addr = self.memory.execbuffer_addr();
+        let start = self.ops.len();
+        self.ops.extend_from_slice(&module.code);
+
+        // re-apply relocations for the new location of the code. the ones that need adjustment are only
+        // recorded once all of them succeeded, so nothing refers to the code on failure
+        let mut managed = Vec::new();
+        for (loc, target) in module.relocations {
+            let target = match loc.relocation.kind() {
+                RelocationKind::RelToAbs => target,
+                RelocationKind::Relative
+                | RelocationKind::AbsToRel => target + base
+            };
+            let loc = PatchLoc::new(AssemblyOffset(loc.location.0 + base), loc.offset, loc.relocation);
+            if loc.patch(buf_offset, buf_addr, &mut self.ops, target).is_err() {
+                self.ops.truncate(start);
+                return Err(DynasmError::ImpossibleRelocation(TargetKind::Managed));
+            }
+            if loc.needs_adjustment() {
+                managed.push(loc);
+            }
+        }
+        for loc in managed {
+            self.managed.add(loc);
+        }
+
+        for (loc, name) in module.externs {
+            let loc = PatchLoc::new(AssemblyOffset(loc.location.0 + base), loc.offset, loc.relocation);
+            self.relocs.add_global_named(&name, loc);
+        }
+
+        for (name, offset) in globals {
+            self.labels.define_global(name, AssemblyOffset(offset))?;
+            if let Some(perf) = &mut self.perf {
+                perf.label(name.to_string(), offset);
+            }
+        }
+
+        let mut dynamic_labels = Vec::with_capacity(module.dynamics.len());
+        for offset in module.dynamics {
+            let id = self.labels.new_dynamic_label();
+            if let Some(offset) = offset {
+                self.labels.define_dynamic(id, AssemblyOffset(base + offset))?;
+                if let Some(perf) = &mut self.perf {
+                    perf.label(format!("dynamic_label_{}", id.0), base + offset);
+                }
+            }
+            dynamic_labels.push(id);
+        }
+
+        Ok(LoadedModule {
+            offset: AssemblyOffset(base),
+            dynamic_labels
+        })
+    }
+
     /// Finalize this assembler, returning the internal executablebuffer if no Executor instances exist.
     /// This panics if any uncommitted changes caused errors near the end. To handle these, call `commit()` explicitly beforehand.
     pub fn finalize(mut self) -> Result<ExecutableBuffer, Self> {
@@ -595,14 +1710,55 @@ impl<R: Relocation> Assembler<R> {
         }
     }
 
+    /// Returns if the executable memory of this assembler was reserved up front.
+    /// If so, committed code will never be moved.
+    pub fn is_fixed(&self) -> bool {
+        self.memory.is_fixed()
+    }
+
+    /// Returns a pointer to the committed contents of a data section at `offset`, like the offset of a label defined
+    /// in it. Data sections are never moved, so this pointer stays valid for as long as the executable buffer of this
+    /// assembler lives. Contents of the read-only data section must not be written through it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` does not lie in a data section.
+    pub fn data_ptr(&self, offset: AssemblyOffset) -> *mut u8 {
+        assert!(self.rodata.contains(offset.0) || self.data.contains(offset.0), "Offset {} does not lie in a data section", offset.0);
+        (self.memory.execbuffer_addr() + offset.0) as *mut u8
+    }
+
+    /// Returns if the executable memory of this assembler was placed according to the hint it was created with.
+    /// As committing code can move the executable memory, this can change after every commit.
+    pub fn is_placed(&self) -> bool {
+        self.memory.is_placed()
+    }
+
     /// Create an executor which can be used to execute code while still assembling code
+    ///
+    /// # Panics
+    ///
+    /// Panics if this assembler uses `MappingStrategy::LockFree`.
     pub fn reader(&self) -> Executor {
         Executor {
             execbuffer: self.memory.reader()
         }
     }
 
-    /// Provides access to the assemblers internal labels registry
+    /// Create an executor which can be used to execute code while still assembling code, without ever
+    /// having to wait for the assembler.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this assembler does not use `MappingStrategy::LockFree`.
+    pub fn lock_free_reader(&self) -> LockFreeExecutor {
+        LockFreeExecutor {
+            execbuffer: self.memory.lock_free_reader()
+        }
+    }
+
+    /// Provides access to the assemblers internal labels registry. Labels defined in uncommitted cold code resolve to
+    /// provisional offsets, as described for `Section::Cold`.
     pub fn labels(&self) -> &LabelRegistry {
         &self.labels
     }
@@ -612,20 +1768,151 @@ impl<R: Relocation> Assembler<R> {
         &mut self.labels
     }
 
+    // create a relocation at the current offset, and capture it if a patchpoint is being assembled
+    fn new_patchloc(&mut self, offset: isize, kind: R) -> PatchLoc<R> {
+        let loc = PatchLoc::new(self.offset(), offset, kind);
+        if let Some((capture, copy)) = &mut self.capture {
+            capture.push(copy(&loc));
+        }
+        loc
+    }
+
+    // the buffer of `section`, unless it is the hot section
+    fn section_buffer(&mut self, section: Section) -> Option<&mut SectionBuffer> {
+        match section {
+            Section::Hot => None,
+            Section::Cold => Some(&mut self.cold),
+            Section::Rodata => Some(&mut self.rodata),
+            Section::Data => Some(&mut self.data)
+        }
+    }
+
+    // returns if code is being assembled into one of the data sections. As these are committed separately from the
+    // code, their contents are never relaxed or moved.
+    fn in_data_section(&self) -> bool {
+        matches!(self.section, Section::Rodata | Section::Data)
+    }
+
+    // record a relocation to the absolute address `target`
+    fn extern_relocation(&mut self, loc: PatchLoc<R>, target: usize) {
+        if loc.relocation.kind() == RelocationKind::Relative {
+            // veneers are only placed after the code, and only branches can go through them
+            let field = loc.location.0 - loc.relocation.field_offset();
+            if self.rodata.contains(field) || self.data.contains(field) {
+                self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
+                return;
+            }
+            self.veneers.add(loc, target);
+        } else if let Some(relax) = &mut self.relax {
+            relax.add(loc, target, TargetKind::Extern(target));
+        } else if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target).is_err() {
+            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)))
+        } else if loc.needs_adjustment() {
+            self.managed.add(loc)
+        }
+    }
+
+    // Stop deferring relocations if that was only done for other sections, and these have been placed. Relocations in
+    // the hot section are then encoded right away again.
+    fn stop_deferring(&mut self) {
+        if self.section == Section::Hot && self.relax.as_ref().is_some_and(|relax| !relax.is_relaxing()) {
+            self.relax = None;
+        }
+    }
+
+    // align the current section to `alignment` with `with`, or with no-op instructions if it is `None`
+    fn pad_to(&mut self, alignment: usize, with: Option<u8>) {
+        let start = self.offset();
+        let padding = (alignment - start.0 % alignment) % alignment;
+        let mut bytes = Vec::with_capacity(padding);
+        pad::<R>(&mut bytes, padding, with);
+        self.extend(bytes);
+        if let Some(buffer) = self.section_buffer(self.section) {
+            buffer.align = buffer.align.max(alignment);
+        }
+        let data = self.in_data_section();
+        match &mut self.relax {
+            Some(relax) if !data => relax.add_align(start, padding, alignment, with),
+            _ => ()
+        }
+    }
+
+    // place the uncommitted code of the cold section after the hot code, and move everything referring to it along
+    fn place_cold(&mut self) {
+        if !self.cold.used {
+            return;
+        }
+
+        // pad the hot code so the cold code keeps its alignment
+        let start = self.memory.committed() + self.ops.len();
+        let (mut cold, alignment) = self.cold.take();
+        let padding = (alignment - start % alignment) % alignment;
+        let f = self.cold.placement(start + padding);
+        self.cold.used = self.section == Section::Cold;
+        pad::<R>(&mut self.ops, padding, None);
+        self.ops.append(&mut cold);
+
+        self.labels.relocate(f);
+        self.relocs.relocate(f);
+        self.veneers.relocate(f);
+        self.imports.relocate(f);
+        self.patchpoints.relocate(f);
+        self.unwind.relocate(f);
+        if let Some(perf) = &mut self.perf {
+            perf.relocate(|offset| f(AssemblyOffset(offset)).0);
+        }
+        if let Some(relax) = &mut self.relax {
+            relax.relocate(f);
+            relax.add_align(AssemblyOffset(start), padding, alignment, None);
+        }
+    }
+
     // encode uncommited relocations
     fn encode_relocs(&mut self) -> Result<(), DynasmError> {
+        self.place_cold();
+
+        // Resolve symbols, after which they are handled like any other relocation to an address. If any symbol is
+        // unknown, they are all kept so the code can be committed again once it is known.
+        let symbols = &self.symbols;
+        let targets = self.relocs.symbols().map(|name| symbols.resolve(name)).collect::<Result<Vec<_>, _>>()?;
+        let resolved: Vec<_> = self.relocs.take_symbols().zip(targets).collect();
+        for ((loc, _), target) in resolved {
+            self.extern_relocation(loc, target);
+        }
+
         let buf_offset = self.memory.committed();
         let buf_addr = self.memory.execbuffer_addr();
         let buf = &mut self.ops;
 
+        // relocations in data sections cannot refer to imported labels, as these are only patched in the code
+        let (rodata, data) = (self.rodata.range(), self.data.range());
+        let in_data = |loc: &PatchLoc<R>| {
+            let field = loc.location.0 - loc.relocation.field_offset();
+            rodata.contains(&field) || data.contains(&field)
+        };
+
         // If we accrued any errors while assembling before, emit them now.
         if let Some(e) = self.error.take() {
             return Err(e);
         }
 
-        // Resolve globals
+        // Resolve globals, first to this assembler, then to imported labels
         for (loc, name) in self.relocs.take_globals() {
-            let target = self.labels.resolve_global(name)?;
+            let target = match self.labels.resolve_global(name) {
+                Ok(target) => target,
+                Err(e) => match self.imports.resolve_global(name) {
+                    Some(_) if in_data(&loc) => return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name))),
+                    Some((index, target)) => {
+                        self.imports.add(loc, index, target, TargetKind::Global(name));
+                        continue;
+                    },
+                    None => return Err(e)
+                }
+            };
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, target.0, TargetKind::Global(name));
+                continue;
+            }
             if loc.patch(buf_offset, buf_addr, buf, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
             }
@@ -634,54 +1921,126 @@ impl<R: Relocation> Assembler<R> {
             }
         }
 
-        // Resolve dynamics
+        // Resolve dynamics, first to this assembler, then to imported labels
         for (loc, id) in self.relocs.take_dynamics() {
-            let target = self.labels.resolve_dynamic(id)?;
+            let target = match self.labels.resolve_dynamic(id) {
+                Ok(target) => target,
+                Err(e) => match self.imports.resolve_dynamic(id) {
+                    Some(_) if in_data(&loc) => return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id))),
+                    Some((index, target)) => {
+                        self.imports.add(loc, index, target, TargetKind::Dynamic(id));
+                        continue;
+                    },
+                    None => return Err(e)
+                }
+            };
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, target.0, TargetKind::Dynamic(id));
+                continue;
+            }
             if loc.patch(buf_offset, buf_addr, buf, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
             }
+            self.retargets.add(&loc, id);
             if loc.needs_adjustment() {
                 self.managed.add(loc)
             }
         }
 
         // Check that there are no unknown local labels
-        for (_, name) in self.relocs.take_locals() {
+        if let Some((_, name)) = self.relocs.take_locals().next() {
             return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
         }
 
+        // Perform branch relaxation, and encode the relocations that were waiting for it
+        if let Some(relax) = &mut self.relax {
+            if let Some(layout) = relax.relax(buf_offset, buf) {
+                self.labels.relocate(|offset| layout.map(offset));
+                self.veneers.relocate(|offset| layout.map(offset));
+                self.imports.relocate(|offset| layout.map(offset));
+                self.patchpoints.relocate(|offset| layout.map(offset));
+                self.unwind.relocate(|offset| layout.map(offset));
+                if let Some(perf) = &mut self.perf {
+                    perf.relocate(|offset| layout.map(AssemblyOffset(offset)).0);
+                }
+            }
+
+            for (loc, target, kind) in relax.take() {
+                // relocations in data sections are patched into their uncommitted contents
+                let field = loc.location.0 - loc.relocation.field_offset();
+                let patched = if self.rodata.contains(field) {
+                    loc.patch(self.rodata.start(), buf_addr, &mut self.rodata.ops, target)
+                } else if self.data.contains(field) {
+                    loc.patch(self.data.start(), buf_addr, &mut self.data.ops, target)
+                } else {
+                    loc.patch(buf_offset, buf_addr, buf, target)
+                };
+                if patched.is_err() {
+                    return Err(DynasmError::ImpossibleRelocation(kind));
+                }
+                // retargeting only patches code
+                match kind {
+                    TargetKind::Dynamic(id) if !in_data(&loc) => self.retargets.add(&loc, id),
+                    _ => ()
+                }
+                if loc.needs_adjustment() {
+                    self.managed.add(loc)
+                }
+            }
+        }
+        self.retargets.commit();
+
+        // Add veneers for new targets of branches to absolute addresses, and encode these branches. Code in a fixed
+        // reservation is never moved, so it only needs veneers for the targets it cannot reach.
+        let fixed_addr = if self.memory.is_fixed() { Some(buf_addr) } else { None };
+        self.veneers.emit(buf_offset, fixed_addr, buf);
+        self.veneers.patch(buf_offset, Some(buf_addr), buf)?;
+
+        // Encode relocations to imported labels
+        self.imports.patch(buf_offset, buf_addr, buf)?;
+
+        self.stop_deferring();
         Ok(())
     }
 }
 
 impl<R: Relocation> Extend<u8> for Assembler<R> {
     fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
-        self.ops.extend(iter)
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.extend(iter),
+            None => self.ops.extend(iter)
+        }
     }
 }
 
 impl<'a, R: Relocation> Extend<&'a u8> for Assembler<R> {
     fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
-        self.ops.extend(iter)
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.extend(iter),
+            None => self.ops.extend(iter)
+        }
     }
 }
 
 impl<R: Relocation> DynasmApi for Assembler<R> {
     fn offset(&self) -> AssemblyOffset {
-        AssemblyOffset(self.memory.committed() + self.ops.len())
+        match self.section {
+            Section::Hot => AssemblyOffset(self.memory.committed() + self.ops.len()),
+            Section::Cold => self.cold.offset(),
+            Section::Rodata => self.rodata.offset(),
+            Section::Data => self.data.offset()
+        }
     }
 
     fn push(&mut self, value: u8) {
-        self.ops.push(value);
+        match self.section_buffer(self.section) {
+            Some(buffer) => buffer.ops.push(value),
+            None => self.ops.push(value)
+        }
     }
 
     fn align(&mut self, alignment: usize, with: u8) {
-        let misalign = self.offset().0 % alignment;
-        if misalign != 0 {
-            for _ in misalign .. alignment {
-                self.push(with);
-            }
-        }
+        self.pad_to(alignment, Some(with));
     }
 }
 
@@ -691,7 +2050,9 @@ impl<R: Relocation> DynasmLabelApi for Assembler<R> {
     fn local_label(&mut self, name: &'static str) {
         let offset = self.offset();
         for loc in self.relocs.take_locals_named(name) {
-            if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, offset.0).is_err() {
+            if let Some(relax) = &mut self.relax {
+                relax.add(loc, offset.0, TargetKind::Forward(name));
+            } else if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, offset.0).is_err() {
                 self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)))
             } else if loc.needs_adjustment() {
                 self.managed.add(loc)
@@ -701,27 +2062,33 @@ impl<R: Relocation> DynasmLabelApi for Assembler<R> {
     }
     fn global_label( &mut self, name: &'static str) {
         let offset = self.offset();
+        let data = self.in_data_section();
         if let Err(e) = self.labels.define_global(name, offset) {
             self.error = Some(e)
+        } else if let (Some(perf), false) = (&mut self.perf, data) {
+            perf.label(name.to_string(), offset.0);
         }
     }
     fn dynamic_label(&mut self, id: DynamicLabel) {
         let offset = self.offset();
+        let data = self.in_data_section();
         if let Err(e) = self.labels.define_dynamic(id, offset) {
             self.error = Some(e)
+        } else if let (Some(perf), false) = (&mut self.perf, data) {
+            perf.label(format!("dynamic_label_{}", id.0), offset.0);
         }
     }
     fn global_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_global(name, PatchLoc::new(location, offset, kind));
+        let loc = self.new_patchloc(offset, kind);
+        self.relocs.add_global(name, loc);
     }
     fn dynamic_relocation(&mut self, id: DynamicLabel, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_dynamic(id, PatchLoc::new(location, offset, kind));
+        let loc = self.new_patchloc(offset, kind);
+        self.relocs.add_dynamic(id, loc);
     }
     fn forward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
-        let location = self.offset();
-        self.relocs.add_local(name, PatchLoc::new(location, offset, kind));
+        let loc = self.new_patchloc(offset, kind);
+        self.relocs.add_local(name, loc);
     }
     fn backward_relocation(&mut self, name: &'static str, offset: isize, kind: R) {
         let target = match self.labels.resolve_local(name) {
@@ -731,22 +2098,57 @@ impl<R: Relocation> DynasmLabelApi for Assembler<R> {
                 return;
             }
         };
-        let location = self.offset();
-        let loc = PatchLoc::new(location, offset, kind);
-        if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target).is_err() {
+        let loc = self.new_patchloc(offset, kind);
+        if let Some(relax) = &mut self.relax {
+            relax.add(loc, target, TargetKind::Backward(name));
+        } else if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target).is_err() {
             self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)))
         } else if loc.needs_adjustment() {
             self.managed.add(loc)
         }
     }
     fn bare_relocation(&mut self, target: usize, kind: R) {
+        let loc = self.new_patchloc(0, kind);
+        self.extern_relocation(loc, target);
+    }
+    fn symbol_relocation(&mut self, name: &'static str, kind: R) {
+        let loc = self.new_patchloc(0, kind);
+        self.relocs.add_symbol(name, loc);
+    }
+    fn push_label_scope(&mut self) {
+        self.labels.push_label_scope(&mut self.relocs);
+    }
+    fn pop_label_scope(&mut self) {
+        if let Err(e) = self.labels.pop_label_scope(&mut self.relocs) {
+            self.error = Some(e)
+        }
+    }
+    fn relaxable_reloc(&mut self) {
         let location = self.offset();
-        let loc = PatchLoc::new(location, 0, kind);
-        if loc.patch(self.memory.committed(), self.memory.execbuffer_addr(), &mut self.ops, target).is_err() {
-            self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)))
-        } else if loc.needs_adjustment() {
-            self.managed.add(loc)
+        let data = self.in_data_section();
+        match &mut self.relax {
+            // the code of patchpoints may not change size
+            Some(relax) if self.capture.is_none() && !data => relax.mark_relaxable(location),
+            _ => ()
+        }
+    }
+    fn record_error(&mut self, error: DynasmError) {
+        self.error = Some(error)
+    }
+    fn switch_section(&mut self, section: Section) {
+        // code in the cold section only gets its final offset when it is committed, and the contents of data sections
+        // are committed separately from the code, so relocations have to wait for that
+        if let Some(buffer) = self.section_buffer(section) {
+            if !buffer.exists() {
+                self.record_error(DynasmError::UnsupportedDirective(".section"));
+                return;
+            }
+            buffer.used = true;
+            if self.relax.is_none() {
+                self.relax = Some(RelaxRegistry::deferring());
+            }
         }
+        self.section = section;
     }
 }
 
@@ -759,11 +2161,16 @@ pub struct Modifier<'a, R: Relocation> {
     asmoffset: usize,
     previous_asmoffset: usize,
     buffer: &'a mut [u8],
+    buffer_addr: usize,
 
     labels: &'a mut LabelRegistry,
     relocs: &'a mut RelocRegistry<R>,
     old_managed: &'a mut ManagedRelocs<R>,
     new_managed: ManagedRelocs<R>,
+    veneers: &'a mut VeneerRegistry<R>,
+    symbols: &'a SymbolRegistry,
+    imports: &'a mut ImportRegistry<R>,
+    retargets: &'a mut RetargetRegistry<R>,
 
     error: Option<DynasmError>
 }
@@ -772,6 +2179,9 @@ impl<'a, R: Relocation> Modifier<'a, R> {
     /// Move the modifier cursor to the selected location.
     pub fn goto(&mut self, offset: AssemblyOffset) {
         self.old_managed.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.veneers.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.imports.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.retargets.remove_between(self.previous_asmoffset, self.asmoffset);
         self.asmoffset = offset.0;
         self.previous_asmoffset = offset.0;
     }
@@ -796,16 +2206,25 @@ impl<'a, R: Relocation> Modifier<'a, R> {
 
     // encode uncommited relocations
     fn encode_relocs(&mut self) -> Result<(), DynasmError> {
-        let buf_addr = self.buffer.as_ptr() as usize;
+        let buf_addr = self.buffer_addr;
 
         // If we accrued any errors while assembling before, emit them now.
         if let Some(e) = self.error.take() {
             return Err(e);
         }
 
-        // Resolve globals
+        // Resolve globals, first to this assembler, then to imported labels
         for (loc, name) in self.relocs.take_globals() {
-            let target = self.labels.resolve_global(name)?;
+            let target = match self.labels.resolve_global(name) {
+                Ok(target) => target,
+                Err(e) => match self.imports.resolve_global(name) {
+                    Some((index, target)) => {
+                        self.imports.add(loc, index, target, TargetKind::Global(name));
+                        continue;
+                    },
+                    None => return Err(e)
+                }
+            };
             if loc.patch(0, buf_addr, self.buffer, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Global(name)));
             }
@@ -814,26 +2233,45 @@ impl<'a, R: Relocation> Modifier<'a, R> {
             }
         }
 
-        // Resolve dynamics
+        // Resolve dynamics, first to this assembler, then to imported labels
         for (loc, id) in self.relocs.take_dynamics() {
-            let target = self.labels.resolve_dynamic(id)?;
+            let target = match self.labels.resolve_dynamic(id) {
+                Ok(target) => target,
+                Err(e) => match self.imports.resolve_dynamic(id) {
+                    Some((index, target)) => {
+                        self.imports.add(loc, index, target, TargetKind::Dynamic(id));
+                        continue;
+                    },
+                    None => return Err(e)
+                }
+            };
             if loc.patch(0, buf_addr, self.buffer, target.0).is_err() {
                 return Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(id)));
             }
+            self.retargets.add(&loc, id);
             if loc.needs_adjustment() {
                 self.new_managed.add(loc);
             }
         }
 
         // Check for unknown locals
-        for (_, name) in self.relocs.take_locals() {
+        if let Some((_, name)) = self.relocs.take_locals().next() {
             return Err(DynasmError::UnknownLabel(LabelKind::Local(name)));
         }
 
         self.old_managed.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.veneers.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.imports.remove_between(self.previous_asmoffset, self.asmoffset);
+        self.retargets.remove_between(self.previous_asmoffset, self.asmoffset);
         self.previous_asmoffset = self.asmoffset;
 
         self.old_managed.append(&mut self.new_managed);
+        self.retargets.commit();
+
+        // No veneers can be added to committed code, so new branches to absolute addresses have to reach their
+        // target directly or through an existing veneer.
+        self.veneers.patch(0, Some(buf_addr), self.buffer)?;
+        self.imports.patch(0, buf_addr, self.buffer)?;
 
         Ok(())
     }
@@ -881,7 +2319,7 @@ impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
     fn local_label(&mut self, name: &'static str) {
         let offset = self.offset();
         for loc in self.relocs.take_locals_named(name) {
-            if loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, offset.0).is_err()  {
+            if loc.patch(0, self.buffer_addr, self.buffer, offset.0).is_err()  {
                 self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Forward(name)));
             } else if loc.needs_adjustment() {
                 self.new_managed.add(loc);
@@ -897,7 +2335,17 @@ impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
     }
     fn dynamic_label(&mut self, id: DynamicLabel) {
         let offset = self.offset();
-        if let Err(e) = self.labels.define_dynamic(id, offset) {
+        if !(self.retargets.is_retargetable(id) && self.labels.resolve_dynamic(id).is_ok()) {
+            if let Err(e) = self.labels.define_dynamic(id, offset) {
+                self.error = Some(e);
+            }
+            return;
+        }
+
+        // patch all references to the label that were not overwritten, and only retarget it if they all reach it
+        self.retargets.remove_between(self.previous_asmoffset, self.asmoffset);
+        if let Err(e) = self.retargets.patch(id, self.buffer_addr, self.buffer, offset.0)
+            .and_then(|()| self.labels.redefine_dynamic(id, offset)) {
             self.error = Some(e);
         }
     }
@@ -923,7 +2371,7 @@ impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
         };
         let location = self.offset();
         let loc = PatchLoc::new(location, offset, kind);
-        if loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target).is_err() {
+        if loc.patch(0, self.buffer_addr, self.buffer, target).is_err() {
             self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Backward(name)));
         } else if loc.needs_adjustment() {
             self.new_managed.add(loc)
@@ -932,12 +2380,31 @@ impl<'a, R: Relocation> DynasmLabelApi for Modifier<'a, R> {
     fn bare_relocation(&mut self, target: usize, kind: R) {
         let location = self.offset();
         let loc = PatchLoc::new(location, 0, kind);
-        if loc.patch(0, self.buffer.as_ptr() as usize, self.buffer, target).is_err() {
+        if loc.relocation.kind() == RelocationKind::Relative {
+            self.veneers.add(loc, target);
+        } else if loc.patch(0, self.buffer_addr, self.buffer, target).is_err() {
             self.error = Some(DynasmError::ImpossibleRelocation(TargetKind::Extern(target)));
         } else if loc.needs_adjustment() {
             self.new_managed.add(loc)
         }
     }
+    fn symbol_relocation(&mut self, name: &'static str, kind: R) {
+        match self.symbols.resolve(name) {
+            Ok(target) => self.bare_relocation(target, kind),
+            Err(e) => self.error = Some(e)
+        }
+    }
+    fn push_label_scope(&mut self) {
+        self.labels.push_label_scope(self.relocs);
+    }
+    fn pop_label_scope(&mut self) {
+        if let Err(e) = self.labels.pop_label_scope(self.relocs) {
+            self.error = Some(e)
+        }
+    }
+    fn record_error(&mut self, error: DynasmError) {
+        self.error = Some(error)
+    }
 }
 
 
@@ -1020,3 +2487,1015 @@ impl<'a, 'b> Extend<&'b u8> for UncommittedModifier<'a> {
         self.extend(iter.into_iter().cloned())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relocations::RelocationSize;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dual_mapped() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_strategy(components::MappingStrategy::DualMapped).unwrap();
+        let reader = ops.reader();
+
+        let start = ops.offset();
+        ops.extend(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]); // mov eax, 42; ret
+        ops.commit().unwrap();
+
+        {
+            let buf = reader.lock();
+            assert_eq!(&buf[..], &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+
+            // committing more code does not require the lock to be released
+            ops.extend(&[0xCC; 16]);
+            ops.commit().unwrap();
+            assert_eq!(buf.len(), 22);
+
+            #[cfg(target_arch = "x86_64")]
+            {
+                let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+                assert_eq!(f(), 42);
+            }
+        }
+
+        // grow the buffer beyond its initial allocation
+        ops.extend(vec![0x90; 8192]);
+        ops.commit().unwrap();
+        assert_eq!(reader.lock().len(), 22 + 8192);
+        assert_eq!(&reader.lock()[..6], &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+
+        ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(1));
+            modifier.push(0x07);
+        }).unwrap();
+        assert_eq!(&reader.lock()[..6], &[0xB8, 0x07, 0x00, 0x00, 0x00, 0xC3]);
+    }
+
+    #[test]
+    fn test_fixed_reservation() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(1 << 20, components::MappingStrategy::ProtectionSwapping).unwrap();
+        assert!(ops.is_fixed());
+        let reader = ops.reader();
+
+        ops.extend(&[0xC3]);
+        ops.commit().unwrap();
+        let addr = reader.lock().ptr(AssemblyOffset(0)) as usize;
+
+        for _ in 0 .. 16 {
+            ops.extend(vec![0xCC; 4096]);
+            ops.commit().unwrap();
+            assert_eq!(reader.lock().ptr(AssemblyOffset(0)) as usize, addr);
+        }
+        assert_eq!(reader.lock().len(), 1 + 16 * 4096);
+    }
+
+    #[test]
+    fn test_fixed_reservation_exhausted() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(4096, components::MappingStrategy::ProtectionSwapping).unwrap();
+        ops.extend(vec![0xCC; 4097]);
+        assert_eq!(ops.commit(), Err(DynasmError::OutOfMemory));
+        assert_eq!(ops.reader().lock().len(), 0);
+
+        // nothing was consumed by the failed commit, so a smaller one still works
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(4096, components::MappingStrategy::ProtectionSwapping).unwrap();
+        let label = ops.new_dynamic_label();
+        ops.dynamic_label(label);
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp =>label
+        ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 4)));
+        let checkpoint = ops.checkpoint();
+        ops.extend(vec![0xCC; 4092]);
+        assert_eq!(ops.commit(), Err(DynasmError::OutOfMemory));
+        assert_eq!(ops.reader().lock().len(), 0);
+
+        ops.rollback(&checkpoint).unwrap();
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0xE9, 0xFB, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_incremental_commit() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(1 << 16, components::MappingStrategy::ProtectionSwapping).unwrap();
+        let reader = ops.reader();
+
+        ops.extend(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]); // mov eax, 42; ret
+        ops.commit().unwrap();
+
+        // commit chunks that straddle page boundaries
+        let mut expected = vec![0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3];
+        for i in 0 .. 50u8 {
+            let chunk = vec![i; 1000];
+            expected.extend(&chunk);
+            ops.extend(chunk);
+            ops.commit().unwrap();
+        }
+
+        let buf = reader.lock();
+        assert_eq!(&buf[..], &expected[..]);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(AssemblyOffset(0))) };
+            assert_eq!(f(), 42);
+        }
+    }
+
+    // returns the protection of the page at `addr`, as listed in /proc/self/maps
+    #[cfg(target_os = "linux")]
+    fn page_protection(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let range = usize::from_str_radix(start, 16).ok()? .. usize::from_str_radix(end, 16).ok()?;
+            if range.contains(&addr) { fields.next().map(|perms| perms[.. 3].to_string()) } else { None }
+        }).expect("address is not mapped")
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_commit_cost() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(8 * page_size, components::MappingStrategy::ProtectionSwapping).unwrap();
+        ops.extend(vec![0x90; 3 * page_size]);
+        ops.commit().unwrap();
+        let base = ops.reader().lock().ptr(AssemblyOffset(0)) as usize;
+
+        // take execute permission away from the first page. reprotecting it during a commit would restore it.
+        mmap::protect_data(base, page_size, false).unwrap();
+
+        ops.extend(&[0xC3; 16]);
+        ops.commit().unwrap();
+
+        assert_eq!(page_protection(base), "r--");
+        assert_eq!(page_protection(base + page_size), "r-x");
+        assert_eq!(page_protection(base + 3 * page_size), "r-x");
+        assert_eq!(&ops.reader().lock()[3 * page_size ..], &[0xC3; 16]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lock_free() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_strategy(components::MappingStrategy::LockFree).unwrap();
+        let reader = ops.lock_free_reader();
+
+        let start = ops.offset();
+        ops.extend(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]); // mov eax, 42; ret
+        ops.commit().unwrap();
+
+        let old = reader.load();
+        assert_eq!(&old[..], &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+
+        // altering code publishes a copy, and leaves the old buffer intact
+        ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(1));
+            modifier.push(0x07);
+        }).unwrap();
+        assert_eq!(&old[..], &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]);
+        assert_eq!(&reader.load()[..], &[0xB8, 0x07, 0x00, 0x00, 0x00, 0xC3]);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(old.ptr(start)) };
+            assert_eq!(f(), 42);
+            let buf = reader.load();
+            let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+            assert_eq!(f(), 7);
+        }
+
+        // a failed alteration is never published
+        let result = ops.alter(|modifier| {
+            modifier.goto(AssemblyOffset(1));
+            modifier.push_u32(0);
+            modifier.global_relocation("missing", 0, x64::X64Relocation::from_size(RelocationSize::DWord));
+        });
+        assert_eq!(result, Err(DynasmError::UnknownLabel(LabelKind::Global("missing"))));
+        assert_eq!(&reader.load()[..], &[0xB8, 0x07, 0x00, 0x00, 0x00, 0xC3]);
+
+        // growing the buffer, and finalizing only once all readers are gone
+        ops.extend(vec![0x90; 8192]);
+        ops.commit().unwrap();
+        assert_eq!(reader.load().len(), 6 + 8192);
+        drop(old);
+
+        let ops = ops.finalize().unwrap_err();
+        drop(reader);
+        let buf = ops.finalize().unwrap();
+        assert_eq!(&buf[..6], &[0xB8, 0x07, 0x00, 0x00, 0x00, 0xC3]);
+    }
+
+    #[test]
+    fn test_relaxation() {
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.enable_relaxation();
+        let far = ops.new_dynamic_label();
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp >skip
+        ops.forward_reloc("skip", 0, (0, 4));
+        ops.relaxable_reloc();
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp >skip, not relaxable
+        ops.forward_reloc("skip", 0, (0, 4));
+        ops.extend(&[0x90; 4]);
+        ops.local_label("skip");
+        ops.extend(&[0x0F, 0x84, 0, 0, 0, 0]); // je =>far
+        ops.dynamic_reloc(far, 0, (0, 4));
+        ops.relaxable_reloc();
+        ops.align(16, 0x90);
+        ops.global_label("aligned");
+        ops.extend(&[0xCC; 200]);
+        ops.dynamic_label(far);
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->aligned
+        ops.global_reloc("aligned", 0, (0, 4));
+        ops.relaxable_reloc();
+        ops.local_label("back");
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp <back
+        ops.backward_reloc("back", 0, (0, 4));
+        ops.relaxable_reloc();
+        let code = ops.finalize().unwrap();
+
+        let mut expected = vec![0xEB, 0x09, 0xE9, 0x04, 0, 0, 0, 0x90, 0x90, 0x90, 0x90, 0x0F, 0x84, 0xD7, 0, 0, 0];
+        expected.extend(&[0x90; 15]);
+        expected.extend(&[0xCC; 200]);
+        expected.extend(&[0xE9, 0x33, 0xFF, 0xFF, 0xFF, 0xEB, 0xFE]);
+        assert_eq!(code, expected);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_relaxation_assembler() {
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.push(0xC3);
+        ops.commit().unwrap();
+
+        ops.enable_relaxation();
+        ops.global_label("count");
+        ops.extend(&[0x31, 0xC0]); // xor eax, eax
+        ops.local_label("repeat");
+        ops.extend(&[0x83, 0xC0, 0x01]); // add eax, 1
+        ops.extend(&[0x83, 0xF8, 0x0A]); // cmp eax, 10
+        ops.extend(&[0x0F, 0x85, 0, 0, 0, 0]); // jne <repeat
+        ops.backward_reloc("repeat", 0, (0, 4));
+        ops.relaxable_reloc();
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        assert_eq!(ops.offset(), AssemblyOffset(12));
+
+        let start = ops.labels().resolve_global("count").unwrap();
+        let buf = ops.finalize().unwrap();
+        assert_eq!(&buf[9 .. 11], &[0x75, 0xF8]);
+        let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(), 10);
+    }
+
+    #[test]
+    fn test_veneers() {
+        // branches that cannot reach their target share a veneer, others jump to their target directly
+        let far = 0x1234_5678_9ABC_usize;
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0x1000);
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern far
+        ops.bare_reloc(far, (0, 4));
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp extern far
+        ops.bare_reloc(far, (0, 4));
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern 0x2000
+        ops.bare_reloc(0x2000, (0, 4));
+        let code = ops.finalize().unwrap();
+
+        // the veneers are jumped over
+        let mut expected = vec![0xE8, 0x13, 0, 0, 0, 0xE9, 0x0E, 0, 0, 0, 0xE8, 0xF1, 0x0F, 0, 0];
+        expected.extend(&[0xE9, 0x21, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend(&[0x49, 0xBB, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12, 0, 0, 0x41, 0xFF, 0xE3, 0, 0, 0]);
+        expected.extend(&[0x49, 0xBB, 0x00, 0x20, 0, 0, 0, 0, 0, 0, 0x41, 0xFF, 0xE3]);
+        assert_eq!(code, expected);
+
+        let mut ops = VecAssembler::<aarch64::Aarch64Relocation>::new(0);
+        ops.extend(&[0, 0, 0, 0x94]); // bl extern far
+        ops.bare_reloc(far, (0,));
+        let code = ops.finalize().unwrap();
+        assert_eq!(&code[0 .. 4], &[0x02, 0, 0, 0x94]);
+        assert_eq!(&code[4 .. 8], &[0x05, 0, 0, 0x14]);
+        assert_eq!(&code[8 .. 16], &[0x50, 0, 0, 0x58, 0x00, 0x02, 0x1F, 0xD6]);
+        assert_eq!(RelocationSize::QWord.read_value(&code[16 .. 24]) as usize, far);
+
+        // relocations without a veneer have to reach their target
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.extend(&[0xEB, 0]); // jmp extern far
+        ops.bare_reloc(far, (0, 1));
+        assert_eq!(ops.finalize().unwrap_err(), DynasmError::ImpossibleRelocation(TargetKind::Extern(far)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_veneers_assembler() {
+        extern "sysv64" fn add_one(value: i32) -> i32 {
+            value + 1
+        }
+        // this differs from `add_one`, so optimized builds do not merge them into a single target
+        extern "sysv64" fn double(value: i32) -> i32 {
+            value * 2 - 40
+        }
+
+        fn assemble<D: DynasmLabelApi<Relocation=x64::X64Relocation>>(ops: &mut D) {
+            ops.push(0x50); // push rax
+            ops.extend(&[0xBF, 41, 0, 0, 0]); // mov edi, 41
+            ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern add_one
+            ops.bare_reloc(add_one as *const () as usize, (0, 4));
+            ops.push(0x59); // pop rcx
+            ops.push(0xC3); // ret
+        }
+
+        // modules can be loaded anywhere, so they always use their veneers
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        assemble(&mut ops);
+        let module = ops.finalize_module().unwrap();
+        assert_eq!(&module.code()[7 .. 11], &[0x0D, 0, 0, 0]);
+        assert_eq!(&module.code()[24 .. 26], &[0x49, 0xBB]);
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        let loaded = ops.load_module(module).unwrap();
+        let direct = ops.offset();
+        assemble(&mut ops);
+        ops.commit().unwrap();
+
+        // code committed in the middle of a function jumps over the veneers emitted after it
+        let split = ops.offset();
+        ops.push(0x50); // push rax
+        ops.extend(&[0xBF, 41, 0, 0, 0]); // mov edi, 41
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern double
+        ops.bare_reloc(double as *const () as usize, (0, 4));
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, split.0 + 11 + 5 + 16);
+        ops.push(0x59); // pop rcx
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+
+        let buf = ops.finalize().unwrap();
+        for start in [loaded.offset, direct, split] {
+            let f: extern "sysv64" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+            assert_eq!(f(), 42);
+        }
+
+        // code that is never moved only gets veneers for the targets it cannot reach directly
+        let mut ops = Assembler::<x64::X64Relocation>::with_reservation(0x10000, components::MappingStrategy::ProtectionSwapping).unwrap();
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        let near = ops.reader().lock().ptr(AssemblyOffset(0)) as usize;
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern near
+        ops.bare_reloc(near, (0, 4));
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, 6);
+        assert_eq!(&ops.reader().lock()[1 .. 6], &[0xE8, 0xFA, 0xFF, 0xFF, 0xFF]);
+
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern far
+        ops.bare_reloc(0x1234_5678_9ABC, (0, 4));
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0, 11 + 5 + 13);
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn test_placement() {
+        extern "sysv64" fn add_one(value: i32) -> i32 {
+            value + 1
+        }
+        let add_one_addr = add_one as *const () as usize;
+
+        let hint = PlacementHint::new(0x4000_0000, 0x1000);
+        assert!(hint.contains(0x3FFF_F000, 0x2001));
+        assert!(!hint.contains(0x3FFF_EFFF, 0x10));
+        assert!(!hint.contains(0x4000_0FF0, 0x12));
+        assert!(!hint.contains(0x4000_0000, 0));
+
+        let hint = PlacementHint::near_text();
+        let (buffer, placed) = ExecutableBuffer::new_near(0x1000, hint).unwrap();
+        assert!(placed);
+        assert!(hint.contains(buffer.base_addr(), 0x1000));
+
+        // memory that cannot be placed is mapped elsewhere
+        let occupied = PlacementHint::new(buffer.base_addr() + 0x800, 0x800);
+        let (other, placed) = ExecutableBuffer::new_near(0x1000, occupied).unwrap();
+        assert!(!placed);
+        assert_ne!(other.base_addr(), buffer.base_addr());
+        #[cfg(target_os = "linux")]
+        {
+            let memory = MemoryManager::with_placement(0x1000, components::MappingStrategy::DualMapped, hint).unwrap();
+            assert!(memory.is_placed());
+        }
+
+        let mut ops = Assembler::<x64::X64Relocation>::with_placement(PlacementHint::near(add_one_addr), components::MappingStrategy::ProtectionSwapping).unwrap();
+        ops.push(0x50); // push rax
+        ops.extend(&[0xBF, 41, 0, 0, 0]); // mov edi, 41
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern add_one
+        ops.bare_reloc(add_one_addr, (0, 4));
+        ops.push(0x59); // pop rcx
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        assert!(ops.is_placed());
+
+        // the placement is kept when the buffer grows
+        ops.extend(&[0xCC; 0x3000]);
+        ops.commit().unwrap();
+        assert!(ops.is_placed());
+
+        let buf = ops.finalize().unwrap();
+        let call_end = buf.ptr(AssemblyOffset(11)) as usize;
+        assert_eq!(call_end.wrapping_add(RelocationSize::DWord.read_value(&buf[7 .. 11]) as usize), add_one_addr);
+        let f: extern "sysv64" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 42);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_symbols() {
+        extern "sysv64" fn add_one(value: i32) -> i32 {
+            value + 1
+        }
+
+        fn assemble<D: DynasmLabelApi<Relocation=x64::X64Relocation>>(ops: &mut D) {
+            ops.push(0x50); // push rax
+            ops.extend(&[0xBF, 41, 0, 0, 0]); // mov edi, 41
+            ops.extend(&[0xE8, 0, 0, 0, 0]); // call extern "add_one"
+            ops.symbol_reloc("add_one", (0, 4));
+            ops.push(0x59); // pop rcx
+            ops.push(0xC3); // ret
+        }
+
+        let mut symbols = std::collections::HashMap::new();
+        symbols.insert("add_one", add_one as *const () as usize);
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.set_symbol_resolver(symbols);
+        assemble(&mut ops);
+        ops.commit().unwrap();
+        let buf = ops.finalize().unwrap();
+        let f: extern "sysv64" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 42);
+
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0x1000);
+        ops.set_symbol_resolver(|name: &str| if name == "add_one" { Some(0x2000) } else { None });
+        assemble(&mut ops);
+        assert_eq!(&ops.finalize().unwrap()[7 .. 11], &[0xF5, 0x0F, 0, 0]);
+
+        // symbols have to be known by the resolver
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        assemble(&mut ops);
+        assert_eq!(ops.finalize().unwrap_err(), DynasmError::UnknownSymbol("add_one"));
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.set_symbol_resolver(|_: &str| None);
+        assemble(&mut ops);
+        assert_eq!(ops.commit().unwrap_err(), DynasmError::UnknownSymbol("add_one"));
+
+        // unknown symbols are kept, so they can be resolved later
+        ops.set_symbol_resolver(|name: &str| if name == "add_one" { Some(add_one as *const () as usize) } else { None });
+        ops.commit().unwrap();
+        let buf = ops.finalize().unwrap();
+        let f: extern "sysv64" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(AssemblyOffset(0))) };
+        assert_eq!(f(), 42);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_linking() {
+        let mut stubs = Assembler::<x64::X64Relocation>::new().unwrap();
+        let exports = stubs.exports();
+        let answer = stubs.new_dynamic_label();
+        stubs.global_label("one");
+        stubs.extend(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3]); // mov eax, 1; ret
+        stubs.dynamic_label(answer);
+        stubs.extend(&[0xB8, 0x29, 0x00, 0x00, 0x00, 0xC3]); // mov eax, 41; ret
+
+        // labels are only published once they are committed
+        assert_eq!(exports.resolve_global("one"), None);
+        stubs.commit().unwrap();
+        assert_eq!(exports.resolve_global("one"), Some(AssemblyOffset(0)));
+        assert_eq!(exports.resolve_dynamic(answer), Some(AssemblyOffset(6)));
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.import(&exports);
+        let imported = ops.import_dynamic_label(&exports, answer);
+        ops.push(0x50); // push rax
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call ->one
+        ops.global_relocation("one", 0, x64::X64Relocation::from_encoding((0, 4)));
+        ops.extend(&[0x89, 0xC2]); // mov edx, eax
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call =>imported
+        ops.dynamic_relocation(imported, 0, x64::X64Relocation::from_encoding((0, 4)));
+        ops.extend(&[0x01, 0xD0]); // add eax, edx
+        ops.push(0x59); // pop rcx
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        let run = || {
+            let buf = reader.lock();
+            let f: extern "sysv64" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(AssemblyOffset(0))) };
+            f()
+        };
+        assert_eq!(run(), 42);
+
+        // move the exported code. the importing code keeps calling the old copy until it is relinked, after which
+        // the old copy is released
+        let old_addr = exports.addr();
+        stubs.extend(vec![0xCC; 0x10000]);
+        stubs.commit().unwrap();
+        assert_ne!(exports.addr(), old_addr);
+        assert_eq!(exports.retained_buffers(), 1);
+        assert_eq!(run(), 42);
+        ops.relink().unwrap();
+        assert_eq!(exports.retained_buffers(), 0);
+        assert_eq!(run(), 42);
+
+        // and move the importing code
+        ops.extend(vec![0xCC; 0x10000]);
+        ops.commit().unwrap();
+        assert_eq!(run(), 42);
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.import(&exports);
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call ->two
+        ops.global_relocation("two", 0, x64::X64Relocation::from_encoding((0, 4)));
+        assert_eq!(ops.commit().unwrap_err(), DynasmError::UnknownLabel(LabelKind::Global("two")));
+
+        // freed labels are withdrawn
+        stubs.free_dynamic_label(answer).unwrap();
+        assert_eq!(exports.resolve_dynamic(answer), None);
+        assert_eq!(exports.resolve_global("one"), Some(AssemblyOffset(0)));
+    }
+
+    #[test]
+    fn test_named_globals() {
+        let name = format!("function_{}", 1);
+        assert!(std::ptr::eq(components::intern(&name), components::intern("function_1")));
+
+        // names are shared with other threads
+        let interned = components::intern(&name);
+        let other = std::thread::spawn(|| (components::interned("function_1"), components::intern("function_1"))).join().unwrap();
+        assert!(std::ptr::eq(other.0.unwrap(), interned) && std::ptr::eq(other.1, interned));
+
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->(name)
+        ops.named_global_reloc(&name, 0, (0, 4));
+        ops.named_global_label(&name);
+        ops.push(0xC3); // ret
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->function_1
+        ops.global_reloc("function_1", 0, (0, 4));
+        assert_eq!(ops.finalize().unwrap(), vec![0xE9, 0, 0, 0, 0, 0xC3, 0xE9, 0xFA, 0xFF, 0xFF, 0xFF]);
+
+        let mut labels = LabelRegistry::new();
+        labels.define_global_named(&name, AssemblyOffset(3)).unwrap();
+        assert_eq!(labels.resolve_global("function_1"), Ok(AssemblyOffset(3)));
+        assert_eq!(labels.define_global_named(&name, AssemblyOffset(4)), Err(DynasmError::DuplicateLabel(LabelKind::Global("function_1"))));
+        assert_eq!(labels.resolve_global_named("function_1"), Some(AssemblyOffset(3)));
+
+        // looking up unknown names does not intern them
+        assert_eq!(labels.resolve_global_named("function_2"), None);
+        assert_eq!(components::interned("function_2"), None);
+    }
+
+    #[test]
+    fn test_label_scopes() {
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.local_label("loop");
+        ops.extend(&[0xEB, 0]); // jmp >next
+        ops.forward_reloc("next", 0, (0, 1));
+        ops.push_label_scope();
+        ops.local_label("loop");
+        ops.extend(&[0xEB, 0]); // jmp <loop
+        ops.backward_reloc("loop", 0, (0, 1));
+        ops.local_label("next");
+        ops.extend(&[0xEB, 0]); // jmp >next
+        ops.forward_reloc("next", 0, (0, 1));
+        ops.local_label("next");
+        ops.pop_label_scope();
+        ops.local_label("next");
+        ops.extend(&[0xEB, 0]); // jmp <loop
+        ops.backward_reloc("loop", 0, (0, 1));
+        assert_eq!(ops.finalize().unwrap(), vec![0xEB, 0x04, 0xEB, 0xFE, 0xEB, 0x00, 0xEB, 0xF8]);
+
+        // forward references have to be resolved within their scope
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.push_label_scope();
+        ops.extend(&[0xEB, 0]); // jmp >done
+        ops.forward_reloc("done", 0, (0, 1));
+        ops.pop_label_scope();
+        ops.local_label("done");
+        assert_eq!(ops.finalize().unwrap_err(), DynasmError::UnknownLabel(LabelKind::Local("done")));
+
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.pop_label_scope();
+        assert_eq!(ops.finalize().unwrap_err(), DynasmError::UnbalancedScope);
+    }
+
+    #[test]
+    fn test_dynamic_label_recycling() {
+        let mut labels = LabelRegistry::new();
+        let first = labels.new_dynamic_label();
+        labels.define_dynamic(first, AssemblyOffset(4)).unwrap();
+        labels.free_dynamic(first);
+        assert!(!labels.is_live(first));
+
+        // ids are reused, but the new label is distinct and undefined
+        let reused = labels.new_dynamic_label();
+        assert_eq!(reused.get_id(), first.get_id());
+        assert_ne!(reused, first);
+        assert_eq!(labels.resolve_dynamic(reused), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(reused))));
+
+        let generation = labels.new_generation();
+        let a = labels.new_dynamic_label();
+        let b = labels.new_dynamic_label();
+        labels.new_generation();
+        let c = labels.new_dynamic_label();
+        labels.free_generation(generation);
+        assert!(labels.is_live(reused) && !labels.is_live(a) && !labels.is_live(b) && labels.is_live(c));
+        assert_eq!(labels.dynamics().count(), 4);
+
+        // labels freed individually are not freed again with their generation
+        let generation = labels.new_generation();
+        let d = labels.new_dynamic_label();
+        let e = labels.new_dynamic_label();
+        labels.free_dynamic(d);
+        let f = labels.new_dynamic_label();
+        labels.free_generation(generation);
+        assert!(!labels.is_live(e) && !labels.is_live(f) && labels.is_live(c));
+        assert_eq!(labels.dynamics().count(), 4);
+
+        // ids that were never created are unknown, as are those of another registry
+        let foreign = DynamicLabel(100, 0);
+        assert_eq!(labels.resolve_dynamic(foreign), Err(DynasmError::UnknownLabel(LabelKind::Dynamic(foreign))));
+        assert_eq!(LabelExports::new().resolve_dynamic(c), None);
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        let generation = ops.new_label_generation();
+        let label = ops.new_dynamic_label();
+        ops.extend(&[0xEB, 0]); // jmp =>label
+        ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 1)));
+        ops.dynamic_label(label);
+        ops.free_label_generation(generation).unwrap();
+        assert_eq!(ops.new_dynamic_label().get_id(), label.get_id());
+        assert_eq!(&ops.reader().lock()[..], &[0xEB, 0x00]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "use of freed dynamic label")]
+    fn test_freed_dynamic_label() {
+        let mut labels = LabelRegistry::new();
+        let id = labels.new_dynamic_label();
+        labels.free_dynamic(id);
+        labels.new_dynamic_label();
+        let _ = labels.define_dynamic(id, AssemblyOffset(0));
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_stale_dynamic_label() {
+        let mut labels = LabelRegistry::new();
+        let stale = labels.new_dynamic_label();
+        labels.free_dynamic(stale);
+        let live = labels.new_dynamic_label();
+        assert_eq!(live.get_id(), stale.get_id());
+        labels.define_dynamic(live, AssemblyOffset(4)).unwrap();
+
+        let unknown = Err(DynasmError::UnknownLabel(LabelKind::Dynamic(stale)));
+        assert_eq!(labels.define_dynamic(stale, AssemblyOffset(8)), unknown);
+        assert_eq!(labels.redefine_dynamic(stale, AssemblyOffset(8)), unknown);
+        assert_eq!(labels.resolve_dynamic(live), Ok(AssemblyOffset(4)));
+    }
+
+    #[test]
+    fn test_unknown_dynamic_label() {
+        let mut labels = LabelRegistry::new();
+        let foreign = DynamicLabel(100, 0);
+        let unknown = Err(DynasmError::UnknownLabel(LabelKind::Dynamic(foreign)));
+        assert_eq!(labels.define_dynamic(foreign, AssemblyOffset(0)), unknown);
+        assert_eq!(labels.redefine_dynamic(foreign, AssemblyOffset(0)), unknown);
+    }
+
+    #[test]
+    fn test_retargetable_labels() {
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        let label = ops.new_retargetable_label();
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp =>label
+        ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 4)));
+        ops.dynamic_label(label);
+        ops.push(0xC3); // ret
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..5], &[0xE9, 0, 0, 0, 0]);
+
+        // redefining the label patches the committed references to it
+        ops.alter(|m| {
+            m.goto(AssemblyOffset(6));
+            m.dynamic_label(label);
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..5], &[0xE9, 1, 0, 0, 0]);
+
+        // references that cannot reach the new definition are reported, and the label keeps its definition
+        ops.extend(&[0xEB, 0]); // jmp =>label
+        ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 1)));
+        ops.extend(vec![0xCC; 0x100]);
+        let far = ops.offset();
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        let result = ops.alter(|m| {
+            m.goto(far);
+            m.dynamic_label(label);
+        });
+        assert_eq!(result, Err(DynasmError::ImpossibleRelocation(TargetKind::Dynamic(label))));
+        assert_eq!(&ops.reader().lock()[..9], &[0xE9, 1, 0, 0, 0, 0xC3, 0xC3, 0xEB, 0xFD]);
+        assert_eq!(ops.labels().resolve_dynamic(label), Ok(AssemblyOffset(6)));
+
+        // references that were overwritten are forgotten
+        ops.alter(|m| {
+            m.goto(AssemblyOffset(7));
+            m.push(0x90);
+            m.push(0x90);
+            m.goto(far);
+            m.dynamic_label(label);
+        }).unwrap();
+        assert_eq!(&ops.reader().lock()[..9], &[0xE9, 0x04, 0x01, 0, 0, 0xC3, 0xC3, 0x90, 0x90]);
+
+        // other dynamic labels still cannot be defined twice
+        let fixed = ops.new_dynamic_label();
+        ops.dynamic_label(fixed);
+        ops.commit().unwrap();
+        assert_eq!(ops.alter(|m| m.dynamic_label(fixed)), Err(DynasmError::DuplicateLabel(LabelKind::Dynamic(fixed))));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_patchpoints() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_strategy(MappingStrategy::DualMapped).unwrap();
+        let first = ops.new_dynamic_label();
+        let second = ops.new_dynamic_label();
+        ops.push(0xC3); // ret
+        let patchpoint = ops.patchpoint(5, |ops| {
+            ops.extend(&[0xE8, 0, 0, 0, 0]); // call =>first
+            ops.dynamic_relocation(first, 0, x64::X64Relocation::from_encoding((0, 4)));
+        });
+        ops.dynamic_label(first);
+        ops.push(0xC3); // ret
+        ops.dynamic_label(second);
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+
+        // the patchpoint is aligned and padded to 8 bytes
+        let reader = ops.reader();
+        assert_eq!(&reader.lock()[..16], &[0xC3, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0xE8, 3, 0, 0, 0, 0x90, 0x90, 0x90]);
+
+        ops.retarget_patchpoint(patchpoint, second).unwrap();
+        assert_eq!(&reader.lock()[8..13], &[0xE8, 4, 0, 0, 0]);
+
+        let addr = reader.lock().ptr(AssemblyOffset(0)) as usize;
+        ops.retarget_patchpoint_extern(patchpoint, addr).unwrap();
+        assert_eq!(&reader.lock()[8..13], &[0xE8, 0xF3, 0xFF, 0xFF, 0xFF]);
+
+        let far = addr.wrapping_add(1 << 40);
+        assert_eq!(ops.retarget_patchpoint_extern(patchpoint, far), Err(DynasmError::ImpossibleRelocation(TargetKind::Extern(far))));
+        assert_eq!(&reader.lock()[8..13], &[0xE8, 0xF3, 0xFF, 0xFF, 0xFF]);
+
+        // aarch64 patchpoints are padded with whole nop instructions
+        let mut ops = Assembler::<aarch64::Aarch64Relocation>::with_strategy(MappingStrategy::DualMapped).unwrap();
+        let first = ops.new_dynamic_label();
+        let second = ops.new_dynamic_label();
+        ops.extend(&[0xC0, 0x03, 0x5F, 0xD6]); // ret
+        let patchpoint = ops.patchpoint(8, |ops| {
+            ops.extend(&[0, 0, 0, 0x94]); // bl =>first
+            ops.dynamic_relocation(first, 0, aarch64::Aarch64Relocation::from_encoding((0,)));
+        });
+        ops.dynamic_label(first);
+        ops.extend(&[0xC0, 0x03, 0x5F, 0xD6]); // ret
+        ops.dynamic_label(second);
+        ops.extend(&[0xC0, 0x03, 0x5F, 0xD6]); // ret
+        ops.commit().unwrap();
+
+        let reader = ops.reader();
+        assert_eq!(&reader.lock()[4..16], &[0x1F, 0x20, 0x03, 0xD5, 0x02, 0, 0, 0x94, 0x1F, 0x20, 0x03, 0xD5]);
+        ops.retarget_patchpoint(patchpoint, second).unwrap();
+        assert_eq!(&reader.lock()[8..12], &[0x03, 0, 0, 0x94]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[should_panic(expected = "use of discarded patchpoint")]
+    fn test_discarded_patchpoint() {
+        let mut ops = Assembler::<x64::X64Relocation>::with_strategy(MappingStrategy::DualMapped).unwrap();
+        let label = ops.new_dynamic_label();
+        ops.dynamic_label(label);
+        ops.push(0xC3); // ret
+        let checkpoint = ops.checkpoint();
+        let discarded = ops.patchpoint(5, |ops| {
+            ops.extend(&[0xE8, 0, 0, 0, 0]); // call =>label
+            ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 4)));
+        });
+        ops.rollback(&checkpoint).unwrap();
+
+        // the id of the discarded patchpoint is reused, but its handle stays invalid
+        let patchpoint = ops.patchpoint(5, |ops| {
+            ops.extend(&[0xE8, 0, 0, 0, 0]); // call =>label
+            ops.dynamic_relocation(label, 0, x64::X64Relocation::from_encoding((0, 4)));
+        });
+        assert_ne!(patchpoint, discarded);
+        ops.commit().unwrap();
+        ops.retarget_patchpoint(patchpoint, label).unwrap();
+        let _ = ops.retarget_patchpoint(discarded, label);
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        let label = ops.new_dynamic_label();
+        let abandoned = ops.new_dynamic_label();
+        ops.extend(&[0xEB, 0]); // jmp >done
+        ops.forward_reloc("done", 0, (0, 1));
+        ops.dynamic_label(label);
+        let checkpoint = ops.checkpoint();
+        ops.global_label("speculative");
+        ops.local_label("done");
+        ops.extend(&[0xEB, 0]); // jmp =>abandoned
+        ops.dynamic_reloc(abandoned, 0, (0, 1));
+        ops.pop_label_scope();
+        ops.rollback(&checkpoint).unwrap();
+        assert_eq!(ops.offset(), AssemblyOffset(2));
+
+        // labels defined since can be defined again, while labels defined before are kept
+        ops.push(0x90); // nop
+        ops.local_label("done");
+        ops.global_label("speculative");
+        ops.extend(&[0xEB, 0]); // jmp =>label
+        ops.dynamic_reloc(label, 0, (0, 1));
+        ops.commit().unwrap();
+        assert_eq!(ops.rollback(&checkpoint), Err(DynasmError::RollbackPastCommit));
+        assert_eq!(ops.finalize().unwrap(), vec![0xEB, 0x01, 0x90, 0xEB, 0xFD]);
+
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        let checkpoint = ops.checkpoint();
+        ops.global_label("speculative");
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->missing
+        ops.global_relocation("missing", 0, x64::X64Relocation::from_encoding((0, 4)));
+        ops.rollback(&checkpoint).unwrap();
+        ops.global_label("speculative");
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+        assert_eq!(&ops.reader().lock()[..], &[0xC3, 0xC3]);
+        assert_eq!(ops.labels().resolve_global("speculative"), Ok(AssemblyOffset(1)));
+        assert_eq!(ops.rollback(&checkpoint), Err(DynasmError::RollbackPastCommit));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_cold_section() {
+        let mut ops = VecAssembler::<x64::X64Relocation>::new(0);
+        ops.extend(&[0x85, 0xFF]); // test edi, edi
+        ops.extend(&[0x0F, 0x84, 0, 0, 0, 0]); // je >slow
+        ops.forward_reloc("slow", 0, (0, 4));
+        ops.switch_section(Section::Cold);
+        ops.local_label("slow");
+        ops.extend(&[0xB8, 1, 0, 0, 0]); // mov eax, 1
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp >done
+        ops.forward_reloc("done", 0, (0, 4));
+        ops.switch_section(Section::Hot);
+        ops.extend(&[0x31, 0xC0]); // xor eax, eax
+        ops.local_label("done");
+        ops.push(0xC3); // ret
+        let code = ops.finalize().unwrap();
+
+        let expected = [0x85, 0xFF, 0x0F, 0x84, 3, 0, 0, 0, 0x31, 0xC0, 0xC3, 0xB8, 1, 0, 0, 0, 0xE9, 0xF5, 0xFF, 0xFF, 0xFF];
+        assert_eq!(code, expected);
+
+        // the cold code keeps its alignment, and can be rolled back like hot code
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.push(0xC3);
+        ops.commit().unwrap();
+        let start = ops.offset();
+        ops.extend(&[0x85, 0xFF]); // test edi, edi
+        ops.extend(&[0x0F, 0x84, 0, 0, 0, 0]); // je ->slow
+        ops.global_reloc("slow", 0, (0, 4));
+        ops.switch_section(Section::Cold);
+        ops.align(16, 0xCC);
+        ops.global_label("slow");
+        ops.extend(&[0xB8, 1, 0, 0, 0]); // mov eax, 1
+        let checkpoint = ops.checkpoint();
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->wrong
+        ops.global_reloc("wrong", 0, (0, 4));
+        ops.rollback(&checkpoint).unwrap();
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp >done
+        ops.forward_reloc("done", 0, (0, 4));
+        ops.switch_section(Section::Hot);
+        ops.extend(&[0x31, 0xC0]); // xor eax, eax
+        ops.local_label("done");
+        ops.push(0xC3); // ret
+        ops.commit().unwrap();
+
+        // relocations are only deferred while the cold section has uncommitted code, and modules cannot be loaded there
+        assert!(ops.relax.is_none());
+        ops.switch_section(Section::Cold);
+        let mut module = VecAssembler::<x64::X64Relocation>::new(0);
+        module.push(0xC3);
+        assert_eq!(ops.load_module(module.finalize_module().unwrap()).unwrap_err(), DynasmError::UnsupportedDirective(".section"));
+        ops.switch_section(Section::Hot);
+
+        assert_eq!(ops.labels().resolve_global("slow").unwrap(), AssemblyOffset(16));
+        let buf = ops.finalize().unwrap();
+        let f: extern "C" fn(i32) -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(0), 1);
+        assert_eq!(f(5), 0);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_data_sections() {
+        for &strategy in &[components::MappingStrategy::ProtectionSwapping, components::MappingStrategy::DualMapped] {
+            let mut ops = Assembler::<x64::X64Relocation>::with_data_sections(4096, 4096, 4096, strategy).unwrap();
+            let start = ops.offset();
+            ops.extend(&[0x48, 0x8B, 0x05, 0, 0, 0, 0]); // mov rax, [->constant]
+            ops.global_reloc("constant", 0, (0, 4));
+            ops.extend(&[0x48, 0x83, 0x05, 0, 0, 0, 0, 1]); // add qword [->counter], 1
+            ops.global_reloc("counter", 0, (1, 4));
+            ops.extend(&[0x48, 0x03, 0x05, 0, 0, 0, 0]); // add rax, [->counter]
+            ops.global_reloc("counter", 0, (0, 4));
+            ops.push(0xC3); // ret
+            ops.switch_section(Section::Rodata);
+            ops.global_label("constant");
+            ops.extend(&40u64.to_le_bytes());
+            ops.switch_section(Section::Data);
+            ops.align(8, 0);
+            let checkpoint = ops.checkpoint();
+            ops.global_label("wrong");
+            ops.push(1);
+            ops.rollback(&checkpoint).unwrap();
+            ops.global_label("counter");
+            ops.extend(&[0; 8]);
+            ops.switch_section(Section::Hot);
+            ops.commit().unwrap();
+
+            assert_eq!(ops.labels().resolve_global("constant").unwrap(), AssemblyOffset(4096));
+            assert_eq!(ops.labels().resolve_global("counter").unwrap(), AssemblyOffset(8192));
+            let f: extern "C" fn() -> u64 = unsafe { std::mem::transmute(ops.reader().lock().ptr(start)) };
+            assert_eq!(f(), 41);
+
+            // altering the code leaves the data sections writable
+            ops.alter(|modifier| modifier.goto(start)).unwrap();
+            assert_eq!(f(), 42);
+            let counter = ops.data_ptr(AssemblyOffset(8192)) as *const u64;
+            assert_eq!(unsafe { counter.read() }, 2);
+
+            // data that does not fit is not committed
+            let checkpoint = ops.checkpoint();
+            ops.switch_section(Section::Data);
+            ops.extend(vec![0; 4096]);
+            ops.switch_section(Section::Hot);
+            assert_eq!(ops.commit(), Err(DynasmError::OutOfMemory));
+            ops.rollback(&checkpoint).unwrap();
+            ops.commit().unwrap();
+        }
+
+        let lock_free = Assembler::<x64::X64Relocation>::with_data_sections(4096, 4096, 4096, components::MappingStrategy::LockFree);
+        assert_eq!(lock_free.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+        // assemblers without data sections report switching to one
+        let mut ops = Assembler::<x64::X64Relocation>::new().unwrap();
+        ops.switch_section(Section::Rodata);
+        ops.push(0xC3);
+        assert_eq!(ops.commit(), Err(DynasmError::UnsupportedDirective(".section")));
+    }
+
+    #[test]
+    fn test_absolute_relocations() {
+        test_absolute_relocations_for(x64::X64Relocation::with_kind(RelocationSize::QWord, RelocationKind::AbsToRel));
+        test_absolute_relocations_for(x86::X86Relocation::from_encoding((0, 8, 1)));
+        test_absolute_relocations_for(aarch64::Aarch64Relocation::from_encoding((24,)));
+    }
+
+    fn test_absolute_relocations_for<R: Relocation + Clone>(relocation: R) {
+        let mut ops = Assembler::<R>::new().unwrap();
+        let reader = ops.reader();
+        let target = ops.new_dynamic_label();
+
+        ops.global_label("backward");
+        ops.extend(&[0; 8]);
+        ops.global_relocation("backward", 0, relocation.clone()); // .qword abs ->backward
+        ops.extend(&[0; 8]);
+        ops.dynamic_relocation(target, 0, relocation); // .qword abs =>target
+        ops.dynamic_label(target);
+        ops.commit().unwrap();
+
+        let addr = reader.lock().ptr(AssemblyOffset(0)) as usize;
+        {
+            let buf = reader.lock();
+            assert_eq!(RelocationSize::QWord.read_value(&buf[0 .. 8]) as usize, addr);
+            assert_eq!(RelocationSize::QWord.read_value(&buf[8 .. 16]) as usize, addr + 16);
+        }
+
+        // growing the buffer moves the committed code, after which the absolute addresses are adjusted
+        ops.extend(vec![0; 1 << 20]);
+        ops.commit().unwrap();
+
+        let buf = reader.lock();
+        let moved = buf.ptr(AssemblyOffset(0)) as usize;
+        assert_ne!(moved, addr);
+        assert_eq!(RelocationSize::QWord.read_value(&buf[0 .. 8]) as usize, moved);
+        assert_eq!(RelocationSize::QWord.read_value(&buf[8 .. 16]) as usize, moved + 16);
+    }
+}
@@ -2,9 +2,11 @@
 // Unfortunately Memmap itself doesn't support a cheap zero-length variant
 
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fs::File;
 use std::io;
 
-use memmap::{Mmap, MmapMut};
+use memmap::{Mmap, MmapMut, MmapOptions};
 
 use crate::AssemblyOffset;
 
@@ -12,10 +14,11 @@ use crate::AssemblyOffset;
 /// This structure does not allocate when its size is 0.
 #[derive(Debug)]
 pub struct ExecutableBuffer {
-    // length of the buffer that has actually been written to
-    length: usize,
+    // length of the buffer that has actually been written to. This is atomic so it can be
+    // updated through a shared reference when the buffer is written to through an alias.
+    length: AtomicUsize,
     // backing buffer
-    buffer: Option<Mmap>
+    buffer: Option<Backing<Mmap>>
 }
 
 /// ExecutableBuffer equivalent that holds a buffer of mutable memory instead of executable memory. It also derefs to a `&mut [u8]`.
@@ -25,7 +28,54 @@ pub struct MutableBuffer {
     // length of the buffer that has actually been written to
     length: usize,
     // backing buffer
-    buffer: Option<MmapMut>
+    buffer: Option<Backing<MmapMut>>
+}
+
+/// A request to place memory close to a certain address, so code placed in it can reach this address
+/// with relative branches and relocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlacementHint {
+    /// The address to place memory close to
+    pub addr: usize,
+    /// The maximum distance between `addr` and any byte of the memory
+    pub distance: usize,
+}
+
+impl PlacementHint {
+    /// Request memory within `distance` bytes of `addr`.
+    pub fn new(addr: usize, distance: usize) -> PlacementHint {
+        PlacementHint {
+            addr,
+            distance
+        }
+    }
+
+    /// Request memory that can reach `addr` with 32-bit relative displacements, like x64 `call` and `jmp`.
+    pub fn near(addr: usize) -> PlacementHint {
+        PlacementHint::new(addr, i32::MAX as usize)
+    }
+
+    /// Request memory that can reach the code of this program with 32-bit relative displacements.
+    /// This uses the address of a function in this crate, so it is only meaningful if this crate is
+    /// linked statically, which is the default.
+    pub fn near_text() -> PlacementHint {
+        PlacementHint::near(PlacementHint::near_text as *const () as usize)
+    }
+
+    /// Returns if all of the memory at `addr .. addr + len` lies within the requested distance.
+    pub fn contains(&self, addr: usize, len: usize) -> bool {
+        len != 0 && addr >= self.addr.saturating_sub(self.distance)
+            && addr.checked_add(len).is_some_and(|end| end - 1 <= self.addr.saturating_add(self.distance))
+    }
+}
+
+// The memory backing a buffer. As memmap cannot place mappings at a specific address, memory that was
+// placed close to a hint is mapped manually.
+#[derive(Debug)]
+enum Backing<M> {
+    Memmap(M),
+    #[cfg(unix)]
+    Placed(PlacedMap),
 }
 
 impl ExecutableBuffer {
@@ -36,7 +86,7 @@ impl ExecutableBuffer {
     /// buffer. Note that if this buffer is accessed through an Executor, these pointers
     /// will only be valid as long as its lock is held. When no locks are held,
     /// The assembler is free to relocate the executable buffer when it requires
-    /// more memory than available.
+    /// more memory than available, unless its memory was reserved up front.
     pub fn ptr(&self, offset: AssemblyOffset) -> *const u8 {
         &self[offset.0] as *const u8
     }
@@ -47,30 +97,144 @@ impl ExecutableBuffer {
         let buffer = if size == 0 {
             None
         } else {
-            Some(MmapMut::map_anon(size)?.make_exec()?)
+            Some(Backing::Memmap(MmapMut::map_anon(size)?.make_exec()?))
         };
 
         Ok(ExecutableBuffer {
-            length: 0,
+            length: AtomicUsize::new(0),
             buffer
         })
     }
 
+    /// Create a new executable buffer of size `size`, trying to place it according to `hint`. If that is not
+    /// possible, it is placed anywhere instead. Also returns if the buffer was placed according to `hint`.
+    pub fn new_near(size: usize, hint: PlacementHint) -> io::Result<(ExecutableBuffer, bool)> {
+        #[cfg(unix)]
+        {
+            if let Some(map) = map_near(size, hint, libc::PROT_READ | libc::PROT_EXEC, None) {
+                return Ok((ExecutableBuffer {
+                    length: AtomicUsize::new(0),
+                    buffer: Some(Backing::Placed(map))
+                }, true));
+            }
+        }
+
+        let buffer = ExecutableBuffer::new(size)?;
+        let placed = hint.contains(buffer.base_addr(), size);
+        Ok((buffer, placed))
+    }
+
+    /// Create a new executable buffer, backed by shared memory of size `size`, together with a
+    /// writable alias of this memory that is mapped at a different address.
+    /// Data written through the alias becomes visible in the executable buffer
+    /// without ever requiring its protection to be changed.
+    /// If `hint` is given, the executable mapping is placed according to it if possible.
+    pub(crate) fn new_dual_mapped(size: usize, hint: Option<PlacementHint>) -> io::Result<(ExecutableBuffer, Option<MmapMut>)> {
+        if size == 0 {
+            return Ok((ExecutableBuffer::default(), None));
+        }
+
+        let file = shared_memory_file(size)?;
+        let alias = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            if let Some(hint) = hint {
+                if let Some(map) = map_near(size, hint, libc::PROT_READ | libc::PROT_EXEC, Some(file.as_raw_fd())) {
+                    return Ok((ExecutableBuffer {
+                        length: AtomicUsize::new(0),
+                        buffer: Some(Backing::Placed(map))
+                    }, Some(alias)));
+                }
+            }
+        }
+
+        let buffer = unsafe { MmapOptions::new().len(size).map_exec(&file)? };
+
+        Ok((ExecutableBuffer {
+            length: AtomicUsize::new(0),
+            buffer: Some(Backing::Memmap(buffer))
+        }, Some(alias)))
+    }
+
+    /// Returns the address at which the backing buffer of this executable buffer starts.
+    pub(crate) fn base_addr(&self) -> usize {
+        self.buffer.as_ref().map(|b| b.as_ptr() as usize).unwrap_or(0)
+    }
+
+    /// Publish `length` bytes of this buffer as initialized. This is only meant to be used
+    /// when data was written to the buffer through an alias, as otherwise no shared reference
+    /// could observe the change.
+    pub(crate) fn set_len(&self, length: usize) {
+        self.length.store(length, Ordering::Release)
+    }
+
     /// Query the backing size of this executable buffer
     pub fn size(&self) -> usize {
         self.buffer.as_ref().map(|b| b.len()).unwrap_or(0) as usize
     }
 
+    /// Copy `data` into this buffer at `offset`, and extend the initialized length of this buffer to cover it.
+    /// Only the pages touched by `data` are temporarily made writable while this happens, so the cost of
+    /// this operation does not depend on the size of the buffer.
+    /// `offset + data.len()` should not be larger than the allocated size, otherwise this method panics.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len();
+        assert!(end <= self.size(), "Write past the end of an executable buffer");
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        {
+            let base = self.base_addr();
+            let page_size = page_size();
+            let start_page = offset - offset % page_size;
+            let end_page = match end % page_size {
+                0 => end,
+                misalign => end + page_size - misalign
+            };
+            let end_page = end_page.min(self.size());
+
+            protect(base + start_page, end_page - start_page, true)?;
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), (base + offset) as *mut u8, data.len());
+            }
+            protect(base + start_page, end_page - start_page, false)?;
+        }
+
+        #[cfg(not(unix))]
+        {
+            let length = self.length.load(Ordering::Relaxed);
+            let mut buffer = std::mem::replace(self, ExecutableBuffer::default()).make_mut()?;
+            buffer.set_len(end);
+            buffer[offset .. end].copy_from_slice(data);
+            buffer.set_len(length);
+            *self = buffer.make_exec()?;
+        }
+
+        if end > self.length.load(Ordering::Relaxed) {
+            self.set_len(end);
+        }
+        Ok(())
+    }
+
     /// Change this executable buffer into a mutable buffer.
     pub fn make_mut(self) -> io::Result<MutableBuffer> {
-        let buffer = if let Some(map) = self.buffer {
-            Some(map.make_mut()?)
-        } else {
-            None
+        let buffer = match self.buffer {
+            Some(Backing::Memmap(map)) => Some(Backing::Memmap(map.make_mut()?)),
+            #[cfg(unix)]
+            Some(Backing::Placed(map)) => {
+                protect(map.addr, map.len, true)?;
+                Some(Backing::Placed(map))
+            },
+            None => None
         };
 
         Ok(MutableBuffer {
-            length: self.length,
+            length: self.length.into_inner(),
             buffer
         })
     }
@@ -83,7 +247,7 @@ impl MutableBuffer {
         let buffer = if size == 0 {
             None
         } else {
-            Some(MmapMut::map_anon(size)?)
+            Some(Backing::Memmap(MmapMut::map_anon(size)?))
         };
 
         Ok(MutableBuffer {
@@ -92,6 +256,24 @@ impl MutableBuffer {
         })
     }
 
+    /// Create a new mutable buffer of size `size`, trying to place it according to `hint`. If that is not
+    /// possible, it is placed anywhere instead. Also returns if the buffer was placed according to `hint`.
+    pub fn new_near(size: usize, hint: PlacementHint) -> io::Result<(MutableBuffer, bool)> {
+        #[cfg(unix)]
+        {
+            if let Some(map) = map_near(size, hint, libc::PROT_READ | libc::PROT_WRITE, None) {
+                return Ok((MutableBuffer {
+                    length: 0,
+                    buffer: Some(Backing::Placed(map))
+                }, true));
+            }
+        }
+
+        let buffer = MutableBuffer::new(size)?;
+        let placed = hint.contains(buffer.as_ptr() as usize, size);
+        Ok((buffer, placed))
+    }
+
     /// Query the backing size of this mutable buffer
     pub fn size(&self) -> usize {
         self.buffer.as_ref().map(|b| b.len()).unwrap_or(0) as usize
@@ -105,14 +287,18 @@ impl MutableBuffer {
 
     /// Change this mutable buffer into an executable buffer.
     pub fn make_exec(self) -> io::Result<ExecutableBuffer> {
-        let buffer = if let Some(map) = self.buffer {
-            Some(map.make_exec()?)
-        } else {
-            None
+        let buffer = match self.buffer {
+            Some(Backing::Memmap(map)) => Some(Backing::Memmap(map.make_exec()?)),
+            #[cfg(unix)]
+            Some(Backing::Placed(map)) => {
+                protect(map.addr, map.len, false)?;
+                Some(Backing::Placed(map))
+            },
+            None => None
         };
 
         Ok(ExecutableBuffer {
-            length: self.length,
+            length: AtomicUsize::new(self.length),
             buffer
         })
     }
@@ -121,7 +307,7 @@ impl MutableBuffer {
 impl Default for ExecutableBuffer {
     fn default() -> ExecutableBuffer {
         ExecutableBuffer {
-            length: 0,
+            length: AtomicUsize::new(0),
             buffer: None
         }
     }
@@ -136,11 +322,32 @@ impl Default for MutableBuffer {
     }
 }
 
+impl<M: Deref<Target=[u8]>> Deref for Backing<M> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Backing::Memmap(map) => map,
+            #[cfg(unix)]
+            Backing::Placed(map) => unsafe { std::slice::from_raw_parts(map.addr as *const u8, map.len) }
+        }
+    }
+}
+
+impl DerefMut for Backing<MmapMut> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Backing::Memmap(map) => map,
+            #[cfg(unix)]
+            Backing::Placed(map) => unsafe { std::slice::from_raw_parts_mut(map.addr as *mut u8, map.len) }
+        }
+    }
+}
+
 impl Deref for ExecutableBuffer {
     type Target = [u8];
     fn deref(&self) -> &[u8] {
         if let Some(map) = &self.buffer {
-            &map[..self.length]
+            &map[..self.length.load(Ordering::Acquire)]
         } else {
             &[]
         }
@@ -167,3 +374,136 @@ impl DerefMut for MutableBuffer {
         }
     }
 }
+
+// Create an anonymous file of size `size` backed by shared memory.
+#[cfg(target_os = "linux")]
+fn shared_memory_file(size: usize) -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::memfd_create(b"dynasmrt\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_memory_file(_size: usize) -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Other, "Dual mapped buffers are not supported on this platform"))
+}
+
+// Returns the page size of the system
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Change the protection of the pages in the range `addr .. addr + len` to either RW or RX.
+#[cfg(unix)]
+fn protect(addr: usize, len: usize, writable: bool) -> io::Result<()> {
+    let prot = if writable {
+        libc::PROT_READ | libc::PROT_WRITE
+    } else {
+        libc::PROT_READ | libc::PROT_EXEC
+    };
+
+    if unsafe { libc::mprotect(addr as *mut libc::c_void, len, prot) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Change the protection of the pages in the range `addr .. addr + len`, which hold data that is not executed,
+// to either R or RW.
+#[cfg(unix)]
+pub(crate) fn protect_data(addr: usize, len: usize, writable: bool) -> io::Result<()> {
+    let prot = if writable {
+        libc::PROT_READ | libc::PROT_WRITE
+    } else {
+        libc::PROT_READ
+    };
+
+    if unsafe { libc::mprotect(addr as *mut libc::c_void, len, prot) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn protect_data(_addr: usize, _len: usize, _writable: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Data sections are not supported on this platform"))
+}
+
+// An anonymous or shared mapping that was placed manually.
+#[cfg(unix)]
+#[derive(Debug)]
+struct PlacedMap {
+    addr: usize,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Drop for PlacedMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+// The amount of addresses tried on either side of a placement hint.
+#[cfg(unix)]
+const PLACEMENT_ATTEMPTS: usize = 64;
+
+// Try to map `len` bytes with protection `prot` according to `hint`. The mapping is anonymous, or of the shared
+// file `fd`. As the address given to mmap is only a hint to the kernel, which is free to ignore it when the
+// address is in use, this tries addresses spread over the requested range, starting closest to the hint. Where
+// the kernel supports it, mappings are never placed elsewhere, so these do not have to be undone. Returns `None`
+// if no attempt succeeded, leaving it to the caller to map the memory anywhere instead.
+#[cfg(unix)]
+fn map_near(len: usize, hint: PlacementHint, prot: libc::c_int, fd: Option<libc::c_int>) -> Option<PlacedMap> {
+    if len == 0 {
+        return None;
+    }
+
+    let page_size = page_size();
+    let flags = match fd {
+        Some(_) => libc::MAP_SHARED,
+        None => libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+    };
+    // kernels before Linux 4.17 ignore this flag, and treat the address as a hint
+    #[cfg(target_os = "linux")]
+    let flags = flags | libc::MAP_FIXED_NOREPLACE;
+    let step = (hint.distance / PLACEMENT_ATTEMPTS).max(page_size);
+    let step = step - step % page_size;
+    let start = hint.addr - hint.addr % page_size;
+
+    for i in 0 .. PLACEMENT_ATTEMPTS {
+        let above = start.checked_add(i * step);
+        let below = start.checked_sub(i * step + len);
+        for addr in above.into_iter().chain(below) {
+            let addr = addr - addr % page_size;
+            if !hint.contains(addr, len) {
+                continue;
+            }
+
+            let mapped = unsafe { libc::mmap(addr as *mut libc::c_void, len, prot, flags, fd.unwrap_or(-1), 0) };
+            if mapped == libc::MAP_FAILED {
+                continue;
+            }
+
+            let map = PlacedMap {
+                addr: mapped as usize,
+                len
+            };
+            if hint.contains(map.addr, len) {
+                return Some(map);
+            }
+        }
+    }
+
+    None
+}
@@ -0,0 +1,398 @@
+//! This module implements relocatable code modules: assembled code that can be stored, and later placed into
+//! an `Assembler` at a different address without assembling it again.
+//!
+//! A `CodeModule` is created with `VecAssembler::finalize_module`. It contains the assembled code, the global and
+//! dynamic labels defined in it, all relocations whose value depends on the address the code is placed at,
+//! and references to global labels that were not defined in the module. It can be serialized with
+//! `CodeModule::to_bytes`, and deserialized with `CodeModule::from_bytes` as long as the relocation type matches.
+//! `Assembler::load_module` then places the module in an assembler and re-applies its relocations.
+
+use crate::AssemblyOffset;
+use crate::components::PatchLoc;
+use crate::relocations::{Relocation, RelocationKind, RelocationSize};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use std::error;
+use std::fmt;
+
+const MAGIC: &[u8; 8] = b"DYNASMOD";
+
+/// The version of the serialized module format. Version 2 stores the kind of x64 relocations, so modules written
+/// in version 1 are rejected with `ModuleError::UnsupportedVersion` and have to be assembled again.
+pub const MODULE_VERSION: u32 = 2;
+
+
+/// A relocation type that can be stored in a serialized `CodeModule`.
+pub trait ModuleRelocation: Relocation + Sized {
+    /// A name identifying this relocation type. Modules are only loaded with the relocation type they were built for.
+    const NAME: &'static str;
+    /// Append a serialized representation of this relocation to `buf`.
+    fn write_to(&self, buf: &mut Vec<u8>);
+    /// Read a relocation back from its serialized representation. Returns `None` if `buf` is not a valid
+    /// serialized relocation.
+    fn read_from(buf: &[u8]) -> Option<Self>;
+}
+
+impl ModuleRelocation for RelocationSize {
+    const NAME: &'static str = "size";
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+    fn read_from(buf: &[u8]) -> Option<Self> {
+        match *buf {
+            [size] => read_size(size),
+            _ => None
+        }
+    }
+}
+
+/// Helper to read a serialized `RelocationSize`.
+pub(crate) fn read_size(size: u8) -> Option<RelocationSize> {
+    match size {
+        1 => Some(RelocationSize::Byte),
+        2 => Some(RelocationSize::Word),
+        4 => Some(RelocationSize::DWord),
+        8 => Some(RelocationSize::QWord),
+        _ => None
+    }
+}
+
+/// Helper to read a serialized `RelocationKind`.
+pub(crate) fn read_kind(kind: u8) -> Option<RelocationKind> {
+    match kind {
+        0 => Some(RelocationKind::Relative),
+        1 => Some(RelocationKind::AbsToRel),
+        2 => Some(RelocationKind::RelToAbs),
+        _ => None
+    }
+}
+
+
+/// The various errors that can occur when reading a serialized `CodeModule`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    /// The data is not a valid serialized module
+    InvalidFormat,
+    /// The module was serialized with an unsupported version of the format
+    UnsupportedVersion(u32),
+    /// The module was built for a different relocation type
+    RelocationMismatch(String),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::InvalidFormat => write!(f, "Invalid code module"),
+            ModuleError::UnsupportedVersion(v) => write!(f, "Unsupported code module version: {}", v),
+            ModuleError::RelocationMismatch(r) => write!(f, "Code module was built for relocation type '{}'", r),
+        }
+    }
+}
+
+impl error::Error for ModuleError {
+    fn description(&self) -> &str {
+        match self {
+            ModuleError::InvalidFormat => "Invalid code module",
+            ModuleError::UnsupportedVersion(_) => "Unsupported code module version",
+            ModuleError::RelocationMismatch(_) => "Code module was built for a different relocation type",
+        }
+    }
+}
+
+
+/// A relocatable piece of assembled code. See the module level documentation for more information.
+#[derive(Debug, Clone)]
+pub struct CodeModule<R: Relocation> {
+    pub(crate) code: Vec<u8>,
+    // global labels defined in this module, and their offsets
+    pub(crate) globals: Vec<(String, usize)>,
+    // offsets of the dynamic labels of this module, indexed by their id
+    pub(crate) dynamics: Vec<Option<usize>>,
+    // resolved relocations that depend on the address of the module, and their targets.
+    // targets are offsets in the module, except for RelToAbs relocations, where they are absolute addresses.
+    pub(crate) relocations: Vec<(PatchLoc<R>, usize)>,
+    // relocations targeting global labels that are not defined in this module
+    pub(crate) externs: Vec<(PatchLoc<R>, String)>,
+}
+
+impl<R: Relocation> CodeModule<R> {
+    /// Returns the assembled code in this module, with relocations applied as if it was placed at the base
+    /// address of the `VecAssembler` it was assembled with.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Iterate through the global labels defined in this module, and their offsets in the module.
+    pub fn globals<'a>(&'a self) -> impl Iterator<Item=(&'a str, AssemblyOffset)> + 'a {
+        self.globals.iter().map(|(name, offset)| (name.as_str(), AssemblyOffset(*offset)))
+    }
+
+    /// Iterate through the global labels this module refers to that have to be defined when loading it.
+    pub fn externs<'a>(&'a self) -> impl Iterator<Item=&'a str> + 'a {
+        self.externs.iter().map(|(_, name)| name.as_str())
+    }
+}
+
+impl<R: ModuleRelocation> CodeModule<R> {
+    /// Serialize this module.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.write_u32::<LittleEndian>(MODULE_VERSION).unwrap();
+        write_bytes(&mut buf, R::NAME.as_bytes());
+        write_bytes(&mut buf, &self.code);
+
+        buf.write_u32::<LittleEndian>(self.globals.len() as u32).unwrap();
+        for (name, offset) in &self.globals {
+            write_bytes(&mut buf, name.as_bytes());
+            buf.write_u64::<LittleEndian>(*offset as u64).unwrap();
+        }
+
+        buf.write_u32::<LittleEndian>(self.dynamics.len() as u32).unwrap();
+        for offset in &self.dynamics {
+            match offset {
+                Some(offset) => {
+                    buf.push(1);
+                    buf.write_u64::<LittleEndian>(*offset as u64).unwrap();
+                },
+                None => buf.push(0)
+            }
+        }
+
+        buf.write_u32::<LittleEndian>(self.relocations.len() as u32).unwrap();
+        for (loc, target) in &self.relocations {
+            write_patchloc(&mut buf, loc);
+            buf.write_u64::<LittleEndian>(*target as u64).unwrap();
+        }
+
+        buf.write_u32::<LittleEndian>(self.externs.len() as u32).unwrap();
+        for (loc, name) in &self.externs {
+            write_patchloc(&mut buf, loc);
+            write_bytes(&mut buf, name.as_bytes());
+        }
+
+        buf
+    }
+
+    /// Deserialize a module. This fails if the module was serialized by an incompatible version of this crate,
+    /// or for a different relocation type.
+    pub fn from_bytes(data: &[u8]) -> Result<CodeModule<R>, ModuleError> {
+        let mut reader = Reader { data };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ModuleError::InvalidFormat);
+        }
+        let version = reader.u32()?;
+        if version != MODULE_VERSION {
+            return Err(ModuleError::UnsupportedVersion(version));
+        }
+        let name = reader.string()?;
+        if name != R::NAME {
+            return Err(ModuleError::RelocationMismatch(name));
+        }
+
+        let code = reader.bytes()?.to_vec();
+
+        let mut globals = Vec::new();
+        for _ in 0 .. reader.u32()? {
+            let name = reader.string()?;
+            globals.push((name, reader.offset(code.len())?));
+        }
+
+        let mut dynamics = Vec::new();
+        for _ in 0 .. reader.u32()? {
+            dynamics.push(match reader.take(1)?[0] {
+                0 => None,
+                1 => Some(reader.offset(code.len())?),
+                _ => return Err(ModuleError::InvalidFormat)
+            });
+        }
+
+        let mut relocations = Vec::new();
+        for _ in 0 .. reader.u32()? {
+            let loc = reader.patchloc(code.len())?;
+            let target = reader.u64()? as usize;
+            relocations.push((loc, target));
+        }
+
+        let mut externs = Vec::new();
+        for _ in 0 .. reader.u32()? {
+            let loc = reader.patchloc(code.len())?;
+            externs.push((loc, reader.string()?));
+        }
+
+        if !reader.data.is_empty() {
+            return Err(ModuleError::InvalidFormat);
+        }
+
+        Ok(CodeModule {
+            code,
+            globals,
+            dynamics,
+            relocations,
+            externs
+        })
+    }
+}
+
+
+/// Information about a module that was placed in an `Assembler`.
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    /// The offset the module was placed at
+    pub offset: AssemblyOffset,
+    /// The dynamic labels of the module, as defined in the assembler. These are indexed by the id
+    /// the label had when the module was assembled.
+    pub dynamic_labels: Vec<crate::DynamicLabel>,
+}
+
+
+fn write_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+    buf.extend_from_slice(data);
+}
+
+fn write_patchloc<R: ModuleRelocation>(buf: &mut Vec<u8>, loc: &PatchLoc<R>) {
+    buf.write_u64::<LittleEndian>(loc.location.0 as u64).unwrap();
+    buf.write_i64::<LittleEndian>(loc.offset as i64).unwrap();
+    let mut relocation = Vec::new();
+    loc.relocation.write_to(&mut relocation);
+    write_bytes(buf, &relocation);
+}
+
+struct Reader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ModuleError> {
+        if self.data.len() < len {
+            return Err(ModuleError::InvalidFormat);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, ModuleError> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, ModuleError> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    // read an offset into code of size `len`
+    fn offset(&mut self, len: usize) -> Result<usize, ModuleError> {
+        let offset = self.u64()?;
+        if offset > len as u64 {
+            return Err(ModuleError::InvalidFormat);
+        }
+        Ok(offset as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], ModuleError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, ModuleError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| ModuleError::InvalidFormat)
+    }
+
+    // read a relocation located in code of size `len`
+    fn patchloc<R: ModuleRelocation>(&mut self, len: usize) -> Result<PatchLoc<R>, ModuleError> {
+        let location = self.offset(len)?;
+        let offset = self.u64()? as i64 as isize;
+        let relocation = R::read_from(self.bytes()?).ok_or(ModuleError::InvalidFormat)?;
+        if location < relocation.field_offset() || location - relocation.field_offset() + relocation.size() > len {
+            return Err(ModuleError::InvalidFormat);
+        }
+        Ok(PatchLoc::new(AssemblyOffset(location), offset, relocation))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, VecAssembler, DynasmApi, DynasmLabelApi, DynasmError, TargetKind};
+    use crate::x64::X64Relocation;
+    use crate::x86::X86Relocation;
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_code_module() {
+        let mut ops = VecAssembler::<X64Relocation>::new(0x1000);
+        let helper = ops.new_dynamic_label();
+        ops.global_label("module_entry");
+        ops.extend(&[0xE8, 0, 0, 0, 0]); // call =>helper
+        ops.dynamic_reloc(helper, 0, (0, 4));
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->module_external
+        ops.global_reloc("module_external", 0, (0, 4));
+        ops.dynamic_label(helper);
+        ops.extend(&[0xB8, 40, 0, 0, 0, 0xC3]); // mov eax, 40; ret
+        let module = ops.finalize_module().unwrap();
+        assert_eq!(module.externs().collect::<Vec<_>>(), vec!["module_external"]);
+
+        let bytes = module.to_bytes();
+        let module = CodeModule::<X64Relocation>::from_bytes(&bytes).unwrap();
+
+        let mut ops = Assembler::<X64Relocation>::new().unwrap();
+        ops.global_label("module_external");
+        ops.extend(&[0x83, 0xC0, 0x02, 0xC3]); // add eax, 2; ret
+        let loaded = ops.load_module(module).unwrap();
+        assert_eq!(loaded.offset, AssemblyOffset(4));
+        assert_eq!(ops.labels().resolve_dynamic(loaded.dynamic_labels[0]).unwrap(), AssemblyOffset(14));
+        ops.commit().unwrap();
+
+        let start = ops.labels().resolve_global("module_entry").unwrap();
+        let buf = ops.finalize().unwrap();
+        let f: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(start)) };
+        assert_eq!(f(), 42);
+
+        // relocations that depend on the address of the code are applied again when loading
+        let mut ops = VecAssembler::<X86Relocation>::new(0x1000);
+        let data = ops.new_dynamic_label();
+        ops.extend(&[0; 8]);
+        ops.dynamic_reloc(data, 0, (0, 8, 1)); // absolute address of =>data
+        ops.extend(&[0; 8]);
+        ops.bare_reloc(0x1234_5678, (0, 8, 2)); // relative address of 0x12345678
+        ops.dynamic_label(data);
+        let bytes = ops.finalize_module().unwrap().to_bytes();
+        let module = CodeModule::<X86Relocation>::from_bytes(&bytes).unwrap();
+
+        let mut ops = Assembler::<X86Relocation>::new().unwrap();
+        ops.extend(&[0; 4]);
+        ops.load_module(module).unwrap();
+        ops.commit().unwrap();
+        let buf = ops.finalize().unwrap();
+        let addr = buf.ptr(AssemblyOffset(0)) as usize;
+        assert_eq!(RelocationSize::QWord.read_value(&buf[4 .. 12]) as usize, addr + 20);
+        assert_eq!(RelocationSize::QWord.read_value(&buf[12 .. 20]) as usize, 0x1234_5678usize.wrapping_sub(addr + 20));
+
+        // loading a module whose relocations cannot be applied leaves nothing behind
+        let mut failing = VecAssembler::<X86Relocation>::new(0x1000);
+        failing.local_label("start");
+        failing.extend(&[0; 8]);
+        failing.backward_reloc("start", 0, (0, 8, 1)); // absolute address of <start
+        failing.extend(&[0; 4]);
+        failing.bare_reloc(0x1234_5678, (0, 4, 2)); // relative address of 0x12345678, out of range of the buffer
+        let failing = CodeModule::<X86Relocation>::from_bytes(&failing.finalize_module().unwrap().to_bytes()).unwrap();
+
+        let mut ops = Assembler::<X86Relocation>::new().unwrap();
+        assert_eq!(ops.load_module(failing).unwrap_err(), DynasmError::ImpossibleRelocation(TargetKind::Managed));
+        assert_eq!(ops.offset(), AssemblyOffset(0));
+        assert_eq!(ops.managed.iter().count(), 0);
+
+        // modules are rejected when built for a different relocation type or format version
+        assert_eq!(CodeModule::<X64Relocation>::from_bytes(&bytes).unwrap_err(), ModuleError::RelocationMismatch("x86".to_string()));
+        let mut modified = bytes.clone();
+        modified[8] = 3;
+        assert_eq!(CodeModule::<X86Relocation>::from_bytes(&modified).unwrap_err(), ModuleError::UnsupportedVersion(3));
+        let mut modified = bytes.clone();
+        modified.push(0);
+        assert_eq!(CodeModule::<X86Relocation>::from_bytes(&modified).unwrap_err(), ModuleError::InvalidFormat);
+    }
+}
@@ -0,0 +1,322 @@
+//! This module implements support for profiling assembled code with linux `perf`.
+//!
+//! A `PerfRecorder` can write the names and locations of assembled functions to a perf map file
+//! (`/tmp/perf-<pid>.map`), and to a jitdump file (`jit-<pid>.dump`) which also contains the code itself.
+//! Perf map files are picked up by `perf report` automatically. Jitdump files have to be merged into
+//! the recorded profile with `perf inject --jit`, and require the profile to be recorded with
+//! `perf record -k mono`.
+//! Additionally, the recorder can register each symbol with debuggers through the GDB JIT interface, if the `gdb-jit`
+//! feature is enabled.
+//!
+//! A recorder can be attached to an `Assembler` with `Assembler::set_perf_recorder`, which then records a
+//! symbol for every global and dynamic label defined afterwards, as well as any range named
+//! with `Assembler::define_symbol`. Symbols are recorded when the code they contain is committed,
+//! and are recorded again at their new location whenever the assembler moves its executable buffer.
+
+#[cfg(feature = "gdb-jit")]
+use crate::gdb::GdbJitEntry;
+
+use byteorder::{NativeEndian, WriteBytesExt};
+use memmap::{Mmap, MmapOptions};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const JIT_CODE_LOAD: u32 = 0;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u32 = 62;
+#[cfg(target_arch = "x86")]
+const ELF_MACHINE: u32 = 3;
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u32 = 183;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+const ELF_MACHINE: u32 = 0;
+
+
+/// Records the names and locations of assembled code in the formats understood by linux `perf`.
+#[derive(Debug, Default)]
+pub struct PerfRecorder {
+    // the perf map file, if enabled
+    map: Option<File>,
+    // the jitdump file, if enabled
+    dump: Option<JitDump>,
+    // the symbols registered with the GDB JIT interface, if enabled
+    #[cfg(feature = "gdb-jit")]
+    gdb: Option<Vec<GdbJitEntry>>,
+
+    // starts of symbols defined by labels in uncommitted code
+    labels: Vec<(String, usize)>,
+    // explicitly named ranges that have not been committed yet
+    pending: Vec<(String, usize, usize)>,
+    // all recorded symbols, so they can be recorded again when the code is moved
+    recorded: Vec<(String, usize, usize)>,
+    // the first error encountered while recording committed symbols, if any
+    error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+struct JitDump {
+    file: File,
+    // perf discovers jitdump files by looking for executable mappings of them
+    _marker: Mmap,
+    code_index: u64,
+}
+
+impl PerfRecorder {
+    /// Create a new `PerfRecorder` that does not write anything yet.
+    pub fn new() -> PerfRecorder {
+        PerfRecorder::default()
+    }
+
+    /// Start writing recorded symbols to `/tmp/perf-<pid>.map`.
+    pub fn enable_perf_map(&mut self) -> io::Result<()> {
+        let path = format!("/tmp/perf-{}.map", process::id());
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.map = Some(file);
+        Ok(())
+    }
+
+    /// Start writing recorded symbols, including their code, to `jit-<pid>.dump` inside `directory`.
+    pub fn enable_jitdump<P: AsRef<Path>>(&mut self, directory: P) -> io::Result<()> {
+        let path = directory.as_ref().join(format!("jit-{}.dump", process::id()));
+        let mut file = OpenOptions::new().create(true).truncate(true).read(true).write(true).open(path)?;
+
+        let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+        header.write_u32::<NativeEndian>(JITDUMP_MAGIC)?;
+        header.write_u32::<NativeEndian>(JITDUMP_VERSION)?;
+        header.write_u32::<NativeEndian>(JITDUMP_HEADER_SIZE)?;
+        header.write_u32::<NativeEndian>(ELF_MACHINE)?;
+        header.write_u32::<NativeEndian>(0)?;
+        header.write_u32::<NativeEndian>(process::id())?;
+        header.write_u64::<NativeEndian>(timestamp())?;
+        header.write_u64::<NativeEndian>(0)?;
+        file.write_all(&header)?;
+
+        let marker = unsafe { MmapOptions::new().len(header.len()).map_exec(&file)? };
+        self.dump = Some(JitDump {
+            file,
+            _marker: marker,
+            code_index: 0
+        });
+        Ok(())
+    }
+
+    /// Start registering recorded symbols with the GDB JIT interface. Symbols stay registered
+    /// for as long as this recorder lives.
+    #[cfg(feature = "gdb-jit")]
+    pub fn enable_gdb_jit(&mut self) {
+        if self.gdb.is_none() {
+            self.gdb = Some(Vec::new());
+        }
+    }
+
+    /// Record that the function `name` is located at `addr`, and consists of `code`.
+    pub fn record(&mut self, name: &str, addr: usize, code: &[u8]) -> io::Result<()> {
+        if let Some(map) = &mut self.map {
+            writeln!(map, "{:x} {:x} {}", addr, code.len(), name)?;
+        }
+
+        if let Some(dump) = &mut self.dump {
+            let total_size = 16 + 40 + name.len() + 1 + code.len();
+
+            let mut record = Vec::with_capacity(total_size);
+            record.write_u32::<NativeEndian>(JIT_CODE_LOAD)?;
+            record.write_u32::<NativeEndian>(total_size as u32)?;
+            record.write_u64::<NativeEndian>(timestamp())?;
+            record.write_u32::<NativeEndian>(process::id())?;
+            record.write_u32::<NativeEndian>(thread_id())?;
+            record.write_u64::<NativeEndian>(addr as u64)?;
+            record.write_u64::<NativeEndian>(addr as u64)?;
+            record.write_u64::<NativeEndian>(code.len() as u64)?;
+            record.write_u64::<NativeEndian>(dump.code_index)?;
+            record.extend_from_slice(name.as_bytes());
+            record.push(0);
+            record.extend_from_slice(code);
+
+            dump.file.write_all(&record)?;
+            dump.code_index += 1;
+        }
+
+        #[cfg(feature = "gdb-jit")]
+        if let Some(gdb) = &mut self.gdb {
+            gdb.push(GdbJitEntry::register(&[(name, addr, code.len())]));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the first error encountered while recording the symbols of committed code since this was last
+    /// called, if any. Symbols that could not be written are not written again.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Record a symbol starting at `offset`, which extends up to the next label symbol or the end
+    /// of the committed code.
+    pub(crate) fn label(&mut self, name: String, offset: usize) {
+        self.labels.push((name, offset));
+    }
+
+    /// Record a symbol spanning from `start` up to `end` once it has been committed.
+    pub(crate) fn range(&mut self, name: String, start: usize, end: usize) {
+        self.pending.push((name, start, end));
+    }
+
+    /// Forget all uncommitted symbols starting at offsets for which `f` returns true.
+    pub(crate) fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.labels.retain(|&(_, start)| !f(start));
+        self.pending.retain(|&(_, start, _)| !f(start));
+    }
+
+    /// Move all uncommitted symbols to the offsets returned by `f` for their current offsets.
+    pub(crate) fn relocate<F: Fn(usize) -> usize>(&mut self, f: F) {
+        for (_, offset) in &mut self.labels {
+            *offset = f(*offset);
+        }
+        for (_, start, end) in &mut self.pending {
+            *start = f(*start);
+            *end = f(*end);
+        }
+    }
+
+    /// Record all symbols that have been committed in `buffer`, located at `addr`. If `moved` is set,
+    /// all previously recorded symbols are recorded again at their new location. As failing to record symbols
+    /// does not affect the code itself, errors are kept until they are taken with `take_error`.
+    pub(crate) fn commit(&mut self, buffer: &[u8], addr: usize, moved: bool) {
+        if let Err(e) = self.record_committed(buffer, addr, moved) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn record_committed(&mut self, buffer: &[u8], addr: usize, moved: bool) -> io::Result<()> {
+        let previous = if moved { 0 } else { self.recorded.len() };
+        let committed = buffer.len();
+
+        // symbols registered at the old location of the code are no longer valid
+        #[cfg(feature = "gdb-jit")]
+        if moved {
+            if let Some(gdb) = &mut self.gdb {
+                gdb.clear();
+            }
+        }
+
+        // label symbols end where the next label symbol starts
+        let mut labels = std::mem::take(&mut self.labels);
+        labels.sort_by_key(|&(_, start)| start);
+        for (i, (name, start)) in labels.iter().enumerate() {
+            let end = labels[i + 1 ..].iter().map(|&(_, s)| s).find(|&s| s > *start).unwrap_or(committed);
+            self.recorded.push((name.clone(), *start, end));
+        }
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].2 <= committed {
+                let symbol = self.pending.remove(i);
+                self.recorded.push(symbol);
+            } else {
+                i += 1;
+            }
+        }
+
+        for i in previous .. self.recorded.len() {
+            let (name, start, end) = self.recorded[i].clone();
+            if start < end {
+                self.record(&name, addr + start, &buffer[start .. end])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+// timestamps have to be taken from the same clock perf uses when recording with `-k mono`
+#[cfg(unix)]
+fn timestamp() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[cfg(not(unix))]
+fn timestamp() -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u32 {
+    process::id()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, DynasmApi, DynasmLabelApi};
+    use crate::x64::X64Relocation;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_perf_recorder() {
+        let dir = std::env::temp_dir().join(format!("dynasmrt-perf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut recorder = PerfRecorder::new();
+        recorder.enable_perf_map().unwrap();
+        recorder.enable_jitdump(&dir).unwrap();
+
+        let mut ops = Assembler::<X64Relocation>::new().unwrap();
+        ops.set_perf_recorder(recorder);
+
+        ops.global_label("perf_test_first");
+        ops.extend(&[0x90; 4]);
+        let second = ops.new_dynamic_label();
+        ops.dynamic_label(second);
+        ops.extend(&[0xC3]);
+        let start = ops.offset();
+        ops.extend(&[0xCC; 3]);
+        ops.define_symbol("perf_test_range", start, ops.offset());
+        ops.commit().unwrap();
+
+        // moving the buffer records all symbols again
+        ops.extend(vec![0x90; 8192]);
+        ops.commit().unwrap();
+        assert!(ops.perf_recorder_mut().unwrap().take_error().is_none());
+
+        let map = std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+        let lines: Vec<_> = map.lines().filter(|l| l.ends_with(" perf_test_first")).collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.split(' ').nth(1) == Some("4")));
+        assert_eq!(map.lines().filter(|l| l.ends_with(&format!(" dynamic_label_{}", second.get_id()))).count(), 2);
+        assert_eq!(map.lines().filter(|l| l.ends_with(" perf_test_range")).count(), 2);
+
+        let dump = std::fs::read(dir.join(format!("jit-{}.dump", std::process::id()))).unwrap();
+        assert_eq!(&dump[..4], &0x4A69_5444u32.to_ne_bytes());
+        let mut records = 0;
+        let mut pos = 40;
+        while pos < dump.len() {
+            let mut size = [0; 4];
+            size.copy_from_slice(&dump[pos + 4 .. pos + 8]);
+            pos += u32::from_ne_bytes(size) as usize;
+            records += 1;
+        }
+        assert_eq!(pos, dump.len());
+        assert_eq!(records, 6);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+    }
+}
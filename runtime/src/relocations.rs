@@ -42,6 +42,47 @@ pub trait Relocation {
     fn kind(&self) -> RelocationKind;
     /// Specifies the default page size on this platform.
     fn page_size() -> usize;
+    /// The encoding of a no-op instruction, used to pad code that can be executed, like the padding around
+    /// patchpoints. Defaults to a single zero byte.
+    fn nop() -> &'static [u8] {
+        &[0]
+    }
+    /// The ELF machine type of this architecture, if relocatable ELF64 objects can be produced for it.
+    fn elf_machine() -> Option<u16> {
+        None
+    }
+    /// The ELF relocation type that computes the same value as this relocation, if there is one.
+    fn elf_relocation_type(&self) -> Option<u32> {
+        None
+    }
+    /// Used for branch relaxation. If this relocation belongs to a jump instruction that ends at the end of `code`,
+    /// and this instruction has a shorter encoding, returns this encoding.
+    fn relax(&self, _code: &[u8]) -> Option<RelaxedJump<Self>> where Self: Sized {
+        None
+    }
+    /// Used for branches to absolute addresses. If this relocation belongs to a branch instruction, returns the code of
+    /// a veneer that jumps to `target` from any address. Branches that cannot reach their target are routed through it.
+    /// Veneers are only used if `jump_over` is implemented as well.
+    fn veneer(&self, _target: usize) -> Option<Vec<u8>> {
+        None
+    }
+    /// Used to place veneers in the middle of code. Returns the code of an unconditional jump over the `length` bytes
+    /// that follow it, whose size does not depend on `length`.
+    fn jump_over(_length: usize) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+
+/// A shorter encoding of a jump instruction, as used by branch relaxation.
+#[derive(Debug, Clone)]
+pub struct RelaxedJump<R: Relocation> {
+    /// The length of the instruction that can be replaced
+    pub length: usize,
+    /// The shorter instruction, with its relocation field left zeroed
+    pub code: Vec<u8>,
+    /// The relocation of the shorter instruction
+    pub relocation: R,
 }
 
 
@@ -139,6 +180,21 @@ impl Relocation for RelocationSize {
     }
 }
 
+/// Append `length` bytes of `with` to `buffer`, or of no-op instructions of `R` if `with` is `None`. Any bytes that
+/// do not fit a whole instruction are zero and come first, so the instructions end at the alignment being padded to.
+pub(crate) fn pad<R: Relocation>(buffer: &mut Vec<u8>, length: usize, with: Option<u8>) {
+    match with {
+        Some(byte) => buffer.resize(buffer.len() + length, byte),
+        None => {
+            let nop = R::nop();
+            buffer.resize(buffer.len() + length % nop.len(), 0);
+            for _ in 0 .. length / nop.len() {
+                buffer.extend_from_slice(nop);
+            }
+        }
+    }
+}
+
 pub(crate) fn fits_signed_bitfield(value: i64, bits: u8) -> bool {
     if bits >= 64 {
         return true;
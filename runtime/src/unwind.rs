@@ -0,0 +1,688 @@
+//! This module implements the generation and registration of unwind information (`.eh_frame` data) for assembled code.
+//!
+//! The way a function changes the stack is described with a `CfiBuilder`, by recording directives at the
+//! `AssemblyOffset` right after the instruction they describe. Adding the builder to an `Assembler` with
+//! `Assembler::add_unwind_info` then makes the unwinder aware of the function once it has been committed.
+//! This allows panics and backtraces to unwind through assembled code.
+//!
+//! Registration with the unwinder is currently only supported on linux.
+//!
+//! ```ignore
+//! let mut cfi = CfiBuilder::new(UnwindArch::X64, ops.offset());
+//! dynasm!(ops
+//!     ; push rbp
+//! );
+//! cfi.push(ops.offset(), unwind::x64::RBP);
+//! dynasm!(ops
+//!     ; mov rbp, rsp
+//! );
+//! cfi.set_frame_pointer(ops.offset());
+//! ```
+//!
+//! A function whose code is split over multiple ranges, like one with slow paths in the cold section, records where
+//! it continues with `CfiBuilder::continue_at`. Each range is then described by its own FDE.
+
+use crate::AssemblyOffset;
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use byteorder::{NativeEndian, WriteBytesExt};
+
+/// DWARF register numbers for x64.
+pub mod x64 {
+    #![allow(missing_docs)]
+    pub const RAX: u16 = 0;
+    pub const RDX: u16 = 1;
+    pub const RCX: u16 = 2;
+    pub const RBX: u16 = 3;
+    pub const RSI: u16 = 4;
+    pub const RDI: u16 = 5;
+    pub const RBP: u16 = 6;
+    pub const RSP: u16 = 7;
+    pub const R8: u16 = 8;
+    pub const R9: u16 = 9;
+    pub const R10: u16 = 10;
+    pub const R11: u16 = 11;
+    pub const R12: u16 = 12;
+    pub const R13: u16 = 13;
+    pub const R14: u16 = 14;
+    pub const R15: u16 = 15;
+    /// The return address
+    pub const RA: u16 = 16;
+}
+
+/// DWARF register numbers for aarch64. General purpose register `xN` is register number `N`.
+pub mod aarch64 {
+    #![allow(missing_docs)]
+    pub const X19: u16 = 19;
+    pub const X20: u16 = 20;
+    pub const X21: u16 = 21;
+    pub const X22: u16 = 22;
+    pub const X23: u16 = 23;
+    pub const X24: u16 = 24;
+    pub const X25: u16 = 25;
+    pub const X26: u16 = 26;
+    pub const X27: u16 = 27;
+    pub const X28: u16 = 28;
+    /// The frame pointer
+    pub const X29: u16 = 29;
+    /// The link register
+    pub const X30: u16 = 30;
+    pub const SP: u16 = 31;
+}
+
+
+/// The architectures unwind information can be generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnwindArch {
+    /// x64, where the return address is pushed on the stack by `call`.
+    X64,
+    /// aarch64, where the return address is passed in `x30`.
+    Aarch64,
+}
+
+impl UnwindArch {
+    fn code_alignment(self) -> u32 {
+        match self {
+            UnwindArch::X64 => 1,
+            UnwindArch::Aarch64 => 4,
+        }
+    }
+
+    fn return_address(self) -> u16 {
+        match self {
+            UnwindArch::X64 => x64::RA,
+            UnwindArch::Aarch64 => aarch64::X30,
+        }
+    }
+
+    fn stack_pointer(self) -> u16 {
+        match self {
+            UnwindArch::X64 => x64::RSP,
+            UnwindArch::Aarch64 => aarch64::SP,
+        }
+    }
+
+    fn frame_pointer(self) -> u16 {
+        match self {
+            UnwindArch::X64 => x64::RBP,
+            UnwindArch::Aarch64 => aarch64::X29,
+        }
+    }
+
+    // the offset of the canonical frame address from the stack pointer at function entry
+    fn initial_cfa_offset(self) -> i32 {
+        match self {
+            UnwindArch::X64 => 8,
+            UnwindArch::Aarch64 => 0,
+        }
+    }
+}
+
+const DATA_ALIGNMENT: i32 = -8;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xC0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0D;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+
+
+// A single change to the unwind state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Directive {
+    DefCfa(u16, i32),
+    DefCfaRegister(u16),
+    DefCfaOffset(i32),
+    Offset(u16, i32),
+    Restore(u16),
+}
+
+
+/// Describes how a single function changes the stack, so the unwinder can find the caller's frame from any
+/// instruction in the function. All directives are recorded at the offset of the first instruction they apply to,
+/// and have to be recorded in order within each range of code the function occupies.
+#[derive(Debug, Clone)]
+pub struct CfiBuilder {
+    arch: UnwindArch,
+    start: AssemblyOffset,
+    directives: Vec<(AssemblyOffset, Directive)>,
+
+    // the ranges of code before the current one, with the number of directives recorded up to their end
+    ranges: Vec<(AssemblyOffset, AssemblyOffset, usize)>,
+    // the start of the current range of code
+    resumed: AssemblyOffset,
+
+    // the current state, so directives can be expressed relative to it
+    cfa_register: u16,
+    cfa_offset: i32,
+}
+
+impl CfiBuilder {
+    /// Create a new `CfiBuilder` for a function targeting `arch` that starts at `start`.
+    pub fn new(arch: UnwindArch, start: AssemblyOffset) -> CfiBuilder {
+        CfiBuilder {
+            arch,
+            start,
+            directives: Vec::new(),
+            ranges: Vec::new(),
+            resumed: start,
+            cfa_register: arch.stack_pointer(),
+            cfa_offset: arch.initial_cfa_offset(),
+        }
+    }
+
+    /// Returns the offset at which this function starts.
+    pub fn start(&self) -> AssemblyOffset {
+        self.start
+    }
+
+    /// Returns the architecture this function targets.
+    pub fn arch(&self) -> UnwindArch {
+        self.arch
+    }
+
+    /// Record that `register` was pushed on the stack, decrementing the stack pointer by 8 bytes, like `push` on x64.
+    pub fn push(&mut self, offset: AssemblyOffset, register: u16) {
+        self.adjust_stack(offset, 8);
+        let saved = -self.cfa_offset;
+        self.save_register(offset, register, saved);
+    }
+
+    /// Record that the stack pointer was decremented by `bytes`. This only changes the unwind state if
+    /// the stack pointer is still used to find the canonical frame address.
+    pub fn adjust_stack(&mut self, offset: AssemblyOffset, bytes: i32) {
+        if self.cfa_register == self.arch.stack_pointer() {
+            self.cfa_offset += bytes;
+            self.add(offset, Directive::DefCfaOffset(self.cfa_offset));
+        }
+    }
+
+    /// Record that the frame pointer (`rbp` on x64, `x29` on aarch64) was set to the current stack pointer. From now on the
+    /// canonical frame address is found relative to the frame pointer, so the stack pointer can be adjusted freely.
+    pub fn set_frame_pointer(&mut self, offset: AssemblyOffset) {
+        self.cfa_register = self.arch.frame_pointer();
+        self.add(offset, Directive::DefCfaRegister(self.cfa_register));
+    }
+
+    /// Record that `register` was saved at `cfa_offset` bytes from the canonical frame address, which is the value
+    /// of the stack pointer right before the function was called.
+    pub fn save_register(&mut self, offset: AssemblyOffset, register: u16, cfa_offset: i32) {
+        self.add(offset, Directive::Offset(register, cfa_offset));
+    }
+
+    /// Record that `register` was restored to the value it had on function entry.
+    pub fn restore_register(&mut self, offset: AssemblyOffset, register: u16) {
+        self.add(offset, Directive::Restore(register));
+    }
+
+    /// Record that the canonical frame address is now found at `cfa_offset` bytes from `register`. This is
+    /// mostly useful for describing epilogues, e.g. to return to using the stack pointer after the frame pointer was popped.
+    pub fn set_cfa(&mut self, offset: AssemblyOffset, register: u16, cfa_offset: i32) {
+        self.cfa_register = register;
+        self.cfa_offset = cfa_offset;
+        self.add(offset, Directive::DefCfa(register, cfa_offset));
+    }
+
+    /// Record that the code of this function up to `end` continues at `start`, like when switching between the hot
+    /// and the cold section. The unwind state at `start` is the one at `end`, and directives recorded from now on
+    /// have to lie at or after `start`.
+    pub fn continue_at(&mut self, end: AssemblyOffset, start: AssemblyOffset) {
+        self.check_order(end);
+        self.ranges.push((self.resumed, end, self.directives.len()));
+        self.resumed = start;
+    }
+
+    fn add(&mut self, offset: AssemblyOffset, directive: Directive) {
+        self.check_order(offset);
+        self.directives.push((offset, directive));
+    }
+
+    // check that `offset` does not lie before the start of the current range or the last directive recorded in it
+    fn check_order(&self, offset: AssemblyOffset) {
+        assert!(offset.0 >= self.resumed.0, "Unwind directives cannot be recorded before the start of the function");
+        let first = self.ranges.last().map_or(0, |&(_, _, count)| count);
+        if let Some(&(last, _)) = self.directives[first ..].last() {
+            assert!(offset.0 >= last.0, "Unwind directives have to be recorded in order");
+        }
+    }
+
+    /// Generate `.eh_frame` data describing this function, located at `addr` and spanning `size` bytes.
+    /// The data consists of a CIE followed by a single FDE, without a terminator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the function occupies multiple ranges of code, which `continue_at` was used for.
+    pub fn eh_frame(&self, addr: usize, size: usize) -> Vec<u8> {
+        assert!(self.ranges.is_empty(), "Functions split over multiple ranges need an FDE for each range");
+        let mut buf = self.cie();
+        self.write_fde(&mut buf, addr, self.start, size, 0 .. self.directives.len());
+        buf
+    }
+
+    /// Generate `.eh_frame` data describing this function in the code located at `addr`, if the function ends at
+    /// offset `end`. The data consists of a CIE followed by an FDE for each range of code the function occupies.
+    pub(crate) fn eh_frame_ranges(&self, addr: usize, end: AssemblyOffset) -> Vec<u8> {
+        let mut buf = self.cie();
+        let last = (self.resumed, end, self.directives.len());
+
+        let mut first = 0;
+        for &(start, end, count) in self.ranges.iter().chain(Some(&last)) {
+            self.write_fde(&mut buf, addr + start.0, start, end.0 - start.0, first .. count);
+            first = count;
+        }
+        buf
+    }
+
+    /// Returns the offset the code of this function ends at, if its last range of code ends at `end`.
+    pub(crate) fn end(&self, end: AssemblyOffset) -> AssemblyOffset {
+        self.ranges.iter().map(|&(_, end, _)| end).fold(end, |a, b| if b.0 > a.0 { b } else { a })
+    }
+
+    /// Move all offsets of this function to the offsets returned by `f` for them.
+    pub(crate) fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        self.start = f(self.start);
+        self.resumed = f(self.resumed);
+        for (offset, _) in &mut self.directives {
+            *offset = f(*offset);
+        }
+        for (start, end, _) in &mut self.ranges {
+            *start = f(*start);
+            *end = f(*end);
+        }
+    }
+
+    // the CIE shared by the FDEs of this function
+    fn cie(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        let mut cie = Vec::new();
+        cie.write_u32::<NativeEndian>(0).unwrap();
+        cie.push(1);
+        cie.extend_from_slice(b"zR\0");
+        write_uleb128(&mut cie, u64::from(self.arch.code_alignment()));
+        write_sleb128(&mut cie, i64::from(DATA_ALIGNMENT));
+        write_uleb128(&mut cie, u64::from(self.arch.return_address()));
+        write_uleb128(&mut cie, 1);
+        cie.push(DW_EH_PE_ABSPTR);
+        self.encode(&mut cie, Directive::DefCfa(self.arch.stack_pointer(), self.arch.initial_cfa_offset()));
+        if self.arch == UnwindArch::X64 {
+            self.encode(&mut cie, Directive::Offset(x64::RA, -8));
+        }
+        write_entry(&mut buf, &cie);
+        buf
+    }
+
+    // Write an FDE for the code at offset `start`, located at `addr` and spanning `size` bytes, which the directives
+    // in the `directives` range of the list of directives describe. The directives before them determine the initial
+    // unwind state.
+    fn write_fde(&self, buf: &mut Vec<u8>, addr: usize, start: AssemblyOffset, size: usize, directives: Range<usize>) {
+        // the CIE is at the start of the buffer
+        let fde_start = buf.len();
+        let mut fde = Vec::new();
+        fde.write_u32::<NativeEndian>((fde_start + 4) as u32).unwrap();
+        fde.write_u64::<NativeEndian>(addr as u64).unwrap();
+        fde.write_u64::<NativeEndian>(size as u64).unwrap();
+        write_uleb128(&mut fde, 0);
+
+        if directives.start != 0 {
+            for directive in self.state_after(directives.start) {
+                self.encode(&mut fde, directive);
+            }
+        }
+
+        let mut location = start.0;
+        for &(offset, directive) in &self.directives[directives] {
+            let delta = (offset.0 - location) as u32;
+            assert!(delta.is_multiple_of(self.arch.code_alignment()), "Unwind directive recorded at a misaligned offset");
+            let delta = delta / self.arch.code_alignment();
+            match delta {
+                0 => (),
+                1 ..= 0x3F => fde.push(DW_CFA_ADVANCE_LOC | delta as u8),
+                0x40 ..= 0xFF => {
+                    fde.push(DW_CFA_ADVANCE_LOC1);
+                    fde.push(delta as u8);
+                },
+                0x100 ..= 0xFFFF => {
+                    fde.push(DW_CFA_ADVANCE_LOC2);
+                    fde.write_u16::<NativeEndian>(delta as u16).unwrap();
+                },
+                _ => {
+                    fde.push(DW_CFA_ADVANCE_LOC4);
+                    fde.write_u32::<NativeEndian>(delta).unwrap();
+                }
+            }
+            location = offset.0;
+            self.encode(&mut fde, directive);
+        }
+        write_entry(buf, &fde);
+    }
+
+    // the directives that restore the unwind state after the first `count` directives
+    fn state_after(&self, count: usize) -> Vec<Directive> {
+        let mut cfa = (self.arch.stack_pointer(), self.arch.initial_cfa_offset());
+        let mut saved = BTreeMap::new();
+        for &(_, directive) in &self.directives[.. count] {
+            match directive {
+                Directive::DefCfa(register, offset) => cfa = (register, offset),
+                Directive::DefCfaRegister(register) => cfa.0 = register,
+                Directive::DefCfaOffset(offset) => cfa.1 = offset,
+                Directive::Offset(register, offset) => {
+                    saved.insert(register, offset);
+                },
+                Directive::Restore(register) => {
+                    saved.remove(&register);
+                }
+            }
+        }
+
+        let mut state = vec![Directive::DefCfa(cfa.0, cfa.1)];
+        state.extend(saved.into_iter().map(|(register, offset)| Directive::Offset(register, offset)));
+        state
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, directive: Directive) {
+        match directive {
+            Directive::DefCfa(register, offset) => if offset >= 0 {
+                buf.push(DW_CFA_DEF_CFA);
+                write_uleb128(buf, u64::from(register));
+                write_uleb128(buf, offset as u64);
+            } else {
+                buf.push(DW_CFA_DEF_CFA_SF);
+                write_uleb128(buf, u64::from(register));
+                write_sleb128(buf, i64::from(offset / DATA_ALIGNMENT));
+            },
+            Directive::DefCfaRegister(register) => {
+                buf.push(DW_CFA_DEF_CFA_REGISTER);
+                write_uleb128(buf, u64::from(register));
+            },
+            Directive::DefCfaOffset(offset) => {
+                buf.push(DW_CFA_DEF_CFA_OFFSET);
+                write_uleb128(buf, offset as u64);
+            },
+            Directive::Offset(register, offset) => {
+                assert!(offset % DATA_ALIGNMENT == 0, "Registers have to be saved at 8-byte aligned offsets");
+                let factored = offset / DATA_ALIGNMENT;
+                if register < 0x40 && factored >= 0 {
+                    buf.push(DW_CFA_OFFSET | register as u8);
+                    write_uleb128(buf, factored as u64);
+                } else {
+                    buf.push(DW_CFA_OFFSET_EXTENDED_SF);
+                    write_uleb128(buf, u64::from(register));
+                    write_sleb128(buf, i64::from(factored));
+                }
+            },
+            Directive::Restore(register) => if register < 0x40 {
+                buf.push(DW_CFA_RESTORE | register as u8);
+            } else {
+                buf.push(DW_CFA_RESTORE_EXTENDED);
+                write_uleb128(buf, u64::from(register));
+            }
+        }
+    }
+}
+
+
+// Write a CIE or FDE, prefixed with its length and padded to pointer alignment.
+fn write_entry(buf: &mut Vec<u8>, entry: &[u8]) {
+    let padding = (8 - (entry.len() + 4) % 8) % 8;
+    buf.write_u32::<NativeEndian>((entry.len() + padding) as u32).unwrap();
+    buf.extend_from_slice(entry);
+    buf.resize(buf.len() + padding, DW_CFA_NOP);
+}
+
+fn write_uleb128(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_sleb128(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+
+/// `.eh_frame` data that has been registered with the unwinder. It is deregistered when this is dropped.
+#[derive(Debug)]
+pub struct FrameRegistration {
+    eh_frame: Box<[u8]>,
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+impl FrameRegistration {
+    /// Register `eh_frame`, a sequence of CIEs and FDEs as generated by `CfiBuilder::eh_frame`, with the unwinder.
+    ///
+    /// # Safety
+    ///
+    /// The FDEs in `eh_frame` have to correctly describe the code they cover for as long as the registration lives,
+    /// as the unwinder will trust them when unwinding through that code.
+    pub unsafe fn register(mut eh_frame: Vec<u8>) -> FrameRegistration {
+        // the unwinder expects the data to be terminated by an empty entry
+        eh_frame.write_u32::<NativeEndian>(0).unwrap();
+        let eh_frame = eh_frame.into_boxed_slice();
+
+        #[cfg(target_os = "linux")]
+        __register_frame(eh_frame.as_ptr());
+
+        FrameRegistration {
+            eh_frame
+        }
+    }
+}
+
+impl Drop for FrameRegistration {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            __deregister_frame(self.eh_frame.as_ptr());
+        }
+    }
+}
+
+
+/// Tracks the unwind information of the functions in an assembler, and keeps it registered at the current
+/// location of the code.
+#[derive(Debug, Default)]
+pub(crate) struct UnwindTable {
+    // functions that have not been fully committed yet, with their end offset
+    pending: Vec<(CfiBuilder, usize)>,
+    // functions that have been committed
+    committed: Vec<(CfiBuilder, usize)>,
+    // the registrations of the committed functions, each covering the functions committed at once
+    registrations: Vec<FrameRegistration>,
+}
+
+impl UnwindTable {
+    /// Add the unwind information of a function, which ends at `end`.
+    pub fn add(&mut self, cfi: CfiBuilder, end: AssemblyOffset) {
+        self.pending.push((cfi, end.0));
+    }
+
+    /// Forget all functions that have not been committed yet whose last byte is at an offset for which `f` returns true.
+    pub fn discard<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.pending.retain(|&(_, end)| end == 0 || !f(end - 1));
+    }
+
+    /// Move all functions that have not been committed yet to the offsets returned by `f` for their current offsets.
+    pub fn relocate<F: Fn(AssemblyOffset) -> AssemblyOffset>(&mut self, f: F) {
+        for (cfi, end) in &mut self.pending {
+            cfi.relocate(&f);
+            *end = f(AssemblyOffset(*end)).0;
+        }
+    }
+
+    /// Register all functions in the `committed` bytes of code located at `addr` that were not registered yet. If
+    /// `moved` is set, the code has been moved since the last time this was called, and all functions are registered
+    /// again.
+    pub fn commit(&mut self, addr: usize, committed: usize, moved: bool) {
+        let mut count = self.committed.len();
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            let (cfi, end) = &self.pending[i];
+            if cfi.end(AssemblyOffset(*end)).0 <= committed {
+                let function = self.pending.remove(i);
+                self.committed.push(function);
+            } else {
+                i += 1;
+            }
+        }
+
+        // deregister the old information before registering it at the new location
+        if moved {
+            self.registrations.clear();
+            count = 0;
+        }
+        if self.committed.len() == count {
+            return;
+        }
+
+        let mut eh_frame = Vec::new();
+        for (cfi, end) in &self.committed[count ..] {
+            eh_frame.extend(cfi.eh_frame_ranges(addr, AssemblyOffset(*end)));
+        }
+        self.registrations.push(unsafe { FrameRegistration::register(eh_frame) });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Assembler, DynasmApi, DynasmLabelApi, Section};
+    use crate::x64::X64Relocation;
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_unwind_info() {
+        use std::sync::Mutex;
+
+        static BACKTRACE: Mutex<String> = Mutex::new(String::new());
+        extern "C" fn capture() {
+            *BACKTRACE.lock().unwrap() = format!("{}", std::backtrace::Backtrace::force_capture());
+        }
+
+        let mut ops = Assembler::<X64Relocation>::new().unwrap();
+        let start = ops.offset();
+        let mut cfi = CfiBuilder::new(UnwindArch::X64, start);
+
+        ops.push(0x55); // push rbp
+        cfi.push(ops.offset(), x64::RBP);
+        ops.extend(&[0x48, 0x89, 0xE5]); // mov rbp, rsp
+        cfi.set_frame_pointer(ops.offset());
+        ops.extend(&[0x48, 0xB8]); // mov rax, capture
+        ops.extend(&(capture as *const () as usize as u64).to_le_bytes());
+        ops.extend(&[0xFF, 0xD0]); // call rax
+        ops.push(0x5D); // pop rbp
+        cfi.set_cfa(ops.offset(), x64::RSP, 8);
+        cfi.restore_register(ops.offset(), x64::RBP);
+        ops.push(0xC3); // ret
+        let end = ops.offset();
+        ops.add_unwind_info(cfi, end);
+
+        // move the buffer to make sure the unwind information follows the code
+        ops.commit().unwrap();
+        ops.extend(vec![0xCC; 8192]);
+        ops.commit().unwrap();
+        assert_eq!(ops.unwind.registrations.len(), 1);
+
+        let reader = ops.reader();
+        let check = |start| {
+            let f: extern "C" fn() = unsafe { std::mem::transmute(reader.lock().ptr(start)) };
+            f();
+
+            let backtrace = BACKTRACE.lock().unwrap();
+            let unwound = backtrace.lines().any(|line| line.trim_end().ends_with("tests::test_unwind_info"));
+            assert!(unwound, "backtrace did not unwind through assembled code:\n{}", backtrace);
+        };
+        check(start);
+
+        // a function with a slow path in the cold section is described by an FDE for each of its parts
+        let start = ops.offset();
+        let mut cfi = CfiBuilder::new(UnwindArch::X64, start);
+        ops.push(0x55); // push rbp
+        cfi.push(ops.offset(), x64::RBP);
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->slow
+        ops.global_reloc("slow", 0, (0, 4));
+        let hot = ops.offset();
+        ops.switch_section(Section::Cold);
+        cfi.continue_at(hot, ops.offset());
+        ops.global_label("slow");
+        ops.extend(&[0x48, 0xB8]); // mov rax, capture
+        ops.extend(&(capture as *const () as usize as u64).to_le_bytes());
+        ops.extend(&[0xFF, 0xD0]); // call rax
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp ->resume
+        ops.global_reloc("resume", 0, (0, 4));
+        let cold = ops.offset();
+        ops.switch_section(Section::Hot);
+        cfi.continue_at(cold, ops.offset());
+        ops.global_label("resume");
+        ops.push(0x5D); // pop rbp
+        cfi.set_cfa(ops.offset(), x64::RSP, 8);
+        cfi.restore_register(ops.offset(), x64::RBP);
+        ops.push(0xC3); // ret
+        let end = ops.offset();
+        ops.add_unwind_info(cfi, end);
+        ops.commit().unwrap();
+
+        // only the new function is registered
+        assert_eq!(ops.unwind.registrations.len(), 2);
+        BACKTRACE.lock().unwrap().clear();
+        check(start);
+
+        // unwind information moves along with relaxed code
+        ops.enable_relaxation();
+        let start = ops.offset();
+        let mut cfi = CfiBuilder::new(UnwindArch::X64, start);
+        ops.extend(&[0xE9, 0, 0, 0, 0]); // jmp >next
+        ops.forward_reloc("next", 0, (0, 4));
+        ops.relaxable_reloc();
+        ops.local_label("next");
+        ops.extend(&[0x48, 0xB8]); // mov rax, capture
+        ops.extend(&(capture as *const () as usize as u64).to_le_bytes());
+        ops.push(0x55); // push rbp
+        cfi.push(ops.offset(), x64::RBP);
+        ops.extend(&[0xFF, 0xD0]); // call rax
+        ops.push(0x5D); // pop rbp
+        cfi.set_cfa(ops.offset(), x64::RSP, 8);
+        cfi.restore_register(ops.offset(), x64::RBP);
+        ops.push(0xC3); // ret
+        let end = ops.offset();
+        ops.add_unwind_info(cfi, end);
+        ops.commit().unwrap();
+        assert_eq!(ops.offset().0 - start.0, 17);
+
+        BACKTRACE.lock().unwrap().clear();
+        check(start);
+    }
+}
@@ -1,28 +1,47 @@
 //! This module implements the relocation model for the x64 architecture, as well as aliases for x64 Assemblers.
 
-use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation};
+use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation, RelaxedJump};
+use crate::elf;
+use crate::module::{self, ModuleRelocation};
 
 
 /// Relocation implementation for the x64 architecture.
 #[derive(Debug, Clone)]
 pub struct X64Relocation {
     size: RelocationSize,
+    kind: RelocationKind,
     offset: u8,
     start_offset: u8
 }
 
+impl X64Relocation {
+    /// Construct a relocation of `size` bytes at the end of an instruction or data item, of the given `kind`.
+    /// The encoding emitted by the `dynasm!` macro always describes a relative relocation, so this is used for
+    /// absolute references to labels in data directives.
+    pub fn with_kind(size: RelocationSize, kind: RelocationKind) -> Self {
+        Self {
+            size,
+            kind,
+            offset: 0,
+            start_offset: 0,
+        }
+    }
+}
+
 impl Relocation for X64Relocation {
     type Encoding = (u8, u8);
     fn from_encoding(encoding: Self::Encoding) -> Self {
         Self {
             offset: encoding.0,
             size: RelocationSize::from_encoding(encoding.1),
+            kind: RelocationKind::Relative,
             start_offset: 0,
         }
     }
     fn from_size(size: RelocationSize) -> Self {
         Self {
             size,
+            kind: RelocationKind::Relative,
             offset: 0,
             start_offset: size as u8,
         }
@@ -43,11 +62,85 @@ impl Relocation for X64Relocation {
         self.size.read_value(buf)
     }
     fn kind(&self) -> RelocationKind {
-        RelocationKind::Relative
+        self.kind
     }
     fn page_size() -> usize {
         4096
     }
+    fn nop() -> &'static [u8] {
+        &[0x90]
+    }
+    fn elf_machine() -> Option<u16> {
+        Some(elf::EM_X86_64)
+    }
+    fn elf_relocation_type(&self) -> Option<u32> {
+        match (self.kind, self.size) {
+            (RelocationKind::Relative, RelocationSize::Byte) => Some(elf::R_X86_64_PC8),
+            (RelocationKind::Relative, RelocationSize::Word) => Some(elf::R_X86_64_PC16),
+            (RelocationKind::Relative, RelocationSize::DWord) => Some(elf::R_X86_64_PC32),
+            (RelocationKind::Relative, RelocationSize::QWord) => Some(elf::R_X86_64_PC64),
+            (RelocationKind::AbsToRel, RelocationSize::QWord) => Some(elf::R_X86_64_64),
+            _ => None
+        }
+    }
+    fn relax(&self, code: &[u8]) -> Option<RelaxedJump<Self>> {
+        if self.size != RelocationSize::DWord || self.kind != RelocationKind::Relative || self.offset != 0 || self.start_offset != 0 {
+            return None;
+        }
+        relax_jump(code).map(|(length, opcode)| RelaxedJump {
+            length,
+            code: vec![opcode, 0],
+            relocation: Self::from_encoding((0, 1))
+        })
+    }
+    fn veneer(&self, target: usize) -> Option<Vec<u8>> {
+        // only call, jmp and jcc with a 32-bit displacement can be routed through a veneer
+        if self.size != RelocationSize::DWord || self.kind != RelocationKind::Relative || self.offset != 0 || self.start_offset != 0 {
+            return None;
+        }
+        // mov r11, target; jmp r11
+        let mut code = vec![0x49, 0xBB];
+        code.extend_from_slice(&(target as u64).to_le_bytes());
+        code.extend_from_slice(&[0x41, 0xFF, 0xE3]);
+        Some(code)
+    }
+    fn jump_over(length: usize) -> Option<Vec<u8>> {
+        // jmp rel32
+        let mut code = vec![0xE9];
+        code.extend_from_slice(&(length as u32).to_le_bytes());
+        Some(code)
+    }
+}
+
+/// Determines if `code` ends with a jump with a 32-bit displacement (`jmp rel32` or `jcc rel32`) that can be encoded
+/// with an 8-bit displacement instead. If so, returns the length of the current encoding and the opcode of the short encoding.
+pub(crate) fn relax_jump(code: &[u8]) -> Option<(usize, u8)> {
+    match code.len().checked_sub(6).map(|start| &code[start .. start + 2]) {
+        Some(&[0x0F, cc]) if cc & 0xF0 == 0x80 => return Some((6, 0x70 | (cc & 0xF))),
+        _ => ()
+    }
+    match code.len().checked_sub(5).map(|start| code[start]) {
+        Some(0xE9) => Some((5, 0xEB)),
+        _ => None
+    }
+}
+
+impl ModuleRelocation for X64Relocation {
+    const NAME: &'static str = "x64";
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&[self.size as u8, self.kind as u8, self.offset, self.start_offset]);
+    }
+    fn read_from(buf: &[u8]) -> Option<Self> {
+        match *buf {
+            [size, kind, offset, start_offset] => Some(Self {
+                size: module::read_size(size)?,
+                kind: module::read_kind(kind)?,
+                offset,
+                start_offset
+            }),
+            _ => None
+        }
+    }
 }
 
 /// An x64 Assembler. This is aliased here for backwards compatability.
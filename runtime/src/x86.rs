@@ -1,6 +1,7 @@
 //! This module implements the relocation model for the x86 architecture, as well as aliases for x68 Assemblers.
 
-use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation};
+use crate::relocations::{Relocation, RelocationSize, RelocationKind, ImpossibleRelocation, RelaxedJump};
+use crate::module::{self, ModuleRelocation};
 
 
 /// Relocation implementation for the x86 architecture.
@@ -51,6 +52,37 @@ impl Relocation for X86Relocation {
     fn page_size() -> usize {
         4096
     }
+    fn nop() -> &'static [u8] {
+        &[0x90]
+    }
+    fn relax(&self, code: &[u8]) -> Option<RelaxedJump<Self>> {
+        if self.size != RelocationSize::DWord || self.kind != RelocationKind::Relative || self.offset != 0 || self.start_offset != 0 {
+            return None;
+        }
+        crate::x64::relax_jump(code).map(|(length, opcode)| RelaxedJump {
+            length,
+            code: vec![opcode, 0],
+            relocation: Self::from_encoding((0, 1, 0))
+        })
+    }
+}
+
+impl ModuleRelocation for X86Relocation {
+    const NAME: &'static str = "x86";
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&[self.size as u8, self.kind as u8, self.offset, self.start_offset]);
+    }
+    fn read_from(buf: &[u8]) -> Option<Self> {
+        match *buf {
+            [size, kind, offset, start_offset] => Some(Self {
+                size: module::read_size(size)?,
+                kind: module::read_kind(kind)?,
+                offset,
+                start_offset
+            }),
+            _ => None
+        }
+    }
 }
 
 
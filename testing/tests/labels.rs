@@ -0,0 +1,227 @@
+#![feature(proc_macro_hygiene)]
+#![allow(unused_imports)]
+
+extern crate dynasmrt;
+extern crate dynasm;
+
+use dynasm::dynasm;
+use dynasmrt::{DynasmApi, DynasmLabelApi};
+
+// GNU-style numeric labels
+#[test]
+fn numeric_labels_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch x64
+       ; 1:
+       ; jmp 1f
+       ; 1:
+       ; jmp 1b
+       ; jmp 2f
+       ; 2:
+       ; jmp >done
+       ; done:
+       ; jmp 1b
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0xE9, 0x00, 0x00, 0x00, 0x00, 0xE9, 0xFB, 0xFF, 0xFF, 0xFF, 0xE9, 0x00, 0x00, 0x00, 0x00, 0xE9, 0x00, 0x00, 0x00, 0x00, 0xE9, 0xEC, 0xFF, 0xFF, 0xFF", "numeric_labels_x64");
+}
+
+#[test]
+fn numeric_labels_aarch64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::aarch64::Aarch64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch aarch64
+       ; 10:
+       ; b 10f
+       ; 10:
+       ; cbz x0, 10b
+       ; adr x1, 10b
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0xB4, 0xE1, 0xFF, 0xFF, 0x10", "numeric_labels_aarch64");
+}
+
+// local labels confined to a scope
+#[test]
+fn label_scopes_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch x64
+       ; jmp >end
+       ; .scope
+       ; jmp >end
+       ; end:
+       ; .endscope
+       ; nop
+       ; end:
+       ; ret
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0xE9, 0x06, 0x00, 0x00, 0x00, 0xE9, 0x00, 0x00, 0x00, 0x00, 0x90, 0xC3", "label_scopes_x64");
+}
+
+#[test]
+fn unresolved_label_in_scope() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch x64
+       ; .scope
+       ; jmp >end
+       ; .endscope
+       ; end:
+       ; ret
+    );
+    assert_eq!(ops.finalize().unwrap_err(), dynasmrt::DynasmError::UnknownLabel(dynasmrt::LabelKind::Local("end")));
+}
+
+// global labels named at runtime
+#[test]
+fn named_global_labels_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    for i in 0 .. 2 {
+        let name = format!("named_global_{}", i);
+        dynasm!(ops
+           ; .arch x64
+           ; ->(name):
+           ; jmp ->(format!("named_global_{}", 1 - i))
+        );
+    }
+    dynasm!(ops
+       ; .arch x64
+       ; jmp ->named_global_0
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0xE9, 0x00, 0x00, 0x00, 0x00, 0xE9, 0xF6, 0xFF, 0xFF, 0xFF, 0xE9, 0xF1, 0xFF, 0xFF, 0xFF", "named_global_labels_x64");
+}
+
+// slow paths placed out of line
+#[test]
+fn cold_section_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch x64
+       ; test edi, edi
+       ; jz >slow
+       ; .cold
+       ; slow:
+       ; mov eax, 1
+       ; jmp >done
+       ; .hot
+       ; xor eax, eax
+       ; done:
+       ; ret
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x85, 0xFF, 0x0F, 0x84, 0x03, 0x00, 0x00, 0x00, 0x31, 0xC0, 0xC3, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xE9, 0xF5, 0xFF, 0xFF, 0xFF", "cold_section_x64");
+}
+
+// constants and mutable data placed after the code
+#[test]
+fn data_sections_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch x64
+       ; lea rax, [->table]
+       ; mov rcx, [->counter]
+       ; ret
+       ; .section rodata
+       ; ->table:
+       ; .dword 1, 2
+       ; .section data
+       ; .align 8, 0
+       ; ->counter:
+       ; .qword 0
+       ; .section text
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x48, 0x8D, 0x05, 0x08, 0x00, 0x00, 0x00, 0x48, 0x8B, 0x0D, 0x0A, 0x00, 0x00, 0x00, 0xC3, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00", "data_sections_x64");
+}
+
+#[test]
+fn data_sections_aarch64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::aarch64::Aarch64Relocation>::new(0);
+    dynasm!(ops
+       ; .arch aarch64
+       ; adr x0, ->table
+       ; adrp x1, ->counter
+       ; ldr w0, [x0]
+       ; ret
+       ; .section rodata
+       ; ->table:
+       ; .dword 7
+       ; .section data
+       ; .align 8, 0
+       ; ->counter:
+       ; .qword 0
+       ; .section text
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x80, 0x00, 0x00, 0x10, 0x01, 0x00, 0x00, 0xB0, 0x00, 0x00, 0x40, 0xB9, 0xC0, 0x03, 0x5F, 0xD6, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00", "data_sections_aarch64");
+}
+
+// absolute addresses of labels in data directives
+#[test]
+fn absolute_references_x64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x64::X64Relocation>::new(0x1000);
+    let label = ops.new_dynamic_label();
+    let abs = 5;
+    dynasm!(ops
+       ; .arch x64
+       ; ->start:
+       ; .qword abs ->start, abs =>label, ->start
+       ; .dword abs >next, abs
+       ; next:
+       ; =>label
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x20, 0x10, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00", "absolute_references_x64");
+}
+
+#[test]
+fn absolute_references_x86() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::x86::X86Relocation>::new(0x1000);
+    let label = ops.new_dynamic_label();
+    dynasm!(ops
+       ; .arch x86
+       ; ->start:
+       ; .dword abs ->start, abs =>label, ->start
+       ; =>label
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x00, 0x10, 0x00, 0x00, 0x0C, 0x10, 0x00, 0x00, 0xF4, 0xFF, 0xFF, 0xFF", "absolute_references_x86");
+}
+
+#[test]
+fn absolute_references_aarch64() {
+    let mut ops = dynasmrt::VecAssembler::<dynasmrt::aarch64::Aarch64Relocation>::new(0x1000);
+    let label = ops.new_dynamic_label();
+    dynasm!(ops
+       ; .arch aarch64
+       ; ->start:
+       ; .qword abs ->start, abs =>label, ->start
+       ; =>label
+    );
+    let buf = ops.finalize().unwrap();
+    let hex: Vec<String> = buf.iter().map(|x| format!("0x{:02X}", *x)).collect();
+    let hex: String = hex.join(", ");
+    assert_eq!(hex, "0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF", "absolute_references_aarch64");
+}